@@ -0,0 +1,158 @@
+use crate::utils::canister_cycle_balance;
+use candid::Principal;
+use ic_cdk::call::{Call, CallRejected, CallResult, Error, RejectCode};
+use ic_cdk::management_canister::DepositCyclesArgs;
+use std::cell::Cell;
+use std::future::IntoFuture;
+
+/// How long an inter-canister call waits for the response.
+///
+/// All the canister clients and the deposits of `canfund` wait unbounded by default, a bounded wait
+/// is opt-in per client with `with_call_wait`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CallWait {
+    /// Waits until the callee responds, which can take arbitrarily long if it is unresponsive.
+    #[default]
+    Unbounded,
+    /// Waits at most the timeout in seconds, after which the call fails with a `SysUnknown` reject
+    /// and it is unknown whether the callee executed it.
+    Bounded { timeout_secs: u32 },
+}
+
+impl CallWait {
+    /// A typical timeout of bounded-wait calls, 5 minutes.
+    pub const DEFAULT_TIMEOUT_SECS: u32 = 300;
+
+    /// Waits at most the timeout in seconds for the response.
+    pub fn bounded(timeout_secs: u32) -> Self {
+        CallWait::Bounded { timeout_secs }
+    }
+
+    /// Creates a call to the method of the canister waiting accordingly for the response.
+    pub fn call<'m, 'a>(&self, canister_id: Principal, method: &'m str) -> Call<'m, 'a> {
+        match self {
+            CallWait::Unbounded => Call::unbounded_wait(canister_id, method),
+            CallWait::Bounded { timeout_secs } => {
+                Call::bounded_wait(canister_id, method).change_timeout(*timeout_secs)
+            }
+        }
+    }
+}
+
+/// Deposits the cycles to the canister through the management canister, waiting accordingly for
+/// the response.
+pub(crate) async fn deposit_cycles(
+    call_wait: CallWait,
+    args: &DepositCyclesArgs,
+    cycles: u128,
+) -> CallResult<()> {
+    call_wait
+        .call(Principal::management_canister(), "deposit_cycles")
+        .with_arg(args)
+        .with_cycles(cycles)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the reject of a method that an implementation of a canister interface does not support,
+/// used by the default implementations of the methods added to the interfaces over time.
+pub(crate) fn unsupported_method(method: &str) -> Error {
+    CallRejected::with_rejection(
+        RejectCode::DestinationInvalid as u32,
+        format!("the method `{method}` is not supported"),
+    )
+    .into()
+}
+
+/// The canisters whose calls are metered to break down the cost of a funding round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CallTarget {
+    Ledger,
+    Cmc,
+}
+
+/// The cycles the canister spent on the metered calls since it started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct CallCycles {
+    pub ledger: u128,
+    pub cmc: u128,
+    /// The number of calls whose cost is unknown, as the balance rose while they were in flight.
+    pub unmeasured: u64,
+}
+
+impl CallCycles {
+    /// Records the cycles spent on a call to the target from the balances before and after it.
+    fn record(&mut self, target: CallTarget, cycles_before: u128, cycles_after: u128) {
+        // Cycles received meanwhile, e.g. from a top-up of the canister, hide the cost of the call.
+        let Some(spent_cycles) = cycles_before.checked_sub(cycles_after) else {
+            self.unmeasured += 1;
+            return;
+        };
+
+        match target {
+            CallTarget::Ledger => self.ledger = self.ledger.saturating_add(spent_cycles),
+            CallTarget::Cmc => self.cmc = self.cmc.saturating_add(spent_cycles),
+        }
+    }
+}
+
+thread_local! {
+    static CALL_CYCLES: Cell<CallCycles> = Cell::default();
+}
+
+/// Awaits the call and records the decrease of the cycles balance meanwhile as spent on the target.
+///
+/// Other work executed while the call is in flight decreases the balance too, so the recorded cycles
+/// are an upper bound. If the balance rose meanwhile, the call is counted as unmeasured instead.
+pub(crate) async fn metered<C: IntoFuture>(target: CallTarget, call: C) -> C::Output {
+    let cycles_before = canister_cycle_balance();
+    let output = call.await;
+    let cycles_after = canister_cycle_balance();
+
+    CALL_CYCLES.with(|call_cycles| {
+        let mut cycles = call_cycles.get();
+        cycles.record(target, cycles_before, cycles_after);
+        call_cycles.set(cycles);
+    });
+
+    output
+}
+
+/// Returns the cycles the canister spent on the metered calls since it started.
+pub(crate) fn call_cycles() -> CallCycles {
+    CALL_CYCLES.with(Cell::get)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_wait_is_unbounded_by_default() {
+        assert_eq!(CallWait::default(), CallWait::Unbounded);
+        assert_eq!(
+            CallWait::bounded(CallWait::DEFAULT_TIMEOUT_SECS),
+            CallWait::Bounded { timeout_secs: 300 }
+        );
+    }
+
+    #[test]
+    fn test_call_with_rising_balance_is_unmeasured() {
+        let mut cycles = CallCycles::default();
+
+        cycles.record(CallTarget::Ledger, 1_000, 900);
+        cycles.record(CallTarget::Cmc, 900, 850);
+        // a top-up received while the call was in flight
+        cycles.record(CallTarget::Ledger, 850, 5_850);
+
+        assert_eq!(
+            cycles,
+            CallCycles {
+                ledger: 100,
+                cmc: 50,
+                unmeasured: 1,
+            }
+        );
+    }
+}
@@ -1,7 +1,9 @@
+use crate::api::call::{metered, unsupported_method, CallTarget, CallWait};
 use async_trait::async_trait;
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use ic_cdk::call::CallResult;
 use ic_ledger_types::{AccountIdentifier, Subaccount};
+use icrc_ledger_types::icrc1::account::Account;
 use serde::Deserialize;
 
 #[derive(CandidType, Deserialize, Default, Clone)]
@@ -28,6 +30,29 @@ pub enum NotifyTopUpResult {
     Err(NotifyError),
 }
 
+#[derive(CandidType)]
+pub struct NotifyMintCyclesArg {
+    pub block_index: u64,
+    pub to_subaccount: Option<icrc_ledger_types::icrc1::account::Subaccount>,
+    pub deposit_memo: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct NotifyMintCyclesSuccess {
+    /// The block index of the deposit on the cycles ledger.
+    pub block_index: Nat,
+    /// The amount of cycles minted.
+    pub minted: Nat,
+    /// The cycles ledger balance of the account after the deposit.
+    pub balance: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum NotifyMintCyclesResult {
+    Ok(NotifyMintCyclesSuccess),
+    Err(NotifyError),
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 pub enum NotifyError {
     Refunded {
@@ -43,6 +68,9 @@ pub enum NotifyError {
     },
 }
 
+/// The interface of the cycles minting canister (CMC).
+///
+/// `notify_mint_cycles` was added after `notify_top_up` and rejects unless it is implemented.
 #[async_trait]
 pub trait CyclesMintingCanister: Send + Sync {
     async fn get_icp_xdr(&self) -> CallResult<GetIcpXdrResult>;
@@ -53,27 +81,54 @@ pub trait CyclesMintingCanister: Send + Sync {
         canister_id: Principal,
     ) -> CallResult<NotifyTopUpResult>;
 
+    /// Notifies the CMC about an ICP transfer to mint cycles into the cycles ledger account of the caller.
+    ///
+    /// The ICP must have been transferred to the address returned by `get_top_up_address` for the caller.
+    async fn notify_mint_cycles(
+        &self,
+        _block_index: u64,
+        _to_subaccount: Option<icrc_ledger_types::icrc1::account::Subaccount>,
+    ) -> CallResult<NotifyMintCyclesResult> {
+        Err(unsupported_method("notify_mint_cycles"))
+    }
+
     fn get_top_up_address(&self, target_canister_id: Principal) -> AccountIdentifier;
+
+    /// Returns the ICRC-1 account of the CMC to transfer ICP to for topping up the target canister,
+    /// which must be the account of the address returned by `get_top_up_address`.
+    fn get_top_up_account(&self, target_canister_id: Principal) -> Account;
 }
 
 pub struct IcCyclesMintingCanister {
     cmc_canister_id: Principal,
+    call_wait: CallWait,
 }
 
 impl IcCyclesMintingCanister {
     pub fn new(cmc_canister_id: Principal) -> Self {
-        Self { cmc_canister_id }
+        Self {
+            cmc_canister_id,
+            call_wait: CallWait::default(),
+        }
+    }
+
+    /// Sets how long the calls wait for the response of the canister.
+    pub fn with_call_wait(mut self, call_wait: CallWait) -> Self {
+        self.call_wait = call_wait;
+        self
     }
 }
 
 #[async_trait]
 impl CyclesMintingCanister for IcCyclesMintingCanister {
     async fn get_icp_xdr(&self) -> CallResult<GetIcpXdrResult> {
-        Ok(
-            ic_cdk::call::Call::unbounded_wait(self.cmc_canister_id, "get_icp_xdr_conversion_rate")
-                .await?
-                .candid()?,
+        Ok(metered(
+            CallTarget::Cmc,
+            self.call_wait
+                .call(self.cmc_canister_id, "get_icp_xdr_conversion_rate"),
         )
+        .await?
+        .candid()?)
     }
 
     async fn notify_top_up(
@@ -81,23 +136,52 @@ impl CyclesMintingCanister for IcCyclesMintingCanister {
         block_index: u64,
         canister_id: Principal,
     ) -> CallResult<NotifyTopUpResult> {
-        Ok(
-            ic_cdk::call::Call::unbounded_wait(self.cmc_canister_id, "notify_top_up")
+        Ok(metered(
+            CallTarget::Cmc,
+            self.call_wait
+                .call(self.cmc_canister_id, "notify_top_up")
                 .with_arg(NotifyTopUpArg {
                     block_index,
                     canister_id,
-                })
-                .await?
-                .candid()?,
+                }),
         )
+        .await?
+        .candid()?)
+    }
+
+    async fn notify_mint_cycles(
+        &self,
+        block_index: u64,
+        to_subaccount: Option<icrc_ledger_types::icrc1::account::Subaccount>,
+    ) -> CallResult<NotifyMintCyclesResult> {
+        Ok(metered(
+            CallTarget::Cmc,
+            self.call_wait
+                .call(self.cmc_canister_id, "notify_mint_cycles")
+                .with_arg(NotifyMintCyclesArg {
+                    block_index,
+                    to_subaccount,
+                    deposit_memo: None,
+                }),
+        )
+        .await?
+        .candid()?)
     }
 
     fn get_top_up_address(&self, target_canister_id: Principal) -> AccountIdentifier {
         AccountIdentifier::new(&self.cmc_canister_id, &Subaccount::from(target_canister_id))
     }
+
+    fn get_top_up_account(&self, target_canister_id: Principal) -> Account {
+        Account {
+            owner: self.cmc_canister_id,
+            subaccount: Some(Subaccount::from(target_canister_id).0),
+        }
+    }
 }
 
-#[cfg(test)]
+/// Mock cycles minting canister for unit tests, available with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
 pub mod test {
     use std::sync::Arc;
 
@@ -105,12 +189,16 @@ pub mod test {
     use async_trait::async_trait;
     use tokio::sync::RwLock;
 
+    /// Records the calls it receives and replies with the configured results,
+    /// or with a rate of 5 XDR per ICP and successful notifications by default.
     #[derive(Default)]
     pub struct TestCmcCanister {
         pub notify_top_up_called_with: Arc<RwLock<Vec<u64>>>,
+        pub notify_mint_cycles_called_with: Arc<RwLock<Vec<u64>>>,
         pub get_icp_xdr_called: Arc<RwLock<bool>>,
 
         pub notify_top_up_returns_with: Option<CallResult<NotifyTopUpResult>>,
+        pub notify_mint_cycles_returns_with: Option<CallResult<NotifyMintCyclesResult>>,
         pub get_icp_xdr_returns_with: Option<CallResult<GetIcpXdrResult>>,
     }
 
@@ -148,11 +236,37 @@ pub mod test {
             Ok(NotifyTopUpResult::Ok(10))
         }
 
+        async fn notify_mint_cycles(
+            &self,
+            block_index: u64,
+            _to_subaccount: Option<icrc_ledger_types::icrc1::account::Subaccount>,
+        ) -> CallResult<NotifyMintCyclesResult> {
+            let mut locked = self.notify_mint_cycles_called_with.write().await;
+            locked.push(block_index);
+
+            if let Some(value) = &self.notify_mint_cycles_returns_with {
+                return value.clone();
+            }
+
+            Ok(NotifyMintCyclesResult::Ok(NotifyMintCyclesSuccess {
+                block_index: 0_u64.into(),
+                minted: 10_u64.into(),
+                balance: 10_u64.into(),
+            }))
+        }
+
         fn get_top_up_address(&self, target_canister_id: Principal) -> AccountIdentifier {
             AccountIdentifier::new(
                 &Principal::anonymous(),
                 &Subaccount::from(target_canister_id),
             )
         }
+
+        fn get_top_up_account(&self, target_canister_id: Principal) -> Account {
+            Account {
+                owner: Principal::anonymous(),
+                subaccount: Some(Subaccount::from(target_canister_id).0),
+            }
+        }
     }
 }
@@ -0,0 +1,90 @@
+use crate::api::call::CallWait;
+use crate::types::{RequestCyclesArgs, RequestCyclesError, RequestCyclesSuccess};
+use async_trait::async_trait;
+use candid::Principal;
+use ic_cdk::call::CallResult;
+
+/// The name of the endpoint a parent funding canister exposes to serve cycles requests.
+pub const REQUEST_CYCLES_METHOD: &str = "canfund_request_cycles";
+
+/// A parent funding canister that deposits cycles on request, e.g. a treasury funding several
+/// sub-funders.
+#[async_trait]
+pub trait FundingCanister: Send + Sync {
+    async fn request_cycles(
+        &self,
+        args: RequestCyclesArgs,
+    ) -> CallResult<Result<RequestCyclesSuccess, RequestCyclesError>>;
+}
+
+pub struct IcFundingCanister {
+    canister_id: Principal,
+    call_wait: CallWait,
+}
+
+impl IcFundingCanister {
+    pub fn new(canister_id: Principal) -> Self {
+        Self {
+            canister_id,
+            call_wait: CallWait::default(),
+        }
+    }
+
+    /// Sets how long the calls wait for the response of the canister.
+    pub fn with_call_wait(mut self, call_wait: CallWait) -> Self {
+        self.call_wait = call_wait;
+        self
+    }
+}
+
+#[async_trait]
+impl FundingCanister for IcFundingCanister {
+    async fn request_cycles(
+        &self,
+        args: RequestCyclesArgs,
+    ) -> CallResult<Result<RequestCyclesSuccess, RequestCyclesError>> {
+        Ok(self
+            .call_wait
+            .call(self.canister_id, REQUEST_CYCLES_METHOD)
+            .with_arg(args)
+            .await?
+            .candid()?)
+    }
+}
+
+/// Mock funding canister for unit tests, available with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+pub mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use async_trait::async_trait;
+    use tokio::sync::RwLock;
+
+    /// Records every request and replies with the configured result, or deposits the requested
+    /// amount by default.
+    #[derive(Default)]
+    pub struct TestFundingCanister {
+        pub request_cycles_called_with: Arc<RwLock<Vec<RequestCyclesArgs>>>,
+        pub request_cycles_returns_with:
+            Option<CallResult<Result<RequestCyclesSuccess, RequestCyclesError>>>,
+    }
+
+    #[async_trait]
+    impl FundingCanister for TestFundingCanister {
+        async fn request_cycles(
+            &self,
+            args: RequestCyclesArgs,
+        ) -> CallResult<Result<RequestCyclesSuccess, RequestCyclesError>> {
+            let amount = args.amount.clone();
+            let mut locked = self.request_cycles_called_with.write().await;
+            locked.push(args);
+
+            if let Some(value) = &self.request_cycles_returns_with {
+                return value.clone();
+            }
+
+            Ok(Ok(RequestCyclesSuccess { deposited: amount }))
+        }
+    }
+}
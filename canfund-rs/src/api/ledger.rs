@@ -1,60 +1,263 @@
-use crate::types::{WithdrawArgs, WithdrawError};
+use crate::api::call::{metered, unsupported_method, CallTarget, CallWait};
+use crate::types::{WithdrawArgs, WithdrawError, WithdrawFromArgs, WithdrawFromError};
 use async_trait::async_trait;
-use candid::Principal;
+use candid::{Nat, Principal};
 use ic_cdk::call::CallResult;
-use ic_ledger_types::{transfer, TransferArgs, TransferResult};
-use icrc_ledger_types::icrc1::transfer::BlockIndex;
+use ic_ledger_types::{AccountBalanceArgs, Tokens, TransferArgs, TransferResult};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{BlockIndex, TransferArg, TransferError};
+use icrc_ledger_types::icrc2::allowance::{Allowance, AllowanceArgs};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
 
+/// The interface of the ICP ledger.
+///
+/// Only `transfer` must be implemented, the other methods reject by default and are only called by
+/// the features that need them.
 #[async_trait]
 pub trait LedgerCanister: Send + Sync {
     async fn transfer(&self, args: TransferArgs) -> CallResult<TransferResult>;
+
+    /// Returns the balance of the account addressed by account identifier.
+    async fn account_balance(&self, _args: AccountBalanceArgs) -> CallResult<Tokens> {
+        Err(unsupported_method("account_balance"))
+    }
+
+    /// Transfers tokens using the ICRC-1 standard, which addresses accounts by principal and subaccount.
+    async fn icrc1_transfer(
+        &self,
+        _args: TransferArg,
+    ) -> CallResult<Result<BlockIndex, TransferError>> {
+        Err(unsupported_method("icrc1_transfer"))
+    }
+
+    /// Returns the balance of the account using the ICRC-1 standard.
+    async fn icrc1_balance_of(&self, _account: Account) -> CallResult<Nat> {
+        Err(unsupported_method("icrc1_balance_of"))
+    }
+
+    /// Returns the transfer fee of the ledger using the ICRC-1 standard.
+    async fn icrc1_fee(&self) -> CallResult<Nat> {
+        Err(unsupported_method("icrc1_fee"))
+    }
+
+    /// Transfers tokens from an account that approved the caller as a spender (ICRC-2).
+    async fn transfer_from(
+        &self,
+        _args: TransferFromArgs,
+    ) -> CallResult<Result<BlockIndex, TransferFromError>> {
+        Err(unsupported_method("icrc2_transfer_from"))
+    }
+
+    /// Returns the allowance the owner of an account approved for a spender (ICRC-2).
+    async fn allowance(&self, _args: AllowanceArgs) -> CallResult<Allowance> {
+        Err(unsupported_method("icrc2_allowance"))
+    }
 }
 
+/// The ICP ledger.
 pub struct IcLedgerCanister {
     canister_id: Principal,
+    call_wait: CallWait,
 }
 
 impl IcLedgerCanister {
     pub fn new(canister_id: Principal) -> Self {
-        Self { canister_id }
+        Self {
+            canister_id,
+            call_wait: CallWait::default(),
+        }
+    }
+
+    /// Sets how long the calls wait for the response of the canister.
+    pub fn with_call_wait(mut self, call_wait: CallWait) -> Self {
+        self.call_wait = call_wait;
+        self
     }
 }
 
 #[async_trait]
 impl LedgerCanister for IcLedgerCanister {
     async fn transfer(&self, args: TransferArgs) -> CallResult<TransferResult> {
-        transfer(self.canister_id, &args).await
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "transfer")
+                .with_arg(args),
+        )
+        .await?
+        .candid()?)
+    }
+
+    async fn account_balance(&self, args: AccountBalanceArgs) -> CallResult<Tokens> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "account_balance")
+                .with_arg(args),
+        )
+        .await?
+        .candid()?)
+    }
+
+    async fn icrc1_transfer(
+        &self,
+        args: TransferArg,
+    ) -> CallResult<Result<BlockIndex, TransferError>> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "icrc1_transfer")
+                .with_arg(args),
+        )
+        .await?
+        .candid()?)
+    }
+
+    async fn icrc1_balance_of(&self, account: Account) -> CallResult<Nat> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "icrc1_balance_of")
+                .with_arg(account),
+        )
+        .await?
+        .candid()?)
+    }
+
+    async fn icrc1_fee(&self) -> CallResult<Nat> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait.call(self.canister_id, "icrc1_fee"),
+        )
+        .await?
+        .candid()?)
+    }
+
+    async fn transfer_from(
+        &self,
+        args: TransferFromArgs,
+    ) -> CallResult<Result<BlockIndex, TransferFromError>> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "icrc2_transfer_from")
+                .with_arg(args),
+        )
+        .await?
+        .candid()?)
+    }
+
+    async fn allowance(&self, args: AllowanceArgs) -> CallResult<Allowance> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "icrc2_allowance")
+                .with_arg(args),
+        )
+        .await?
+        .candid()?)
     }
 }
 
+/// The interface of the cycles ledger.
+///
+/// Only `withdraw` must be implemented, the other methods reject by default and are only called by
+/// the features that need them.
 #[async_trait]
 pub trait WithdrawableLedgerCanister: Send + Sync {
     async fn withdraw(&self, args: WithdrawArgs) -> CallResult<Result<BlockIndex, WithdrawError>>;
+
+    /// Withdraws cycles from an account that approved the caller as a spender (ICRC-2).
+    async fn withdraw_from(
+        &self,
+        _args: WithdrawFromArgs,
+    ) -> CallResult<Result<BlockIndex, WithdrawFromError>> {
+        Err(unsupported_method("withdraw_from"))
+    }
+
+    /// Returns the allowance the owner of an account approved for a spender (ICRC-2).
+    async fn allowance(&self, _args: AllowanceArgs) -> CallResult<Allowance> {
+        Err(unsupported_method("icrc2_allowance"))
+    }
+
+    /// Returns the cycles balance of the account.
+    async fn icrc1_balance_of(&self, _account: Account) -> CallResult<Nat> {
+        Err(unsupported_method("icrc1_balance_of"))
+    }
 }
 
 pub struct CyclesLedgerCanister {
     canister_id: Principal,
+    call_wait: CallWait,
 }
 
 impl CyclesLedgerCanister {
     pub fn new(canister_id: Principal) -> Self {
-        Self { canister_id }
+        Self {
+            canister_id,
+            call_wait: CallWait::default(),
+        }
+    }
+
+    /// Sets how long the calls wait for the response of the canister.
+    pub fn with_call_wait(mut self, call_wait: CallWait) -> Self {
+        self.call_wait = call_wait;
+        self
     }
 }
 
 #[async_trait]
 impl WithdrawableLedgerCanister for CyclesLedgerCanister {
     async fn withdraw(&self, args: WithdrawArgs) -> CallResult<Result<BlockIndex, WithdrawError>> {
-        Ok(
-            ic_cdk::call::Call::unbounded_wait(self.canister_id, "withdraw")
-                .with_arg(args)
-                .await?
-                .candid()?,
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "withdraw")
+                .with_arg(args),
+        )
+        .await?
+        .candid()?)
+    }
+
+    async fn withdraw_from(
+        &self,
+        args: WithdrawFromArgs,
+    ) -> CallResult<Result<BlockIndex, WithdrawFromError>> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "withdraw_from")
+                .with_arg(args),
         )
+        .await?
+        .candid()?)
+    }
+
+    async fn allowance(&self, args: AllowanceArgs) -> CallResult<Allowance> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "icrc2_allowance")
+                .with_arg(args),
+        )
+        .await?
+        .candid()?)
+    }
+
+    async fn icrc1_balance_of(&self, account: Account) -> CallResult<Nat> {
+        Ok(metered(
+            CallTarget::Ledger,
+            self.call_wait
+                .call(self.canister_id, "icrc1_balance_of")
+                .with_arg(account),
+        )
+        .await?
+        .candid()?)
     }
 }
 
-#[cfg(test)]
+/// Mock ledger canisters for unit tests, available with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
 pub mod test {
     use std::sync::Arc;
 
@@ -62,10 +265,21 @@ pub mod test {
     use async_trait::async_trait;
     use tokio::sync::RwLock;
 
+    /// Records every transfer and replies with the configured results, or with a successful transfer
+    /// at block 0 and an unlimited allowance by default.
     #[derive(Default)]
     pub struct TestLedgerCanister {
         pub transfer_called_with: Arc<RwLock<Vec<TransferArgs>>>,
         pub returns_with: Option<CallResult<TransferResult>>,
+        pub account_balance_returns_with: Option<CallResult<Tokens>>,
+        pub icrc1_transfer_called_with: Arc<RwLock<Vec<TransferArg>>>,
+        pub icrc1_transfer_returns_with: Option<CallResult<Result<BlockIndex, TransferError>>>,
+        pub icrc1_balance_of_returns_with: Option<CallResult<Nat>>,
+        pub icrc1_fee_returns_with: Option<CallResult<Nat>>,
+        pub transfer_from_called_with: Arc<RwLock<Vec<TransferFromArgs>>>,
+        pub transfer_from_returns_with: Option<CallResult<Result<BlockIndex, TransferFromError>>>,
+        pub allowance_called_with: Arc<RwLock<Vec<AllowanceArgs>>>,
+        pub allowance_returns_with: Option<CallResult<Allowance>>,
     }
     #[async_trait]
     impl LedgerCanister for TestLedgerCanister {
@@ -79,12 +293,85 @@ pub mod test {
 
             Ok(Ok(0))
         }
+
+        async fn account_balance(&self, _args: AccountBalanceArgs) -> CallResult<Tokens> {
+            if let Some(value) = &self.account_balance_returns_with {
+                return value.clone();
+            }
+
+            Ok(Tokens::from_e8s(0))
+        }
+
+        async fn icrc1_transfer(
+            &self,
+            args: TransferArg,
+        ) -> CallResult<Result<BlockIndex, TransferError>> {
+            let mut locked = self.icrc1_transfer_called_with.write().await;
+            locked.push(args);
+
+            if let Some(value) = &self.icrc1_transfer_returns_with {
+                return value.clone();
+            }
+
+            Ok(Ok(0_u64.into()))
+        }
+
+        async fn icrc1_balance_of(&self, _account: Account) -> CallResult<Nat> {
+            if let Some(value) = &self.icrc1_balance_of_returns_with {
+                return value.clone();
+            }
+
+            Ok(0_u64.into())
+        }
+
+        async fn icrc1_fee(&self) -> CallResult<Nat> {
+            if let Some(value) = &self.icrc1_fee_returns_with {
+                return value.clone();
+            }
+
+            Ok(10_000_u64.into())
+        }
+
+        async fn transfer_from(
+            &self,
+            args: TransferFromArgs,
+        ) -> CallResult<Result<BlockIndex, TransferFromError>> {
+            let mut locked = self.transfer_from_called_with.write().await;
+            locked.push(args);
+
+            if let Some(value) = &self.transfer_from_returns_with {
+                return value.clone();
+            }
+
+            Ok(Ok(0_u64.into()))
+        }
+
+        async fn allowance(&self, args: AllowanceArgs) -> CallResult<Allowance> {
+            let mut locked = self.allowance_called_with.write().await;
+            locked.push(args);
+
+            if let Some(value) = &self.allowance_returns_with {
+                return value.clone();
+            }
+
+            Ok(Allowance {
+                allowance: u128::MAX.into(),
+                expires_at: None,
+            })
+        }
     }
 
+    /// Records every withdrawal and replies with the configured results, or with a successful
+    /// withdrawal at block 0 and an unlimited allowance by default.
     #[derive(Default)]
     pub struct TestCyclesLedgerCanister {
         pub transfer_called_with: Arc<RwLock<Vec<WithdrawArgs>>>,
         pub returns_with: Option<CallResult<Result<BlockIndex, WithdrawError>>>,
+        pub withdraw_from_called_with: Arc<RwLock<Vec<WithdrawFromArgs>>>,
+        pub withdraw_from_returns_with: Option<CallResult<Result<BlockIndex, WithdrawFromError>>>,
+        pub allowance_called_with: Arc<RwLock<Vec<AllowanceArgs>>>,
+        pub allowance_returns_with: Option<CallResult<Allowance>>,
+        pub icrc1_balance_of_returns_with: Option<CallResult<Nat>>,
     }
     #[async_trait]
     impl WithdrawableLedgerCanister for TestCyclesLedgerCanister {
@@ -101,5 +388,95 @@ pub mod test {
 
             Ok(Ok(0_u64.into()))
         }
+
+        async fn withdraw_from(
+            &self,
+            args: WithdrawFromArgs,
+        ) -> CallResult<Result<BlockIndex, WithdrawFromError>> {
+            let mut locked = self.withdraw_from_called_with.write().await;
+            locked.push(args);
+
+            if let Some(value) = &self.withdraw_from_returns_with {
+                return value.clone();
+            }
+
+            Ok(Ok(0_u64.into()))
+        }
+
+        async fn allowance(&self, args: AllowanceArgs) -> CallResult<Allowance> {
+            let mut locked = self.allowance_called_with.write().await;
+            locked.push(args);
+
+            if let Some(value) = &self.allowance_returns_with {
+                return value.clone();
+            }
+
+            Ok(Allowance {
+                allowance: u128::MAX.into(),
+                expires_at: None,
+            })
+        }
+
+        async fn icrc1_balance_of(&self, _account: Account) -> CallResult<Nat> {
+            if let Some(value) = &self.icrc1_balance_of_returns_with {
+                return value.clone();
+            }
+
+            Ok(0_u64.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::call::{CallErrorExt, Error, RejectCode};
+
+    /// A ledger implementing only the methods that were required before the others were added.
+    struct MinimalLedgerCanister;
+
+    #[async_trait]
+    impl LedgerCanister for MinimalLedgerCanister {
+        async fn transfer(&self, _args: TransferArgs) -> CallResult<TransferResult> {
+            Ok(Ok(0))
+        }
+    }
+
+    #[test]
+    fn test_ledgers_wait_unbounded_by_default() {
+        let canister_id = Principal::from_slice(&[1]);
+
+        assert_eq!(
+            IcLedgerCanister::new(canister_id).call_wait,
+            CallWait::Unbounded
+        );
+        assert_eq!(
+            CyclesLedgerCanister::new(canister_id).call_wait,
+            CallWait::Unbounded
+        );
+        assert_eq!(
+            IcLedgerCanister::new(canister_id)
+                .with_call_wait(CallWait::bounded(60))
+                .call_wait,
+            CallWait::Bounded { timeout_secs: 60 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_added_methods_reject_by_default() {
+        let result = MinimalLedgerCanister.icrc1_fee().await;
+
+        match result {
+            Err(Error::CallRejected(rejected)) => {
+                assert_eq!(rejected.reject_code(), Ok(RejectCode::DestinationInvalid));
+                assert!(rejected.reject_message().contains("icrc1_fee"));
+            }
+            _ => panic!("expected a reject"),
+        }
+        assert!(MinimalLedgerCanister
+            .icrc1_balance_of(Account::from(Principal::anonymous()))
+            .await
+            .unwrap_err()
+            .is_clean_reject());
     }
 }
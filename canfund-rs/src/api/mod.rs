@@ -1,4 +1,6 @@
 //! The api module contains interfaces with services like the cycles minting canister.
 
+pub mod call;
 pub mod cmc;
+pub mod funder;
 pub mod ledger;
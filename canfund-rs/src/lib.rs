@@ -4,6 +4,8 @@ pub mod api;
 pub mod errors;
 pub mod manager;
 pub mod operations;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod types;
 pub mod utils;
 
@@ -0,0 +1,148 @@
+/// The cycles the funding canister spent on the operations of a funding round, e.g. on fetching
+/// balances and calling ledgers, measured from the change of its balance.
+///
+/// Any other cycles the funding canister consumed during the round are included in the overhead, so
+/// it is an upper bound of the cost of canfund itself.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoundCost {
+    /// The cycles balance of the funding canister at the start of the round.
+    pub funder_cycles_before: u128,
+    /// The cycles balance of the funding canister at the end of the round.
+    pub funder_cycles_after: u128,
+    /// The cycles obtained for the funding canister during the round.
+    pub received_cycles: u128,
+    /// The cycles deposited from the funding canister during the round.
+    pub deposited_cycles: u128,
+    /// The cycles obtained directly for other canisters during the round.
+    pub obtained_for_canisters_cycles: u128,
+    /// The cycles spent on each kind of operation during the round.
+    pub operations: OperationCycles,
+}
+
+/// The cycles spent on each kind of operation, measured from the change of the balance of the
+/// funding canister while the calls were in flight.
+///
+/// Other work executed meanwhile is included, so each amount is an upper bound. A measurement during
+/// which the balance rose, e.g. because cycles were deposited to the funding canister, is left out
+/// of the amounts and counted as unmeasured, as its cost is unknown.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OperationCycles {
+    /// The cycles spent on fetching the balances of the canisters, e.g. with `canister_status`.
+    pub fetch_balances_cycles: u128,
+    /// The cycles spent on the calls to the ICP ledger and the cycles ledger.
+    pub ledger_cycles: u128,
+    /// The cycles spent on the calls to the CMC, e.g. to notify it of top-ups and mints.
+    pub cmc_cycles: u128,
+    /// The number of measurements left out of the amounts because their cost is unknown.
+    pub unmeasured_operations: u64,
+}
+
+impl OperationCycles {
+    /// Adds the cycles spent on the operations of another round.
+    pub fn add(&mut self, other: &OperationCycles) {
+        self.fetch_balances_cycles = self
+            .fetch_balances_cycles
+            .saturating_add(other.fetch_balances_cycles);
+        self.ledger_cycles = self.ledger_cycles.saturating_add(other.ledger_cycles);
+        self.cmc_cycles = self.cmc_cycles.saturating_add(other.cmc_cycles);
+        self.unmeasured_operations = self
+            .unmeasured_operations
+            .saturating_add(other.unmeasured_operations);
+    }
+}
+
+impl RoundCost {
+    /// Returns the cycles spent on the operations of the round.
+    pub fn overhead_cycles(&self) -> u128 {
+        self.funder_cycles_before
+            .saturating_add(self.received_cycles)
+            .saturating_sub(self.funder_cycles_after)
+            .saturating_sub(self.deposited_cycles)
+    }
+
+    /// Returns the cycles delivered to the funded canisters during the round.
+    pub fn delivered_cycles(&self) -> u128 {
+        self.deposited_cycles
+            .saturating_add(self.obtained_for_canisters_cycles)
+    }
+}
+
+/// The cycles spent on the operations of all funding rounds since the fund manager was created.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OperationCosts {
+    /// The number of rounds the costs were measured for.
+    pub rounds: u64,
+    /// The cycles spent on the operations of the rounds.
+    pub overhead_cycles: u128,
+    /// The cycles delivered to the funded canisters.
+    pub delivered_cycles: u128,
+    /// The cycles spent on each kind of operation of the rounds.
+    pub operations: OperationCycles,
+}
+
+impl OperationCosts {
+    /// Adds the cost of a round.
+    pub fn add(&mut self, cost: &RoundCost) {
+        self.rounds += 1;
+        self.overhead_cycles = self.overhead_cycles.saturating_add(cost.overhead_cycles());
+        self.delivered_cycles = self
+            .delivered_cycles
+            .saturating_add(cost.delivered_cycles());
+        self.operations.add(&cost.operations);
+    }
+
+    /// Returns the overhead in cycles per delivered cycle in permille, or `None` if no cycles were
+    /// delivered.
+    pub fn overhead_permille(&self) -> Option<u128> {
+        if self.delivered_cycles == 0 {
+            return None;
+        }
+
+        Some(self.overhead_cycles.saturating_mul(1_000) / self.delivered_cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_overhead() {
+        let cost = RoundCost {
+            funder_cycles_before: 1_000,
+            funder_cycles_after: 1_350,
+            received_cycles: 500,
+            deposited_cycles: 100,
+            obtained_for_canisters_cycles: 300,
+            operations: OperationCycles {
+                fetch_balances_cycles: 10,
+                ledger_cycles: 20,
+                cmc_cycles: 15,
+                unmeasured_operations: 1,
+            },
+        };
+
+        assert_eq!(cost.overhead_cycles(), 50);
+        assert_eq!(cost.delivered_cycles(), 400);
+
+        let mut costs = OperationCosts::default();
+        assert_eq!(costs.overhead_permille(), None);
+
+        costs.add(&cost);
+        costs.add(&cost);
+
+        assert_eq!(costs.rounds, 2);
+        assert_eq!(costs.overhead_cycles, 100);
+        assert_eq!(costs.delivered_cycles, 800);
+        assert_eq!(costs.overhead_permille(), Some(125));
+        assert_eq!(
+            costs.operations,
+            OperationCycles {
+                fetch_balances_cycles: 20,
+                ledger_cycles: 40,
+                cmc_cycles: 30,
+                unmeasured_operations: 2,
+            }
+        );
+    }
+}
@@ -36,6 +36,17 @@ impl ConsumptionHistory {
         self.sum += consumption;
     }
 
+    /// Changes the number of history elements to keep, dropping the oldest samples that no longer fit.
+    pub fn resize(&mut self, window_size: usize) {
+        while self.samples.len() > window_size {
+            if let Some(oldest_sample) = self.samples.pop_front() {
+                self.sum -= oldest_sample;
+            }
+        }
+
+        self.window_size = window_size;
+    }
+
     /// Returns the average of the samples in the history.
     pub fn average(&self) -> u64 {
         if self.samples.is_empty() {
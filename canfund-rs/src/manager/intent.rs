@@ -0,0 +1,140 @@
+use ic_cdk::management_canister::CanisterId;
+
+use super::record::CyclesBalance;
+
+/// The kind of cycles movement of a funding intent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FundingIntentKind {
+    /// Cycles deposited from the funding canister.
+    Deposit,
+    /// Cycles obtained from the named source.
+    Obtain { source: String },
+}
+
+/// A cycles movement that was started but not yet accounted in the canister record.
+///
+/// Intents are recorded before the movement is started and removed once it is accounted. An intent
+/// left behind by a trapped round or a movement whose outcome is unknown is reconciled in a later
+/// round, so that every cycles movement is accounted at least once.
+///
+/// Obtained cycles are reconciled by the block index of their ledger transaction once the source
+/// completes it, deposits, which have no ledger transaction, by the balance of the canister.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FundingIntent {
+    /// The canister the cycles are moved to.
+    pub canister_id: CanisterId,
+    /// The kind of cycles movement.
+    pub kind: FundingIntentKind,
+    /// The amount of cycles moved.
+    pub amount: u128,
+    /// The cycles balance of the canister when the intent was recorded, if known.
+    pub balance_before: Option<CyclesBalance>,
+    /// The block index of the ledger transaction of the movement, once it is known.
+    pub block_index: Option<u64>,
+    /// The timestamp when the intent was recorded.
+    pub created_at: u64,
+}
+
+impl FundingIntent {
+    /// How long an intent that does not show in the balance is kept, one day, after which the
+    /// ledgers and the CMC no longer accept a retry of its movement.
+    pub const MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+    /// Returns whether the intent is older than `MAX_AGE_SECS` at the timestamp in nanoseconds.
+    pub fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.created_at) / 1_000_000_000 >= Self::MAX_AGE_SECS
+    }
+
+    /// Returns whether this is an obtain intent accounted by the movement of the block index, which
+    /// is the case if the intent has no block index yet or the same one.
+    pub fn is_obtained_by(&self, block_index: Option<u64>) -> bool {
+        matches!(self.kind, FundingIntentKind::Obtain { .. })
+            && (self.block_index.is_none() || self.block_index == block_index)
+    }
+
+    /// Returns whether the movement shows in the balance, given the average consumption of the
+    /// canister in cycles per second.
+    ///
+    /// The movement is considered done if the balance grew by at least half of the amount since the
+    /// intent was recorded, after adding back the cycles consumed in the meantime. Without a known
+    /// previous balance the movement is assumed to be done.
+    pub fn is_settled_by(&self, balance: &CyclesBalance, average_consumption: u128) -> bool {
+        let Some(balance_before) = &self.balance_before else {
+            return true;
+        };
+
+        let elapsed_secs =
+            balance.timestamp.saturating_sub(balance_before.timestamp) / 1_000_000_000;
+        let consumed = average_consumption.saturating_mul(elapsed_secs as u128);
+        let increase = balance
+            .amount
+            .saturating_add(consumed)
+            .saturating_sub(balance_before.amount);
+
+        increase >= self.amount.div_ceil(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn intent(balance_before: Option<CyclesBalance>) -> FundingIntent {
+        FundingIntent {
+            canister_id: Principal::anonymous(),
+            kind: FundingIntentKind::Deposit,
+            amount: 1_000,
+            balance_before,
+            block_index: None,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_is_settled_by_balance_delta() {
+        let intent = intent(Some(CyclesBalance::new(5_000, 0)));
+
+        assert!(intent.is_settled_by(&CyclesBalance::new(6_000, 10 * SECOND), 0));
+        assert!(!intent.is_settled_by(&CyclesBalance::new(5_000, 10 * SECOND), 0));
+        // 10 seconds of consumption at 60 cycles per second hide 600 of the deposited cycles.
+        assert!(intent.is_settled_by(&CyclesBalance::new(5_400, 10 * SECOND), 60));
+        assert!(!intent.is_settled_by(&CyclesBalance::new(4_400, 10 * SECOND), 60));
+    }
+
+    #[test]
+    fn test_is_obtained_by_block_index() {
+        let mut intent = FundingIntent {
+            kind: FundingIntentKind::Obtain {
+                source: "mint".to_string(),
+            },
+            ..intent(None)
+        };
+
+        assert!(intent.is_obtained_by(Some(3)));
+
+        intent.block_index = Some(3);
+        assert!(intent.is_obtained_by(Some(3)));
+        assert!(!intent.is_obtained_by(Some(4)));
+        assert!(!intent.is_obtained_by(None));
+
+        intent.kind = FundingIntentKind::Deposit;
+        assert!(!intent.is_obtained_by(Some(3)));
+    }
+
+    #[test]
+    fn test_is_expired_after_max_age() {
+        let intent = intent(None);
+
+        assert!(!intent.is_expired(0));
+        assert!(!intent.is_expired((FundingIntent::MAX_AGE_SECS - 1) * SECOND));
+        assert!(intent.is_expired(FundingIntent::MAX_AGE_SECS * SECOND));
+    }
+
+    #[test]
+    fn test_is_settled_without_previous_balance() {
+        assert!(intent(None).is_settled_by(&CyclesBalance::new(0, 0), 0));
+    }
+}
@@ -1,19 +1,41 @@
-use std::{cell::RefCell, collections::HashSet, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::utils::time;
+
+/// A process holding the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LockedProcess {
+    /// The timestamp in nanoseconds when the lock was acquired.
+    acquired_at: u64,
+    /// Identifies the acquisition, so that a forcibly released lock is not released again by its
+    /// previous holder.
+    token: u64,
+}
 
 #[derive(Debug, Clone)]
 pub struct ProcessExecutionLock {
-    processes: Rc<RefCell<HashSet<Vec<u8>>>>,
+    processes: Rc<RefCell<HashMap<Vec<u8>, LockedProcess>>>,
+    next_token: Rc<RefCell<u64>>,
 }
 
 impl ProcessExecutionLock {
     pub fn new() -> Self {
         Self {
-            processes: Rc::new(RefCell::new(HashSet::new())),
+            processes: Rc::new(RefCell::new(HashMap::new())),
+            next_token: Rc::new(RefCell::new(0)),
         }
     }
 
     pub fn is_executing(&self, process_id: &[u8]) -> bool {
-        self.processes.borrow().contains(process_id)
+        self.processes.borrow().contains_key(process_id)
+    }
+
+    /// Returns the timestamp in nanoseconds when the lock of the process was acquired, if it is held.
+    pub fn acquired_at(&self, process_id: &[u8]) -> Option<u64> {
+        self.processes
+            .borrow()
+            .get(process_id)
+            .map(|process| process.acquired_at)
     }
 
     pub fn lock(&mut self, process_id: Vec<u8>) -> Option<RunningProcess> {
@@ -21,9 +43,47 @@ impl ProcessExecutionLock {
             return None;
         }
 
-        self.processes.borrow_mut().insert(process_id.clone());
+        let token = {
+            let mut next_token = self.next_token.borrow_mut();
+            *next_token += 1;
+            *next_token
+        };
+
+        self.processes.borrow_mut().insert(
+            process_id.clone(),
+            LockedProcess {
+                acquired_at: time(),
+                token,
+            },
+        );
+
+        Some(RunningProcess::new(
+            Rc::clone(&self.processes),
+            process_id,
+            token,
+        ))
+    }
+
+    /// Forcibly releases the lock of the process if it has been held for longer than the maximum
+    /// duration, e.g. because the process trapped without releasing it.
+    ///
+    /// Returns true if the lock was released.
+    pub fn release_if_stale(
+        &mut self,
+        process_id: &[u8],
+        max_duration_nanos: u64,
+        now: u64,
+    ) -> bool {
+        let mut processes = self.processes.borrow_mut();
+        let is_stale = processes
+            .get(process_id)
+            .is_some_and(|process| now.saturating_sub(process.acquired_at) > max_duration_nanos);
+
+        if is_stale {
+            processes.remove(process_id);
+        }
 
-        Some(RunningProcess::new(Rc::clone(&self.processes), process_id))
+        is_stale
     }
 
     pub fn clear(&mut self) {
@@ -39,22 +99,36 @@ impl Default for ProcessExecutionLock {
 
 #[derive(Debug, Clone)]
 pub struct RunningProcess {
-    processes: Rc<RefCell<HashSet<Vec<u8>>>>,
+    processes: Rc<RefCell<HashMap<Vec<u8>, LockedProcess>>>,
     process_id: Vec<u8>,
+    token: u64,
 }
 
 impl RunningProcess {
-    pub fn new(processes: Rc<RefCell<HashSet<Vec<u8>>>>, process_id: Vec<u8>) -> Self {
+    fn new(
+        processes: Rc<RefCell<HashMap<Vec<u8>, LockedProcess>>>,
+        process_id: Vec<u8>,
+        token: u64,
+    ) -> Self {
         Self {
             processes,
             process_id,
+            token,
         }
     }
 }
 
 impl Drop for RunningProcess {
     fn drop(&mut self) {
-        self.processes.borrow_mut().remove(&self.process_id);
+        let mut processes = self.processes.borrow_mut();
+
+        // The lock may have been released as stale and acquired by another process meanwhile.
+        if processes
+            .get(&self.process_id)
+            .is_some_and(|process| process.token == self.token)
+        {
+            processes.remove(&self.process_id);
+        }
     }
 }
 
@@ -127,4 +201,27 @@ mod tests {
         assert!(!lock.is_executing(&process_id_1));
         assert!(!lock.is_executing(&process_id_2));
     }
+
+    #[test]
+    fn test_releases_stale_lock() {
+        let mut lock = ProcessExecutionLock::new();
+        let process_id = vec![1, 2, 3];
+
+        let stale_process = lock.lock(process_id.clone()).unwrap();
+        let acquired_at = lock.acquired_at(&process_id).unwrap();
+
+        assert!(!lock.release_if_stale(&process_id, 10, acquired_at + 10));
+        assert!(lock.is_executing(&process_id));
+
+        assert!(lock.release_if_stale(&process_id, 10, acquired_at + 11));
+        assert!(!lock.is_executing(&process_id));
+
+        // the previous holder does not release the lock of the new one
+        let running_process = lock.lock(process_id.clone()).unwrap();
+        drop(stale_process);
+        assert!(lock.is_executing(&process_id));
+
+        drop(running_process);
+        assert!(!lock.is_executing(&process_id));
+    }
 }
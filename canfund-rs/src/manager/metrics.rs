@@ -0,0 +1,217 @@
+use std::fmt::Write;
+
+use super::FundManager;
+
+/// Encodes metrics in the Prometheus text exposition format.
+#[derive(Default)]
+struct MetricsEncoder {
+    output: String,
+}
+
+impl MetricsEncoder {
+    /// Encodes a metric without labels.
+    fn encode(&mut self, name: &str, metric_type: &str, help: &str, value: u128) {
+        self.encode_header(name, metric_type, help);
+        let _ = writeln!(self.output, "{name} {value}");
+    }
+
+    /// Encodes a metric with one sample per value of the label.
+    fn encode_labeled(&mut self, name: &str, help: &str, label: &str, samples: &[(String, u128)]) {
+        self.encode_header(name, "gauge", help);
+        for (label_value, value) in samples {
+            let _ = writeln!(self.output, "{name}{{{label}=\"{label_value}\"}} {value}");
+        }
+    }
+
+    fn encode_header(&mut self, name: &str, metric_type: &str, help: &str) {
+        let _ = writeln!(self.output, "# HELP {name} {help}");
+        let _ = writeln!(self.output, "# TYPE {name} {metric_type}");
+    }
+}
+
+impl FundManager {
+    /// Returns the metrics of the fund manager in the Prometheus text exposition format, e.g. to be
+    /// served by the `http_request` endpoint of the funding canister.
+    pub fn get_metrics(&self) -> String {
+        let mut encoder = MetricsEncoder::default();
+
+        let mut canisters: Vec<_> = self.get_canisters().into_iter().collect();
+        canisters.sort_by_key(|(canister_id, _)| *canister_id);
+        let cycles: Vec<_> = canisters
+            .iter()
+            .filter_map(|(canister_id, record)| {
+                let cycles = record.get_cycles().as_ref()?;
+                Some((canister_id.to_text(), cycles.amount))
+            })
+            .collect();
+        let consumption: Vec<_> = canisters
+            .iter()
+            .map(|(canister_id, record)| {
+                (
+                    canister_id.to_text(),
+                    record.get_average_consumption() as u128,
+                )
+            })
+            .collect();
+
+        encoder.encode_labeled(
+            "canfund_canister_cycles_balance",
+            "The last fetched cycles balance of the monitored canister.",
+            "canister_id",
+            &cycles,
+        );
+        encoder.encode_labeled(
+            "canfund_canister_consumption_cycles_per_sec",
+            "The average consumption of the monitored canister in cycles per second.",
+            "canister_id",
+            &consumption,
+        );
+        encoder.encode(
+            "canfund_skipped_rounds_total",
+            "counter",
+            "The number of rounds skipped because the previous round was still running.",
+            self.get_skipped_rounds() as u128,
+        );
+
+        let mut group_reports: Vec<_> = self.get_group_reports().into_iter().collect();
+        group_reports.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let group_deposited: Vec<_> = group_reports
+            .iter()
+            .map(|(group, report)| (group.clone(), report.deposited_cycles))
+            .collect();
+        let group_consumption: Vec<_> = group_reports
+            .iter()
+            .map(|(group, report)| (group.clone(), report.cycles_per_sec))
+            .collect();
+
+        encoder.encode_labeled(
+            "canfund_group_deposited_cycles",
+            "The total of cycles deposited to the canisters of the group.",
+            "group",
+            &group_deposited,
+        );
+        encoder.encode_labeled(
+            "canfund_group_consumption_cycles_per_sec",
+            "The average consumption of the canisters of the group in cycles per second.",
+            "group",
+            &group_consumption,
+        );
+
+        let operation_costs = self.get_operation_costs();
+        encoder.encode(
+            "canfund_overhead_cycles_total",
+            "counter",
+            "The cycles the funding canister spent on the operations of the funding rounds.",
+            operation_costs.overhead_cycles,
+        );
+        encoder.encode(
+            "canfund_delivered_cycles_total",
+            "counter",
+            "The cycles delivered to the funded canisters.",
+            operation_costs.delivered_cycles,
+        );
+        encoder.encode(
+            "canfund_fetch_balances_cycles_total",
+            "counter",
+            "The cycles the funding canister spent on fetching the balances of the canisters.",
+            operation_costs.operations.fetch_balances_cycles,
+        );
+        encoder.encode(
+            "canfund_ledger_calls_cycles_total",
+            "counter",
+            "The cycles the funding canister spent on the calls to the ledgers.",
+            operation_costs.operations.ledger_cycles,
+        );
+        encoder.encode(
+            "canfund_cmc_calls_cycles_total",
+            "counter",
+            "The cycles the funding canister spent on the calls to the CMC.",
+            operation_costs.operations.cmc_cycles,
+        );
+        encoder.encode(
+            "canfund_unmeasured_operations_total",
+            "counter",
+            "The operations whose cost is unknown as the balance of the funding canister rose meanwhile.",
+            operation_costs.operations.unmeasured_operations as u128,
+        );
+
+        let forecast = self.get_treasury_forecast();
+        let sources: Vec<_> = forecast
+            .sources
+            .iter()
+            .filter_map(|capacity| Some((capacity.source.clone(), capacity.available_cycles?)))
+            .collect();
+
+        encoder.encode(
+            "canfund_treasury_funder_cycles",
+            "gauge",
+            "The cycles balance of the funding canister.",
+            forecast.funder_cycles,
+        );
+        encoder.encode_labeled(
+            "canfund_treasury_source_cycles",
+            "The cycles that can be obtained from the source when it was last checked.",
+            "source",
+            &sources,
+        );
+        encoder.encode(
+            "canfund_treasury_consumption_cycles_per_sec",
+            "gauge",
+            "The average consumption of all monitored canisters in cycles per second.",
+            forecast.cycles_per_sec,
+        );
+        if let Some(runway_secs) = forecast.runway_secs() {
+            encoder.encode(
+                "canfund_treasury_runway_seconds",
+                "gauge",
+                "The seconds until the funding canister and the sources run dry at the current consumption.",
+                runway_secs as u128,
+            );
+        }
+
+        encoder.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::{
+        options::{FundManagerOptions, FunderOptions},
+        record::CyclesBalance,
+        RegisterOpts,
+    };
+    use crate::utils::canister_self;
+    use candid::Principal;
+
+    #[test]
+    fn test_metrics_output() {
+        let funded_canister_id = Principal::from_slice(&[1]);
+        let mut fund_manager = FundManager::new();
+        fund_manager
+            .with_options(FundManagerOptions::new().with_funder_options(Some(FunderOptions::new())))
+            .register(funded_canister_id, RegisterOpts::new());
+        {
+            let mut core = fund_manager.inner.borrow_mut();
+            core.canisters
+                .get_mut(&funded_canister_id)
+                .unwrap()
+                .set_cycles(CyclesBalance::new(1_000, 0));
+            core.canisters
+                .get_mut(&canister_self())
+                .unwrap()
+                .set_cycles(CyclesBalance::new(2_000, 0));
+        }
+
+        let metrics = fund_manager.get_metrics();
+
+        assert!(metrics.contains("# TYPE canfund_canister_cycles_balance gauge\n"));
+        assert!(metrics.contains(&format!(
+            "canfund_canister_cycles_balance{{canister_id=\"{funded_canister_id}\"}} 1000\n"
+        )));
+        assert!(metrics.contains("canfund_treasury_funder_cycles 2000\n"));
+        assert!(metrics.contains("canfund_skipped_rounds_total 0\n"));
+        // nothing is consumed yet, so the runway is unknown
+        assert!(!metrics.contains("canfund_treasury_runway_seconds"));
+    }
+}
@@ -1,19 +1,27 @@
 //! The fund manager that monitors and funds canister cycles based on the configuration.
 
 use self::{
+    cost::{OperationCosts, OperationCycles, RoundCost},
+    intent::{FundingIntent, FundingIntentKind},
     lock::ProcessExecutionLock,
     options::{FundManagerOptions, FundStrategy},
     record::{CanisterRecord, CyclesBalance},
+    registration::RegistrationDiff,
+    report::{FundingRoundReport, GroupReport, ObtainedCycles},
+    treasury::{TreasuryForecast, TreasuryRunway},
 };
-use crate::manager::options::ObtainCyclesOptions;
+use crate::api::call::{call_cycles, deposit_cycles};
+use crate::manager::options::{ObtainCyclesOptions, ObtainCyclesSource};
 use crate::manager::record::FundingErrorCode;
 use crate::operations::fetch::{FetchCyclesBalance, FetchCyclesBalanceFromCanisterStatus};
-use ic_cdk::api::{canister_self, debug_print};
-use ic_cdk::management_canister::DepositCyclesArgs;
-use ic_cdk::{
-    api::time,
-    management_canister::{deposit_cycles, CanisterId},
+use crate::operations::obtain::{
+    MintKind, ObtainCyclesError, PendingMint, PendingMints, PendingWithdrawal, PendingWithdrawals,
 };
+use crate::operations::retry::{RetryOperation, RetryPolicy};
+use crate::utils::{canister_cycle_balance, canister_self, time};
+use ic_cdk::api::debug_print;
+use ic_cdk::call::CallErrorExt;
+use ic_cdk::management_canister::{CanisterId, DepositCyclesArgs};
 use ic_cdk_timers::TimerId;
 use std::{
     cell::RefCell,
@@ -24,10 +32,16 @@ use std::{
     time::Duration,
 };
 
+pub mod cost;
 pub mod history;
+pub mod intent;
 pub mod lock;
+pub mod metrics;
 pub mod options;
 pub mod record;
+pub mod registration;
+pub mod report;
+pub mod treasury;
 
 /// The core features of the fund manager.
 pub struct FundManagerCore {
@@ -35,16 +49,34 @@ pub struct FundManagerCore {
     lock: ProcessExecutionLock,
     canisters: HashMap<CanisterId, CanisterRecord>,
     options: FundManagerOptions,
+    /// The report of the last completed funding round.
+    last_round_report: Option<FundingRoundReport>,
+    /// The number of rounds skipped because the previous round was still running.
+    skipped_rounds: u64,
+    /// The cycles movements that were started but not yet accounted, by canister.
+    intents: HashMap<CanisterId, FundingIntent>,
+    /// The ICP transfers of the obtain cycles sources whose cycles have not been minted yet.
+    pending_mints: PendingMints,
+    /// The withdrawals of the obtain cycles sources from the cycles ledger whose outcome is unknown.
+    pending_withdrawals: PendingWithdrawals,
+    /// The capacity of the obtain cycles sources when it was last checked.
+    treasury_runway: Option<TreasuryRunway>,
+    /// The cycles spent on the operations of all rounds.
+    operation_costs: OperationCosts,
 }
 
 /// RegisterOpts holds the options for registering a canister to be monitored by the fund manager.
 /// By default, it uses the `FetchCyclesBalanceFromCanisterStatus` to fetch the cycles balance.
 /// The fund strategy is set to `None` by default, meaning that the global strategy will be applied.
 /// The obtain cycles strategy is set to `None` by default, meaning that the global strategy will be applied.
+/// A canister in a group uses the strategy and obtain cycles options of the group unless it sets its own.
 pub struct RegisterOpts {
     pub cycles_fetcher: Arc<dyn FetchCyclesBalance>,
     pub strategy: Option<FundStrategy>,
     pub obtain_cycles_options: Option<ObtainCyclesOptions>,
+    pub group: Option<String>,
+    pub max_cycles_per_round: Option<u128>,
+    pub tags: Vec<String>,
 }
 
 impl RegisterOpts {
@@ -54,6 +86,9 @@ impl RegisterOpts {
             cycles_fetcher: Arc::new(FetchCyclesBalanceFromCanisterStatus::new()),
             strategy: None,
             obtain_cycles_options: None,
+            group: None,
+            max_cycles_per_round: None,
+            tags: Vec::new(),
         }
     }
 
@@ -77,6 +112,25 @@ impl RegisterOpts {
         self.obtain_cycles_options = Some(obtain_cycles_options);
         self
     }
+
+    /// Sets the group the canister belongs to, see `FundManagerOptions::with_group`.
+    pub fn with_group(mut self, group: impl Into<String>) -> Self {
+        self.group = Some(group.into());
+        self
+    }
+
+    /// Sets the maximum cycles delivered to the canister per funding round, which overrides the
+    /// budget of its group.
+    pub fn with_max_cycles_per_round(mut self, max_cycles_per_round: u128) -> Self {
+        self.max_cycles_per_round = Some(max_cycles_per_round);
+        self
+    }
+
+    /// Adds a tag to the canister, to aggregate the reports of the canisters with the tag.
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
 }
 
 impl Default for RegisterOpts {
@@ -108,7 +162,10 @@ impl FundManager {
 
     /// Configures the fund manager with the specified options.
     pub fn with_options(&mut self, options: FundManagerOptions) -> &mut Self {
-        self.inner.borrow_mut().options = options;
+        let mut inner = self.inner.borrow_mut();
+        inner.options = options;
+        inner.register_funder();
+        drop(inner);
 
         self
     }
@@ -127,6 +184,54 @@ impl FundManager {
         self
     }
 
+    /// Registers the canisters that are not registered yet, leaving the registered ones as they are.
+    pub fn register_many(
+        &mut self,
+        canisters: impl IntoIterator<Item = (CanisterId, RegisterOpts)>,
+    ) -> RegistrationDiff {
+        let mut inner = self.inner.borrow_mut();
+        let mut diff = RegistrationDiff::default();
+        for (canister_id, opts) in canisters {
+            if inner.canisters.contains_key(&canister_id) {
+                diff.unchanged.push(canister_id);
+            } else {
+                inner.register(canister_id, opts);
+                diff.added.push(canister_id);
+            }
+        }
+
+        diff.sorted()
+    }
+
+    /// Unregisters the canisters that are registered.
+    pub fn unregister_many(
+        &mut self,
+        canister_ids: impl IntoIterator<Item = CanisterId>,
+    ) -> RegistrationDiff {
+        let mut inner = self.inner.borrow_mut();
+        let mut diff = RegistrationDiff::default();
+        for canister_id in canister_ids {
+            if inner.unregister(canister_id).is_some() {
+                diff.removed.push(canister_id);
+            }
+        }
+
+        diff.sorted()
+    }
+
+    /// Reconciles the registered canisters with the desired ones, e.g. in `post_upgrade`.
+    ///
+    /// Canisters that are not registered yet are registered, registered canisters that are not
+    /// desired are unregistered, and the options of the others are replaced while keeping their
+    /// balances and consumption history. The funding canister stays registered if it is configured
+    /// with `FundManagerOptions::with_funder_options`.
+    pub fn sync(
+        &mut self,
+        canisters: impl IntoIterator<Item = (CanisterId, RegisterOpts)>,
+    ) -> RegistrationDiff {
+        self.inner.borrow_mut().sync(canisters)
+    }
+
     /// Returns the canisters that are being monitored by the fund manager.
     pub fn get_canisters(&self) -> HashMap<CanisterId, CanisterRecord> {
         self.inner.borrow().canisters.clone()
@@ -142,6 +247,164 @@ impl FundManager {
         self.inner.borrow().options.clone()
     }
 
+    /// Restores the pending and stranded mints, e.g. after an upgrade, so that the pending mints are
+    /// completed instead of transferring the ICP again.
+    pub fn with_pending_mints(&mut self, pending_mints: PendingMints) -> &mut Self {
+        self.inner.borrow_mut().pending_mints = pending_mints;
+
+        self
+    }
+
+    /// Returns the ICP transfers of all obtain cycles sources whose cycles have not been minted yet,
+    /// to be persisted across upgrades together with the stranded mints.
+    pub fn get_pending_mints(&self) -> Vec<PendingMint> {
+        self.inner.borrow().pending_mints.pending()
+    }
+
+    /// Returns the ICP transfers of all obtain cycles sources whose cycles can no longer be minted,
+    /// so that they can be recovered manually.
+    pub fn get_stranded_mints(&self) -> Vec<PendingMint> {
+        self.inner.borrow().pending_mints.stranded()
+    }
+
+    /// Removes a stranded mint once its ICP has been recovered, or once a transfer without a known
+    /// block index is confirmed to not have been executed.
+    pub fn remove_stranded_mint(&mut self, stranded_mint: &PendingMint) -> Option<PendingMint> {
+        self.inner
+            .borrow()
+            .pending_mints
+            .remove_stranded(stranded_mint)
+    }
+
+    /// Restores the pending and stranded withdrawals from the cycles ledger, e.g. after an upgrade,
+    /// so that the pending withdrawals are retried instead of withdrawing the cycles again.
+    pub fn with_pending_withdrawals(
+        &mut self,
+        pending_withdrawals: PendingWithdrawals,
+    ) -> &mut Self {
+        self.inner.borrow_mut().pending_withdrawals = pending_withdrawals;
+
+        self
+    }
+
+    /// Returns the withdrawals of all obtain cycles sources from the cycles ledger whose outcome is
+    /// unknown, to be persisted across upgrades together with the stranded withdrawals.
+    pub fn get_pending_withdrawals(&self) -> Vec<PendingWithdrawal> {
+        self.inner.borrow().pending_withdrawals.pending()
+    }
+
+    /// Returns the withdrawals of all obtain cycles sources from the cycles ledger that can no longer
+    /// be retried, so that their outcome can be checked manually.
+    pub fn get_stranded_withdrawals(&self) -> Vec<PendingWithdrawal> {
+        self.inner.borrow().pending_withdrawals.stranded()
+    }
+
+    /// Removes a stranded withdrawal once its outcome has been checked.
+    pub fn remove_stranded_withdrawal(
+        &mut self,
+        stranded_withdrawal: &PendingWithdrawal,
+    ) -> Option<PendingWithdrawal> {
+        self.inner
+            .borrow()
+            .pending_withdrawals
+            .remove_stranded(stranded_withdrawal)
+    }
+
+    /// Returns the number of rounds skipped because the previous round was still running.
+    pub fn get_skipped_rounds(&self) -> u64 {
+        self.inner.borrow().skipped_rounds
+    }
+
+    /// Returns the record of the funding canister, if it is monitored.
+    pub fn get_funder_record(&self) -> Option<CanisterRecord> {
+        self.inner.borrow().canisters.get(&canister_self()).cloned()
+    }
+
+    /// Returns the cycles movements that were started but not yet accounted, e.g. because the
+    /// round trapped. They are reconciled when the balance of the canister is fetched next.
+    pub fn get_funding_intents(&self) -> Vec<FundingIntent> {
+        self.inner.borrow().intents.values().cloned().collect()
+    }
+
+    /// Returns the report of the last completed funding round, if any.
+    pub fn get_last_round_report(&self) -> Option<FundingRoundReport> {
+        self.inner.borrow().last_round_report.clone()
+    }
+
+    /// Returns the capacity of the obtain cycles sources when it was last checked, if it is checked.
+    pub fn get_treasury_runway(&self) -> Option<TreasuryRunway> {
+        self.inner.borrow().treasury_runway.clone()
+    }
+
+    /// Returns a forecast of how long the funding canister and the obtain cycles sources last at the
+    /// current consumption of all monitored canisters.
+    ///
+    /// The capacity of the sources is the one of the last check, see
+    /// `FundManagerOptions::with_treasury_min_runway_secs`, and the sources are left out if they are
+    /// not checked.
+    pub fn get_treasury_forecast(&self) -> TreasuryForecast {
+        let inner = self.inner.borrow();
+        let funder_cycles = inner
+            .canisters
+            .get(&canister_self())
+            .and_then(|record| record.get_cycles().as_ref().map(|cycles| cycles.amount))
+            .unwrap_or_else(canister_cycle_balance);
+
+        TreasuryForecast {
+            funder_cycles,
+            sources: inner
+                .treasury_runway
+                .as_ref()
+                .map(|treasury_runway| treasury_runway.sources.clone())
+                .unwrap_or_default(),
+            cycles_per_sec: inner.total_average_consumption(),
+            forecast_at: time(),
+        }
+    }
+
+    /// Returns the cycles spent on the operations of all rounds, compared to the cycles delivered.
+    pub fn get_operation_costs(&self) -> OperationCosts {
+        self.inner.borrow().operation_costs.clone()
+    }
+
+    /// Returns the aggregate of the canisters in the group.
+    pub fn get_group_report(&self, group: &str) -> GroupReport {
+        let inner = self.inner.borrow();
+        GroupReport::from_records(
+            inner
+                .canisters
+                .values()
+                .filter(|record| record.get_group().as_deref() == Some(group)),
+        )
+    }
+
+    /// Returns the aggregate of the canisters with the tag.
+    pub fn get_tag_report(&self, tag: &str) -> GroupReport {
+        let inner = self.inner.borrow();
+        GroupReport::from_records(
+            inner
+                .canisters
+                .values()
+                .filter(|record| record.has_tag(tag)),
+        )
+    }
+
+    /// Returns the aggregate of the canisters of each group with registered canisters.
+    pub fn get_group_reports(&self) -> HashMap<String, GroupReport> {
+        let inner = self.inner.borrow();
+        let mut reports: HashMap<String, Vec<&CanisterRecord>> = HashMap::new();
+        for record in inner.canisters.values() {
+            if let Some(group) = record.get_group() {
+                reports.entry(group.clone()).or_default().push(record);
+            }
+        }
+
+        reports
+            .into_iter()
+            .map(|(group, records)| (group, GroupReport::from_records(records)))
+            .collect()
+    }
+
     /// Returns whether the fund manager has started tracking the canisters.
     pub fn is_running(&self) -> bool {
         self.tracker.is_some()
@@ -202,16 +465,27 @@ impl FundManager {
     async fn execute_scheduled_monitoring(manager: Rc<RefCell<FundManagerCore>>) {
         // Lock the process execution to prevent concurrent executions, it is dropped automatically
         // when it goes out of scope.
+        let process_id = "execute_scheduled_monitoring".as_bytes().to_vec();
         let _lock = {
-            manager.borrow_mut().lock.lock(
-                "execute_scheduled_monitoring"
-                    .to_string()
-                    .as_bytes()
-                    .to_vec(),
-            )
+            let mut manager_mut = manager.borrow_mut();
+
+            // Release the lock of a round that exceeded the maximum duration, e.g. because it trapped.
+            if let Some(max_round_duration_secs) = manager_mut.options.max_round_duration_secs() {
+                let max_round_duration_nanos =
+                    Duration::from_secs(max_round_duration_secs).as_nanos() as u64;
+                if manager_mut
+                    .lock
+                    .release_if_stale(&process_id, max_round_duration_nanos, time())
+                {
+                    debug_print("WARNING: Released the stale lock of `execute_scheduled_monitoring`, the previous round exceeded the maximum duration");
+                }
+            }
+
+            manager_mut.lock.lock(process_id)
         };
 
         if _lock.is_none() {
+            manager.borrow_mut().skipped_rounds += 1;
             debug_print("Failed to acquire lock for `execute_scheduled_monitoring`, another process is running");
             return;
         }
@@ -221,174 +495,683 @@ impl FundManager {
             record.reset_funding_failure();
         }
 
-        let (all_canister_ids, chunk_size) = {
+        manager.borrow_mut().register_funder();
+
+        let mut report = FundingRoundReport::new(time());
+        let funder_cycles_before = canister_cycle_balance();
+        let call_cycles_before = call_cycles();
+        let mut fetch_balances_cycles = 0u128;
+        let mut unmeasured_fetches = 0u64;
+
+        // Complete the cycles obtained by previous rounds before obtaining new ones.
+        Self::resume_pending_obtains(Rc::clone(&manager), &mut report).await;
+
+        // Check the capacity of the sources before obtaining cycles from them.
+        let treasury_min_runway_secs = manager.borrow().options.treasury_min_runway_secs();
+        if let Some(min_runway_secs) = treasury_min_runway_secs {
+            let (sources, cycles_per_sec) = {
+                let manager_ref = manager.borrow();
+                (
+                    manager_ref.obtain_cycles_sources(),
+                    manager_ref.total_average_consumption(),
+                )
+            };
+            let treasury_runway = TreasuryRunway::check(&sources, cycles_per_sec, time()).await;
+
+            for capacity in &treasury_runway.sources {
+                if let Some(error) = &capacity.error {
+                    debug_print(format!(
+                        "WARNING: Failed to check the capacity of the obtain cycles source `{}`: {}",
+                        capacity.source, error
+                    ));
+                }
+            }
+            if let Some(runway_secs) = treasury_runway.runway_secs() {
+                if runway_secs < min_runway_secs {
+                    debug_print(format!(
+                        "WARNING: The obtain cycles sources run dry in {}s at the current consumption, {} cycles are left",
+                        runway_secs,
+                        treasury_runway.available_cycles()
+                    ));
+                }
+            }
+
+            manager.borrow_mut().treasury_runway = Some(treasury_runway.clone());
+            report.treasury_runway = Some(treasury_runway);
+        }
+
+        let (funder_canister_ids, all_canister_ids, chunk_size) = {
             let manager_ref = manager.borrow();
-            let all_canister_ids: Vec<(CanisterId, Arc<dyn FetchCyclesBalance>)> = manager_ref
+            let (funder_canister_ids, all_canister_ids): (Vec<_>, Vec<_>) = manager_ref
                 .canisters
                 .iter()
                 .map(|(canister_id, canister_record)| {
                     (*canister_id, canister_record.get_cycles_fetcher())
                 })
-                .collect();
+                .partition(|(canister_id, _)| *canister_id == canister_self());
             let chunk_size = manager_ref.options.chunk_size();
-            (all_canister_ids, chunk_size)
+            (funder_canister_ids, all_canister_ids, chunk_size)
         };
 
-        for canister_ids in all_canister_ids.chunks(cmp::max(1, chunk_size as usize)) {
+        // The funding canister is monitored first, so that it is funded before it funds other canisters.
+        let chunks = std::iter::once(funder_canister_ids.as_slice())
+            .filter(|canister_ids| !canister_ids.is_empty())
+            .chain(all_canister_ids.chunks(cmp::max(1, chunk_size as usize)));
+
+        let obtain_batch_min_cycles = manager.borrow().options.obtain_batch_min_cycles();
+        let mut batched_canisters_to_fund = Vec::new();
+
+        for canister_ids in chunks {
+            // The balances are fetched concurrently, so their cost is measured for the whole chunk.
+            let chunk_cycles_before = canister_cycle_balance();
             let canisters_to_fund =
-                Self::monitor_specified_canisters(Rc::clone(&manager), canister_ids).await;
+                Self::monitor_specified_canisters(Rc::clone(&manager), canister_ids, &mut report)
+                    .await;
+            match chunk_cycles_before.checked_sub(canister_cycle_balance()) {
+                Some(spent_cycles) => {
+                    fetch_balances_cycles = fetch_balances_cycles.saturating_add(spent_cycles)
+                }
+                // Cycles received meanwhile hide the cost of the chunk.
+                None => unmeasured_fetches += 1,
+            }
+
+            // With batching, the canisters are funded once the needs of all canisters are known.
+            if obtain_batch_min_cycles.is_some() {
+                batched_canisters_to_fund.extend(canisters_to_fund);
+                continue;
+            }
 
             // Funds the canisters with the necessary cycles.
             for (canister_id, needed_cycles) in canisters_to_fund {
-                // Before transferring cycles from the funding canister, check if the funding canister actually has enough cycles.
-                let funding_canister_needs_cycles = canister_id != canister_self() && {
-                    // Get the current balance.
-                    let funding_canister_balance = ic_cdk::api::canister_cycle_balance();
-
-                    // Get the record of the funding canister if it exists, to access the previous cycles balance to calculate estimated runtime left.
-                    let maybe_funding_canister_record =
-                        manager.borrow().canisters.get(&canister_self()).cloned();
-
-                    // see if transferring cycles to the canister will make the funding canister run low of cycles
-                    let funding_canister_needed_cycles = calc_needed_cycles(
-                        &CyclesBalance::new(
-                            funding_canister_balance.saturating_sub(needed_cycles),
-                            time(),
-                        ),
-                        maybe_funding_canister_record
-                            .as_ref()
-                            .map_or(0, |record| record.get_average_consumption() as u128),
-                        &maybe_funding_canister_record
-                            .as_ref()
-                            .and_then(|record| record.get_strategy().clone())
-                            .unwrap_or_else(|| manager.borrow().options.strategy().clone()),
-                    );
-
-                    funding_canister_needed_cycles > 0
-                };
+                Self::fund_canister(
+                    Rc::clone(&manager),
+                    canister_id,
+                    needed_cycles,
+                    GlobalObtainCycles::Individually,
+                    &mut report,
+                )
+                .await;
+            }
+        }
 
-                // If either the funding canister is low on cycles,
-                // or it does not have enough cycles to fund another canister,
-                // then need to obtain cycles for the funding canister.
-                if canister_id == canister_self() || funding_canister_needs_cycles {
-                    let maybe_obtain_cycles = manager
-                        .borrow()
-                        .canisters
-                        .get(&canister_id)
-                        .and_then(|record| record.get_obtain_cycles_options().clone())
-                        .or_else(|| manager.borrow().options.obtain_cycles_options().clone());
+        if let Some(min_cycles) = obtain_batch_min_cycles {
+            let batch = Self::obtain_batched_cycles(
+                Rc::clone(&manager),
+                &batched_canisters_to_fund,
+                min_cycles,
+                &mut report,
+            )
+            .await;
 
-                    if let Some(obtain_cycles_options) = maybe_obtain_cycles {
-                        ic_cdk::println!(
-                            "Topping up {} with {} cycles",
-                            canister_id,
-                            needed_cycles
-                        );
+            for (canister_id, needed_cycles) in batched_canisters_to_fund {
+                if canister_id == canister_self() && batch.covers_funder {
+                    continue;
+                }
 
-                        let mut tries_left = 4;
-                        while tries_left > 0 {
-                            tries_left -= 1;
-                            match obtain_cycles_options
-                                .obtain_cycles
-                                .obtain_cycles(needed_cycles, canister_id)
-                                .await
-                            {
-                                Ok(cycles_obtained) => {
-                                    if let Some(record) =
-                                        manager.borrow_mut().canisters.get_mut(&canister_id)
-                                    {
-                                        record.add_deposited_cycles(CyclesBalance::new(
-                                            cycles_obtained,
-                                            time(),
-                                        ));
-                                        debug_print(format!(
-                                            "Successfully obtained {} cycles for canister {}",
-                                            cycles_obtained,
-                                            canister_id.to_text()
-                                        ));
-                                    } else {
-                                        debug_print(format!(
-                                            "Warning: Obtained {} cycles but canister {} not found in records",
-                                            cycles_obtained,
-                                            canister_id.to_text()
-                                        ));
-                                    }
-                                    break;
-                                }
-                                Err(error) => {
-                                    debug_print(format!(
-                                        "Failed to obtain {} cycles for canister {}, err: {}",
-                                        needed_cycles,
-                                        canister_id.to_text(),
-                                        error.details
-                                    ));
-
-                                    if error.can_retry && tries_left > 0 {
-                                        debug_print("Retrying to obtain cycles...");
-                                        continue;
-                                    } else if let Some(record) =
-                                        manager.borrow_mut().canisters.get_mut(&canister_id)
-                                    {
-                                        record.set_funding_failure(
-                                            FundingErrorCode::ObtainCyclesFailed,
-                                            time(),
-                                        );
-                                    }
-                                    break;
-                                }
-                            }
-                        }
+                // The global obtain cycles options were already used by the batch.
+                Self::fund_canister(
+                    Rc::clone(&manager),
+                    canister_id,
+                    needed_cycles,
+                    if batch.failed {
+                        GlobalObtainCycles::BatchFailed
                     } else {
-                        if funding_canister_needs_cycles {
-                            debug_print(format!("WARNING: Could not top up canister {}. Funding canister is low on cycles.", canister_id.to_text()));
-                        }
+                        GlobalObtainCycles::Batched
+                    },
+                    &mut report,
+                )
+                .await;
+            }
+        }
 
-                        debug_print("WARNING: No top-up method configured for topping up the funding canister. Consider configuring `obtain_cycles_options`.");
+        report.funder_cycles = manager
+            .borrow()
+            .canisters
+            .get(&canister_self())
+            .and_then(|record| record.get_cycles().clone());
+        report.completed_at = time();
+
+        let call_cycles_after = call_cycles();
+        let cost = RoundCost {
+            funder_cycles_before,
+            funder_cycles_after: canister_cycle_balance(),
+            received_cycles: report.obtained_cycles_for(canister_self()),
+            deposited_cycles: report.deposited_cycles,
+            obtained_for_canisters_cycles: report
+                .obtained_cycles
+                .iter()
+                .filter(|obtained| obtained.canister_id != canister_self())
+                .map(|obtained| obtained.amount)
+                .sum(),
+            operations: OperationCycles {
+                fetch_balances_cycles,
+                ledger_cycles: call_cycles_after
+                    .ledger
+                    .saturating_sub(call_cycles_before.ledger),
+                cmc_cycles: call_cycles_after.cmc.saturating_sub(call_cycles_before.cmc),
+                unmeasured_operations: unmeasured_fetches.saturating_add(
+                    call_cycles_after
+                        .unmeasured
+                        .saturating_sub(call_cycles_before.unmeasured),
+                ),
+            },
+        };
+        manager.borrow_mut().operation_costs.add(&cost);
+        report.cost = Some(cost);
 
-                        if let Some(record) = manager.borrow_mut().canisters.get_mut(&canister_id) {
-                            record
-                                .set_funding_failure(FundingErrorCode::InsufficientCycles, time());
-                        }
-                    }
-                } else {
-                    match deposit_cycles(&DepositCyclesArgs { canister_id }, needed_cycles).await {
-                        Err(err) => {
+        manager.borrow_mut().last_round_report = Some(report);
+
+        // Execute funding callback after the canisters have been funded.
+        manager.borrow().funding_callback();
+    }
+
+    /// Funds the canister with the needed cycles, obtaining cycles if the funding canister cannot spare
+    /// them.
+    ///
+    /// The global obtain cycles options are only used to obtain the cycles of the canister
+    /// individually, otherwise only the options of the canister record are.
+    async fn fund_canister(
+        manager: Rc<RefCell<FundManagerCore>>,
+        canister_id: CanisterId,
+        needed_cycles: u128,
+        global_obtain_cycles: GlobalObtainCycles,
+        report: &mut FundingRoundReport,
+    ) {
+        // Cap the cycles to the budget the canister or its group has left for the round.
+        let remaining_budget = manager.borrow().remaining_budget(canister_id, report);
+        let needed_cycles = match remaining_budget {
+            Some(0) => {
+                debug_print(format!(
+                    "WARNING: Not funding canister {}, the budget of the canister or its group for the round is used up",
+                    canister_id.to_text()
+                ));
+                if let Some(record) = manager.borrow_mut().canisters.get_mut(&canister_id) {
+                    record.set_funding_failure(FundingErrorCode::GroupBudgetExceeded, time());
+                }
+                return;
+            }
+            Some(remaining_budget) => cmp::min(needed_cycles, remaining_budget),
+            None => needed_cycles,
+        };
+
+        let available_cycles = Self::funder_available_cycles(&manager);
+
+        // Before transferring cycles from the funding canister, check if the funding canister actually has enough cycles.
+        let funding_canister_needs_cycles =
+            canister_id != canister_self() && needed_cycles > available_cycles;
+
+        // If either the funding canister is low on cycles,
+        // or it does not have enough cycles to fund another canister,
+        // then need to obtain cycles for the funding canister.
+        if canister_id == canister_self() || funding_canister_needs_cycles {
+            let maybe_obtain_cycles = manager
+                .borrow()
+                .canisters
+                .get(&canister_id)
+                .and_then(|record| {
+                    manager
+                        .borrow()
+                        .options
+                        .canister_obtain_cycles_options(record)
+                })
+                .or_else(|| {
+                    (global_obtain_cycles == GlobalObtainCycles::Individually)
+                        .then(|| manager.borrow().options.obtain_cycles_options())
+                        .flatten()
+                });
+
+            if let Some(obtain_cycles_options) = maybe_obtain_cycles {
+                ic_cdk::println!("Topping up {} with {} cycles", canister_id, needed_cycles);
+
+                let obtain_retry_policy = manager.borrow().options.obtain_retry_policy().clone();
+                let (pending_mints, pending_withdrawals) = {
+                    let manager_ref = manager.borrow();
+                    (
+                        manager_ref.pending_mints.clone(),
+                        manager_ref.pending_withdrawals.clone(),
+                    )
+                };
+
+                match obtain_cycles_from_sources(
+                    obtain_cycles_options.sources(),
+                    &obtain_retry_policy,
+                    needed_cycles,
+                    canister_id,
+                    report,
+                    &pending_mints,
+                    &pending_withdrawals,
+                    |source| {
+                        manager.borrow_mut().record_intent(
+                            canister_id,
+                            FundingIntentKind::Obtain {
+                                source: source.to_string(),
+                            },
+                            needed_cycles,
+                        )
+                    },
+                )
+                .await
+                {
+                    Ok(obtained_cycles) => {
+                        let mut manager_mut = manager.borrow_mut();
+                        manager_mut.intents.remove(&canister_id);
+
+                        if let Some(record) = manager_mut.canisters.get_mut(&canister_id) {
+                            record.add_deposited_cycles(CyclesBalance::new(
+                                obtained_cycles.amount,
+                                time(),
+                            ));
                             debug_print(format!(
-                                "Failed to fund canister {} with {} cycles, error: {}",
+                                "Successfully obtained {} cycles for canister {} from source {}",
+                                obtained_cycles.amount,
                                 canister_id.to_text(),
-                                needed_cycles,
-                                err,
+                                obtained_cycles.source
                             ));
-
-                            if let Some(record) =
-                                manager.borrow_mut().canisters.get_mut(&canister_id)
-                            {
-                                record.set_funding_failure(FundingErrorCode::DepositFailed, time());
-                            }
-                        }
-                        Ok(_) => {
+                        } else {
                             debug_print(format!(
-                                "Funded canister {} with {} cycles",
-                                canister_id.to_text(),
-                                needed_cycles
+                                "Warning: Obtained {} cycles but canister {} not found in records",
+                                obtained_cycles.amount,
+                                canister_id.to_text()
                             ));
+                        }
 
-                            if let Some(record) =
-                                manager.borrow_mut().canisters.get_mut(&canister_id)
-                            {
-                                record.add_deposited_cycles(CyclesBalance::new(
-                                    needed_cycles,
+                        manager_mut.record_delivered_cycles(
+                            canister_id,
+                            obtained_cycles.amount,
+                            report,
+                        );
+                        drop(manager_mut);
+                        report.obtained_cycles.push(obtained_cycles);
+                    }
+                    Err(error) => {
+                        debug_print(format!(
+                            "Failed to obtain {} cycles for canister {}, err: {}",
+                            needed_cycles,
+                            canister_id.to_text(),
+                            error.details
+                        ));
+
+                        {
+                            let mut manager_mut = manager.borrow_mut();
+                            manager_mut.settle_failed_obtain_intent(canister_id, error.can_retry);
+
+                            if let Some(record) = manager_mut.canisters.get_mut(&canister_id) {
+                                record.set_funding_failure(
+                                    FundingErrorCode::ObtainCyclesFailed,
                                     time(),
-                                ));
+                                );
                             }
                         }
+
+                        if funding_canister_needs_cycles {
+                            let available_cycles = Self::funder_available_cycles(&manager);
+                            Self::partially_fund_canister(
+                                manager,
+                                canister_id,
+                                needed_cycles,
+                                available_cycles,
+                                report,
+                            )
+                            .await;
+                        }
                     }
                 }
+            } else {
+                if funding_canister_needs_cycles {
+                    debug_print(format!(
+                        "WARNING: Could not top up canister {}. Funding canister is low on cycles.",
+                        canister_id.to_text()
+                    ));
+                }
+
+                // The cycles of the canister were part of the batch that could not be obtained.
+                let error_code = if global_obtain_cycles == GlobalObtainCycles::BatchFailed {
+                    FundingErrorCode::ObtainCyclesFailed
+                } else {
+                    debug_print("WARNING: No top-up method configured for topping up the funding canister. Consider configuring `obtain_cycles_options`.");
+
+                    FundingErrorCode::InsufficientCycles
+                };
+
+                if let Some(record) = manager.borrow_mut().canisters.get_mut(&canister_id) {
+                    record.set_funding_failure(error_code, time());
+                }
+
+                if funding_canister_needs_cycles {
+                    Self::partially_fund_canister(
+                        manager,
+                        canister_id,
+                        needed_cycles,
+                        available_cycles,
+                        report,
+                    )
+                    .await;
+                }
             }
+        } else {
+            Self::deposit_to_canister(Rc::clone(&manager), canister_id, needed_cycles, report)
+                .await;
         }
+    }
 
-        // Execute funding callback after the canisters have been funded.
-        manager.borrow().funding_callback();
+    /// Deposits the part of the needed cycles that the funding canister can safely spare, to keep
+    /// the canister running until more cycles are available.
+    async fn partially_fund_canister(
+        manager: Rc<RefCell<FundManagerCore>>,
+        canister_id: CanisterId,
+        needed_cycles: u128,
+        available_cycles: u128,
+        report: &mut FundingRoundReport,
+    ) {
+        if available_cycles == 0 || available_cycles >= needed_cycles {
+            return;
+        }
+
+        debug_print(format!(
+            "WARNING: Partially funding canister {} with {} of {} cycles",
+            canister_id.to_text(),
+            available_cycles,
+            needed_cycles
+        ));
+
+        let deposited =
+            Self::deposit_to_canister(Rc::clone(&manager), canister_id, available_cycles, report)
+                .await;
+
+        if deposited {
+            if let Some(record) = manager.borrow_mut().canisters.get_mut(&canister_id) {
+                record.set_funding_failure(
+                    FundingErrorCode::PartiallyFunded {
+                        shortfall: needed_cycles - available_cycles,
+                    },
+                    time(),
+                );
+            }
+        }
+    }
+
+    /// Returns the cycles the funding canister can deposit to other canisters.
+    fn funder_available_cycles(manager: &Rc<RefCell<FundManagerCore>>) -> u128 {
+        // Get the current balance.
+        let funding_canister_balance = canister_cycle_balance();
+
+        // Get the record of the funding canister if it exists, to access the previous cycles balance to calculate estimated runtime left.
+        let maybe_funding_canister_record =
+            manager.borrow().canisters.get(&canister_self()).cloned();
+
+        let funding_canister_cycles_per_sec = maybe_funding_canister_record
+            .as_ref()
+            .map_or(0, |record| record.get_average_consumption() as u128);
+
+        // The cycles that can be deposited without going below the reserve of the funding canister,
+        // or without making the funding canister run low of cycles according to its strategy.
+        match manager.borrow().options.funder_reserve() {
+            Some(reserve) => {
+                reserve.available_cycles(funding_canister_balance, funding_canister_cycles_per_sec)
+            }
+            None => calc_available_cycles(
+                funding_canister_balance,
+                funding_canister_cycles_per_sec,
+                &maybe_funding_canister_record.as_ref().map_or_else(
+                    || manager.borrow().options.strategy().clone(),
+                    |record| manager.borrow().options.canister_strategy(record).clone(),
+                ),
+            ),
+        }
+    }
+
+    /// Obtains the cycles all canisters funded with the global obtain cycles options are short of, at
+    /// once for the funding canister, so that the cycles are then deposited from its balance.
+    ///
+    /// At least `min_cycles` are obtained if any cycles are needed.
+    async fn obtain_batched_cycles(
+        manager: Rc<RefCell<FundManagerCore>>,
+        canisters_to_fund: &[(CanisterId, u128)],
+        min_cycles: u128,
+        report: &mut FundingRoundReport,
+    ) -> BatchOutcome {
+        let funding_canister_id = canister_self();
+        let Some(obtain_cycles_options) = manager.borrow().options.obtain_cycles_options() else {
+            return BatchOutcome::default();
+        };
+
+        // Canisters with their own obtain cycles options obtain their cycles individually.
+        let (funder_needed_cycles, others_needed_cycles, includes_funder) = {
+            let manager_ref = manager.borrow();
+            let mut funder_needed_cycles = 0u128;
+            let mut others_needed_cycles = 0u128;
+            let mut includes_funder = false;
+
+            for (canister_id, needed_cycles) in canisters_to_fund {
+                let has_own_options =
+                    manager_ref
+                        .canisters
+                        .get(canister_id)
+                        .is_some_and(|record| {
+                            manager_ref
+                                .options
+                                .canister_obtain_cycles_options(record)
+                                .is_some()
+                        });
+                if has_own_options {
+                    continue;
+                }
+
+                if *canister_id == funding_canister_id {
+                    funder_needed_cycles = funder_needed_cycles.saturating_add(*needed_cycles);
+                    includes_funder = true;
+                } else {
+                    others_needed_cycles = others_needed_cycles.saturating_add(*needed_cycles);
+                }
+            }
+
+            (funder_needed_cycles, others_needed_cycles, includes_funder)
+        };
+
+        let batch_cycles = calc_batch_cycles(
+            funder_needed_cycles,
+            others_needed_cycles,
+            Self::funder_available_cycles(&manager),
+            min_cycles,
+        );
+        if batch_cycles == 0 {
+            return BatchOutcome {
+                covers_funder: includes_funder,
+                failed: false,
+            };
+        }
+
+        debug_print(format!(
+            "Obtaining a batch of {} cycles for the funding canister",
+            batch_cycles
+        ));
+
+        let obtain_retry_policy = manager.borrow().options.obtain_retry_policy().clone();
+        let (pending_mints, pending_withdrawals) = {
+            let manager_ref = manager.borrow();
+            (
+                manager_ref.pending_mints.clone(),
+                manager_ref.pending_withdrawals.clone(),
+            )
+        };
+        let result = obtain_cycles_from_sources(
+            obtain_cycles_options.sources(),
+            &obtain_retry_policy,
+            batch_cycles,
+            funding_canister_id,
+            report,
+            &pending_mints,
+            &pending_withdrawals,
+            |source| {
+                manager.borrow_mut().record_intent(
+                    funding_canister_id,
+                    FundingIntentKind::Obtain {
+                        source: source.to_string(),
+                    },
+                    batch_cycles,
+                )
+            },
+        )
+        .await;
+
+        let mut manager_mut = manager.borrow_mut();
+        let failed = result.is_err();
+
+        match result {
+            Ok(obtained_cycles) => {
+                manager_mut.intents.remove(&funding_canister_id);
+
+                if let Some(record) = manager_mut.canisters.get_mut(&funding_canister_id) {
+                    record.add_deposited_cycles(CyclesBalance::new(obtained_cycles.amount, time()));
+                }
+                manager_mut.record_delivered_cycles(
+                    funding_canister_id,
+                    obtained_cycles.amount,
+                    report,
+                );
+
+                report.obtained_cycles.push(obtained_cycles);
+            }
+            Err(error) => {
+                debug_print(format!(
+                    "Failed to obtain a batch of {} cycles, err: {}",
+                    batch_cycles, error.details
+                ));
+
+                manager_mut.settle_failed_obtain_intent(funding_canister_id, error.can_retry);
+
+                if let Some(record) = manager_mut.canisters.get_mut(&funding_canister_id) {
+                    record.set_funding_failure(FundingErrorCode::ObtainCyclesFailed, time());
+                }
+            }
+        }
+
+        BatchOutcome {
+            covers_funder: includes_funder,
+            failed,
+        }
+    }
+
+    /// Deposits the cycles from the funding canister to the canister and accounts them.
+    ///
+    /// Returns whether the cycles were deposited.
+    async fn deposit_to_canister(
+        manager: Rc<RefCell<FundManagerCore>>,
+        canister_id: CanisterId,
+        cycles: u128,
+        report: &mut FundingRoundReport,
+    ) -> bool {
+        let deposit_retry_policy = manager.borrow().options.deposit_retry_policy().clone();
+        let deposit_call_wait = manager.borrow().options.deposit_call_wait();
+        let deposit_args = DepositCyclesArgs { canister_id };
+
+        manager
+            .borrow_mut()
+            .record_intent(canister_id, FundingIntentKind::Deposit, cycles);
+
+        match deposit_retry_policy
+            .retry(RetryOperation::DepositCycles, || {
+                deposit_cycles(deposit_call_wait, &deposit_args, cycles)
+            })
+            .await
+        {
+            Err(err) => {
+                debug_print(format!(
+                    "Failed to fund canister {} with {} cycles, error: {}",
+                    canister_id.to_text(),
+                    cycles,
+                    err,
+                ));
+
+                let mut manager_mut = manager.borrow_mut();
+
+                // Keep the intent if the deposit may have happened, to reconcile it
+                // with the balance in the next round.
+                if err.is_clean_reject() {
+                    manager_mut.intents.remove(&canister_id);
+                }
+
+                if let Some(record) = manager_mut.canisters.get_mut(&canister_id) {
+                    record.set_funding_failure(FundingErrorCode::DepositFailed, time());
+                }
+
+                false
+            }
+            Ok(_) => {
+                debug_print(format!(
+                    "Funded canister {} with {} cycles",
+                    canister_id.to_text(),
+                    cycles
+                ));
+
+                report.deposited_cycles = report.deposited_cycles.saturating_add(cycles);
+
+                let mut manager_mut = manager.borrow_mut();
+                manager_mut.intents.remove(&canister_id);
+
+                if let Some(record) = manager_mut.canisters.get_mut(&canister_id) {
+                    record.add_deposited_cycles(CyclesBalance::new(cycles, time()));
+                }
+                manager_mut.record_delivered_cycles(canister_id, cycles, report);
+
+                true
+            }
+        }
+    }
+
+    /// Resumes the operations left pending by the obtain cycles sources in previous rounds, e.g. CMC
+    /// notifications that did not complete, and records the obtained cycles.
+    async fn resume_pending_obtains(
+        manager: Rc<RefCell<FundManagerCore>>,
+        report: &mut FundingRoundReport,
+    ) {
+        let (sources, pending_mints) = {
+            let manager_ref = manager.borrow();
+            (
+                manager_ref.obtain_cycles_sources(),
+                manager_ref.pending_mints.clone(),
+            )
+        };
+
+        for source in sources {
+            for (canister_id, receipt) in
+                source.obtain_cycles().resume_pending(&pending_mints).await
+            {
+                debug_print(format!(
+                    "Resumed obtaining {} cycles for canister {} from source {}",
+                    receipt.cycles,
+                    canister_id.to_text(),
+                    source.name()
+                ));
+
+                let mut manager_mut = manager.borrow_mut();
+
+                // The source completed the movement of the block index, which accounts the intent.
+                if manager_mut
+                    .intents
+                    .get(&canister_id)
+                    .is_some_and(|intent| intent.is_obtained_by(receipt.block_index))
+                {
+                    manager_mut.intents.remove(&canister_id);
+                }
+
+                if let Some(record) = manager_mut.canisters.get_mut(&canister_id) {
+                    record.add_deposited_cycles(CyclesBalance::new(receipt.cycles, time()));
+                }
+                manager_mut.record_delivered_cycles(canister_id, receipt.cycles, report);
+
+                drop(manager_mut);
+
+                report.obtained_cycles.push(ObtainedCycles {
+                    canister_id,
+                    source: source.name().to_string(),
+                    amount: receipt.cycles,
+                    block_index: receipt.block_index,
+                    timestamp: time(),
+                    mint: receipt.mint,
+                });
+            }
+        }
     }
 
     /// Fetches the cycles balance for the provided canisters and calculates the needed cycles to fund them.
@@ -397,6 +1180,7 @@ impl FundManager {
     async fn monitor_specified_canisters(
         manager: Rc<RefCell<FundManagerCore>>,
         canisters: &[(CanisterId, Arc<dyn FetchCyclesBalance>)],
+        report: &mut FundingRoundReport,
     ) -> Vec<(CanisterId, u128)> {
         let mut canisters_to_fund = Vec::new();
         let options = manager.borrow().options().clone();
@@ -411,6 +1195,22 @@ impl FundManager {
             match &results[i] {
                 Ok(cycles_balance) => {
                     let mut manager_mut = manager.borrow_mut();
+
+                    // Account the movement of a previous round that trapped before accounting it, so
+                    // that the consumption is not skewed by the unaccounted cycles.
+                    if let Some(intent) = manager_mut.reconcile_intent(
+                        *canister_id,
+                        &CyclesBalance::new(*cycles_balance, current_time),
+                    ) {
+                        debug_print(format!(
+                            "Reconciled {} cycles moved to canister {} by a previous round",
+                            intent.amount,
+                            canister_id.to_text()
+                        ));
+
+                        report.reconciled_intents.push(intent);
+                    }
+
                     if let Entry::Occupied(mut entry) = manager_mut.canisters.entry(*canister_id) {
                         let canister_record = entry.get_mut();
 
@@ -420,10 +1220,7 @@ impl FundManager {
                         let needed_cycles = calc_needed_cycles(
                             &canister_record.get_cycles().clone().unwrap_or_default(),
                             canister_record.get_average_consumption() as u128,
-                            canister_record
-                                .get_strategy()
-                                .as_ref()
-                                .unwrap_or_else(|| options.strategy()),
+                            options.canister_strategy(canister_record),
                         );
 
                         if needed_cycles > 0 {
@@ -450,11 +1247,105 @@ impl FundManager {
 }
 
 impl FundManagerCore {
+    /// Returns the distinct obtain cycles sources of the global and the canister specific options.
+    fn obtain_cycles_sources(&self) -> Vec<ObtainCyclesSource> {
+        let mut sources: Vec<ObtainCyclesSource> = Vec::new();
+        let global_options = self.options.obtain_cycles_options();
+        let group_options = self
+            .options
+            .groups()
+            .values()
+            .filter_map(|group| group.obtain_cycles_options());
+        let options = global_options.iter().chain(group_options).chain(
+            self.canisters
+                .values()
+                .filter_map(|record| record.get_obtain_cycles_options().as_ref()),
+        );
+
+        for source in options.flat_map(|options| options.sources()) {
+            if !sources
+                .iter()
+                .any(|known| Arc::ptr_eq(&known.obtain_cycles(), &source.obtain_cycles()))
+            {
+                sources.push(source.clone());
+            }
+        }
+
+        sources
+    }
+
+    /// Returns the sum of the average consumption of the monitored canisters in cycles per second.
+    fn total_average_consumption(&self) -> u128 {
+        self.canisters
+            .values()
+            .map(|record| record.get_average_consumption() as u128)
+            .sum()
+    }
+
+    /// Returns the cycles that can still be delivered to the canister during the round, if the
+    /// canister or its group has a budget.
+    ///
+    /// The budget of the canister overrides the one of its group.
+    fn remaining_budget(
+        &self,
+        canister_id: CanisterId,
+        report: &FundingRoundReport,
+    ) -> Option<u128> {
+        let record = self.canisters.get(&canister_id)?;
+        if let Some(max_cycles_per_round) = record.get_max_cycles_per_round() {
+            let delivered_cycles = report
+                .delivered_cycles
+                .get(&canister_id)
+                .copied()
+                .unwrap_or_default();
+
+            return Some(max_cycles_per_round.saturating_sub(delivered_cycles));
+        }
+
+        let group = record.get_group().as_deref()?;
+        let max_cycles_per_round = self.options.group(group)?.max_cycles_per_round()?;
+        let delivered_cycles = report
+            .group_delivered_cycles
+            .get(group)
+            .copied()
+            .unwrap_or_default();
+
+        Some(max_cycles_per_round.saturating_sub(delivered_cycles))
+    }
+
+    /// Records the cycles delivered to the canister during the round, towards the budgets of the
+    /// canister and its group.
+    fn record_delivered_cycles(
+        &self,
+        canister_id: CanisterId,
+        cycles: u128,
+        report: &mut FundingRoundReport,
+    ) {
+        let delivered_cycles = report.delivered_cycles.entry(canister_id).or_default();
+        *delivered_cycles = delivered_cycles.saturating_add(cycles);
+
+        if let Some(group) = self
+            .canisters
+            .get(&canister_id)
+            .and_then(|record| record.get_group().clone())
+        {
+            let delivered_cycles = report.group_delivered_cycles.entry(group).or_default();
+            *delivered_cycles = delivered_cycles.saturating_add(cycles);
+        }
+    }
+
     pub fn new() -> Rc<RefCell<Self>> {
         Rc::new(RefCell::new(FundManagerCore {
             canisters: HashMap::new(),
             options: FundManagerOptions::default(),
             lock: ProcessExecutionLock::new(),
+            last_round_report: None,
+            skipped_rounds: 0,
+            intents: HashMap::new(),
+            pending_mints: PendingMints::default(),
+            pending_withdrawals: PendingWithdrawals::default(),
+            treasury_runway: None,
+            operation_costs: OperationCosts::default(),
         }))
     }
 
@@ -463,31 +1354,46 @@ impl FundManagerCore {
         &self.options
     }
 
+    /// Returns the number of consumption samples to keep for a canister registered with the options.
+    fn history_window_size(&self, opts: &RegisterOpts) -> usize {
+        let group_strategy = opts
+            .group
+            .as_deref()
+            .and_then(|group| self.options.group(group))
+            .and_then(|group| group.strategy());
+
+        match opts
+            .strategy
+            .as_ref()
+            .or(group_strategy)
+            .unwrap_or_else(|| self.options.strategy())
+        {
+            FundStrategy::BelowEstimatedRuntime(estimated_runtime) => {
+                (estimated_runtime.min_runtime_secs() / self.options.interval_secs()) as usize
+            }
+            _ => 0,
+        }
+    }
+
     /// Register a canister to be monitored by the fund manager.
     ///
     /// If the canister is already registered, it will be ignored.
     pub fn register(&mut self, canister_id: CanisterId, opts: RegisterOpts) {
-        let history_window_size = match &opts.strategy {
-            Some(FundStrategy::BelowEstimatedRuntime(estimated_runtime)) => {
-                estimated_runtime.min_runtime_secs() / self.options.interval_secs()
-            }
-            None => match self.options.strategy() {
-                FundStrategy::BelowEstimatedRuntime(estimated_runtime) => {
-                    estimated_runtime.min_runtime_secs() / self.options.interval_secs()
-                }
-                _ => 0,
-            },
-            _ => 0,
-        };
+        let history_window_size = self.history_window_size(&opts);
 
         match self.canisters.entry(canister_id) {
             Entry::Vacant(entry) => {
-                entry.insert(CanisterRecord::new(
-                    opts.cycles_fetcher,
-                    opts.strategy,
-                    opts.obtain_cycles_options,
-                    history_window_size as usize,
-                ));
+                entry.insert(
+                    CanisterRecord::new(
+                        opts.cycles_fetcher,
+                        opts.strategy,
+                        opts.obtain_cycles_options,
+                        history_window_size,
+                    )
+                    .with_group(opts.group)
+                    .with_max_cycles_per_round(opts.max_cycles_per_round)
+                    .with_tags(opts.tags),
+                );
             }
             Entry::Occupied(_) => {
                 // The canister is already registered so ignore.
@@ -499,9 +1405,166 @@ impl FundManagerCore {
     ///
     /// Returns the canister record if it was found.
     pub fn unregister(&mut self, canister_id: CanisterId) -> Option<CanisterRecord> {
+        self.intents.remove(&canister_id);
         self.canisters.remove(&canister_id)
     }
 
+    /// Reconciles the registered canisters with the desired ones, see `FundManager::sync`.
+    pub fn sync(
+        &mut self,
+        canisters: impl IntoIterator<Item = (CanisterId, RegisterOpts)>,
+    ) -> RegistrationDiff {
+        let mut diff = RegistrationDiff::default();
+        let mut desired = Vec::new();
+
+        for (canister_id, opts) in canisters {
+            desired.push(canister_id);
+            let history_window_size = self.history_window_size(&opts);
+
+            match self.canisters.get_mut(&canister_id) {
+                Some(record) => {
+                    if registration_changed(record, &opts) {
+                        diff.updated.push(canister_id);
+                    } else {
+                        diff.unchanged.push(canister_id);
+                    }
+                    record.update(opts, history_window_size);
+                }
+                None => {
+                    self.register(canister_id, opts);
+                    diff.added.push(canister_id);
+                }
+            }
+        }
+
+        let funder_id = canister_self();
+        let keep_funder = self.options.funder_options().is_some();
+        let removed: Vec<_> = self
+            .canisters
+            .keys()
+            .filter(|canister_id| !desired.contains(canister_id))
+            .filter(|canister_id| !(keep_funder && **canister_id == funder_id))
+            .copied()
+            .collect();
+        for canister_id in removed {
+            self.unregister(canister_id);
+            diff.removed.push(canister_id);
+        }
+
+        diff.sorted()
+    }
+
+    /// Registers the funding canister with its own options if it monitors itself.
+    ///
+    /// If the funding canister is already registered, it will be ignored.
+    fn register_funder(&mut self) {
+        let Some(funder_options) = self.options.funder_options().cloned() else {
+            return;
+        };
+
+        let mut opts = RegisterOpts::new();
+        if let Some(strategy) = funder_options.strategy() {
+            opts = opts.with_strategy(strategy.clone());
+        }
+        if let Some(obtain_cycles_options) = funder_options.obtain_cycles_options() {
+            opts = opts.with_obtain_cycles_options(obtain_cycles_options.clone());
+        }
+
+        self.register(canister_self(), opts);
+    }
+
+    /// Records the intent to move cycles to the canister before the movement is started.
+    ///
+    /// The intent is committed together with the state before the first await of the movement, so
+    /// that it survives a trap happening before the movement is accounted.
+    fn record_intent(&mut self, canister_id: CanisterId, kind: FundingIntentKind, amount: u128) {
+        let balance_before = self
+            .canisters
+            .get(&canister_id)
+            .and_then(|record| record.get_cycles().clone());
+
+        self.intents.insert(
+            canister_id,
+            FundingIntent {
+                canister_id,
+                kind,
+                amount,
+                balance_before,
+                block_index: None,
+                created_at: time(),
+            },
+        );
+    }
+
+    /// Settles the intent of an obtain that failed, keeping it if the outcome is unknown so that it
+    /// is reconciled later, together with the block index of the pending mint of the canister if its
+    /// transfer is known.
+    fn settle_failed_obtain_intent(&mut self, canister_id: CanisterId, can_retry: bool) {
+        if !can_retry {
+            self.intents.remove(&canister_id);
+            return;
+        }
+
+        let block_index = self
+            .pending_mints
+            .pending()
+            .into_iter()
+            .find(|mint| mint.target_canister_id == canister_id && mint.kind == MintKind::TopUp)
+            .and_then(|mint| mint.block_index);
+        if let Some(intent) = self.intents.get_mut(&canister_id) {
+            intent.block_index = block_index;
+        }
+    }
+
+    /// Reconciles the intent left for the canister by a previous round, and accounts the cycles if
+    /// the movement completed.
+    ///
+    /// Obtained cycles are accounted when the source completes the movement of the block index of
+    /// the intent, see `resume_pending_obtains`, so an obtain intent is only dropped here once its
+    /// block index is no longer pending, e.g. because the mint was stranded, or once it expires. A
+    /// deposit is accounted if it shows in the fetched balance, and is kept until it expires
+    /// otherwise, as the deposit call may still complete.
+    ///
+    /// Returns the intent if its cycles were accounted.
+    fn reconcile_intent(
+        &mut self,
+        canister_id: CanisterId,
+        balance: &CyclesBalance,
+    ) -> Option<FundingIntent> {
+        let intent = self.intents.get(&canister_id)?;
+        let Some(record) = self.canisters.get_mut(&canister_id) else {
+            self.intents.remove(&canister_id);
+            return None;
+        };
+
+        if let FundingIntentKind::Obtain { .. } = intent.kind {
+            let block_pending = intent.block_index.is_some_and(|block_index| {
+                self.pending_mints
+                    .pending()
+                    .iter()
+                    .any(|mint| mint.block_index == Some(block_index))
+            });
+            if (intent.block_index.is_some() && !block_pending)
+                || intent.is_expired(balance.timestamp)
+            {
+                self.intents.remove(&canister_id);
+            }
+            return None;
+        }
+
+        if !intent.is_settled_by(balance, record.get_average_consumption() as u128) {
+            if intent.is_expired(balance.timestamp) {
+                self.intents.remove(&canister_id);
+            }
+            return None;
+        }
+
+        let intent = self.intents.remove(&canister_id)?;
+        record.add_deposited_cycles(CyclesBalance::new(intent.amount, intent.created_at));
+
+        Some(intent)
+    }
+
     /// Executes the funding callback if it is set in the options.
     pub fn funding_callback(&self) {
         if let Some(funding_callback) = self.options.funding_callback() {
@@ -510,6 +1573,133 @@ impl FundManagerCore {
     }
 }
 
+/// Obtains the cycles from the sources in order, moving on to the next source when one fails after its
+/// retries or its limit for the round would be exceeded.
+///
+/// `record_attempt` is called with the name of each source before its cycles are obtained, to record
+/// the intent of the attempt. Once the outcome of a source is unknown, its intent is kept and the
+/// following sources are not recorded, so that the intent is reconciled with the right source.
+#[allow(clippy::too_many_arguments)]
+async fn obtain_cycles_from_sources(
+    sources: &[ObtainCyclesSource],
+    default_retry_policy: &RetryPolicy,
+    amount: u128,
+    canister_id: CanisterId,
+    report: &FundingRoundReport,
+    pending_mints: &PendingMints,
+    pending_withdrawals: &PendingWithdrawals,
+    mut record_attempt: impl FnMut(&str),
+) -> Result<ObtainedCycles, ObtainCyclesError> {
+    let mut errors = Vec::new();
+    let mut unknown_outcome = false;
+
+    for source in sources {
+        if let Some(max_cycles_per_round) = source.max_cycles_per_round() {
+            if report
+                .obtained_cycles_from(source.name())
+                .saturating_add(amount)
+                > max_cycles_per_round
+            {
+                errors.push(format!(
+                    "{}: limit of {} cycles per round exceeded",
+                    source.name(),
+                    max_cycles_per_round
+                ));
+                continue;
+            }
+        }
+
+        // Skip a source that is known to not cover the request instead of failing its transfer.
+        if let Some(remaining_capacity) = report.remaining_capacity_of(source.name()) {
+            if remaining_capacity < amount {
+                errors.push(format!(
+                    "{}: insufficient capacity of {} cycles",
+                    source.name(),
+                    remaining_capacity
+                ));
+                continue;
+            }
+        }
+
+        if !unknown_outcome {
+            record_attempt(source.name());
+        }
+
+        let obtain_cycles = source.obtain_cycles();
+        let retry_policy = source
+            .retry_policy()
+            .unwrap_or_else(|| default_retry_policy.clone());
+
+        match retry_policy
+            .retry(RetryOperation::ObtainCycles, || {
+                obtain_cycles.obtain_cycles_with_receipt(
+                    amount,
+                    canister_id,
+                    pending_mints,
+                    pending_withdrawals,
+                )
+            })
+            .await
+        {
+            Ok(receipt) => {
+                return Ok(ObtainedCycles {
+                    canister_id,
+                    source: source.name().to_string(),
+                    amount: receipt.cycles,
+                    block_index: receipt.block_index,
+                    timestamp: time(),
+                    mint: receipt.mint,
+                });
+            }
+            Err(error) => {
+                // the cycles may still be obtained from the source if its error can be retried
+                unknown_outcome |= error.can_retry;
+                errors.push(format!("{}: {}", source.name(), error.details));
+            }
+        }
+    }
+
+    Err(ObtainCyclesError {
+        details: errors.join("; "),
+        can_retry: unknown_outcome,
+    })
+}
+
+/// Returns whether the options change the strategy, obtain cycles sources, group or tags of the
+/// registered canister. The sources are compared by their names and options, as they are usually
+/// recreated with the same names on upgrade. The cycles fetcher is not compared.
+fn registration_changed(record: &CanisterRecord, opts: &RegisterOpts) -> bool {
+    let same_sources = match (
+        record.get_obtain_cycles_options(),
+        &opts.obtain_cycles_options,
+    ) {
+        (Some(current), Some(desired)) => {
+            current.sources().len() == desired.sources().len()
+                && current
+                    .sources()
+                    .iter()
+                    .zip(desired.sources())
+                    .all(|(current, desired)| {
+                        current.name() == desired.name()
+                            && current.max_cycles_per_round() == desired.max_cycles_per_round()
+                            && match (current.retry_policy(), desired.retry_policy()) {
+                                (Some(current), Some(desired)) => current.same_settings(&desired),
+                                (None, None) => true,
+                                _ => false,
+                            }
+                    })
+        }
+        (None, None) => true,
+        _ => false,
+    };
+
+    !same_sources
+        || record.get_strategy() != &opts.strategy
+        || record.get_group() != &opts.group
+        || record.get_max_cycles_per_round() != opts.max_cycles_per_round
+        || record.get_tags() != opts.tags.as_slice()
+}
+
 /// Calculates the needed cycles to fund the canister based on the current, previous cycles balance and
 /// the used strategy.
 fn calc_needed_cycles(
@@ -567,6 +1757,81 @@ fn calc_needed_cycles(
     }
 }
 
+/// How the global obtain cycles options are used to fund a canister.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GlobalObtainCycles {
+    /// The cycles are obtained for the canister on its own.
+    Individually,
+    /// The cycles were obtained in a batch for the funding canister.
+    Batched,
+    /// Obtaining the batch of cycles for the funding canister failed.
+    BatchFailed,
+}
+
+/// The outcome of obtaining the cycles of a round in a batch.
+#[derive(Clone, Copy, Debug, Default)]
+struct BatchOutcome {
+    /// Whether the needs of the funding canister itself were covered by the batch.
+    covers_funder: bool,
+    /// Whether obtaining the batch failed.
+    failed: bool,
+}
+
+/// Calculates the cycles to obtain at once for the funding canister, which are the cycles the funding
+/// canister needs itself and the cycles the other canisters need beyond what it can spare, but at
+/// least the min cycles of a batch.
+///
+/// Returns 0 if no cycles are needed.
+fn calc_batch_cycles(
+    funder_needed_cycles: u128,
+    others_needed_cycles: u128,
+    available_cycles: u128,
+    min_cycles: u128,
+) -> u128 {
+    let shortfall = others_needed_cycles
+        .saturating_sub(available_cycles)
+        .saturating_add(funder_needed_cycles);
+
+    if shortfall == 0 {
+        return 0;
+    }
+
+    cmp::max(shortfall, min_cycles)
+}
+
+/// Calculates the most cycles the funding canister can deposit without its remaining balance
+/// triggering its own funding strategy.
+fn calc_available_cycles(
+    balance: u128,
+    estimated_cycles_per_sec: u128,
+    strategy: &FundStrategy,
+) -> u128 {
+    let leaves_enough = |deposit: u128| {
+        calc_needed_cycles(
+            &CyclesBalance::new(balance - deposit, 0),
+            estimated_cycles_per_sec,
+            strategy,
+        ) == 0
+    };
+
+    if !leaves_enough(0) {
+        return 0;
+    }
+
+    // The needed cycles only grow as the balance shrinks, so the largest deposit can be searched for.
+    let (mut low, mut high) = (0, balance);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if leaves_enough(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    low
+}
+
 impl Drop for FundManager {
     /// Stops the fund manager tracking when the fund manager is dropped.
     fn drop(&mut self) {
@@ -576,9 +1841,54 @@ impl Drop for FundManager {
 
 #[cfg(test)]
 mod tests {
-    use tests::options::{CyclesThreshold, EstimatedRuntime};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use candid::Principal;
+    use tests::options::{CanisterGroup, CyclesThreshold, EstimatedRuntime, FunderOptions};
 
     use super::*;
+    use crate::api::cmc::{test::TestCmcCanister, NotifyError, NotifyTopUpResult};
+    use crate::api::ledger::test::{TestCyclesLedgerCanister, TestLedgerCanister};
+    use crate::operations::obtain::{MintCycles, ObtainCycles, WithdrawFromCyclesLedger};
+    use ic_cdk::call::Error;
+    use ic_ledger_types::Subaccount;
+    use icrc_ledger_types::icrc1::account::Account;
+
+    /// Fails with the configured error, or obtains the requested amount if there is none.
+    struct TestObtainCycles {
+        fails_with: Option<bool>,
+        calls: AtomicUsize,
+    }
+
+    impl TestObtainCycles {
+        fn new(fails_with: Option<bool>) -> Arc<Self> {
+            Arc::new(Self {
+                fails_with,
+                calls: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl ObtainCycles for TestObtainCycles {
+        async fn obtain_cycles(
+            &self,
+            amount: u128,
+            _target_canister_id: CanisterId,
+        ) -> Result<u128, ObtainCyclesError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            match self.fails_with {
+                Some(can_retry) => Err(ObtainCyclesError {
+                    details: "failed".to_string(),
+                    can_retry,
+                }),
+                None => Ok(amount),
+            }
+        }
+    }
 
     #[test]
     fn test_calc_needed_cycles() {
@@ -663,4 +1973,627 @@ mod tests {
 
         assert_eq!(calc_needed_cycles(&current, 0, &strategy), 100);
     }
+
+    #[test]
+    fn test_calc_available_cycles() {
+        let strategy = FundStrategy::BelowThreshold(
+            CyclesThreshold::new()
+                .with_min_cycles(100)
+                .with_fund_cycles(200),
+        );
+
+        assert_eq!(calc_available_cycles(1_000, 0, &strategy), 899);
+        assert_eq!(calc_available_cycles(100, 0, &strategy), 0);
+        assert_eq!(calc_available_cycles(1_000, 0, &FundStrategy::Always(1)), 0);
+
+        let strategy = FundStrategy::BelowEstimatedRuntime(
+            EstimatedRuntime::new()
+                .with_min_runtime_secs(10)
+                .with_fallback_min_cycles(50),
+        );
+
+        // At 20 cycles per second, the remaining balance must last for more than 10 seconds.
+        assert_eq!(calc_available_cycles(1_000, 20, &strategy), 780);
+    }
+
+    #[test]
+    fn test_calc_batch_cycles() {
+        assert_eq!(calc_batch_cycles(0, 500, 1_000, 100), 0);
+        assert_eq!(calc_batch_cycles(0, 1_500, 1_000, 100), 500);
+        assert_eq!(calc_batch_cycles(0, 1_050, 1_000, 100), 100);
+        assert_eq!(calc_batch_cycles(200, 1_500, 1_000, 100), 700);
+        assert_eq!(calc_batch_cycles(200, 0, 1_000, 0), 200);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_cycles_falls_back_to_next_source() {
+        let failing = TestObtainCycles::new(Some(false));
+        let fallback = TestObtainCycles::new(None);
+        let sources = vec![
+            ObtainCyclesSource::new("first", failing.clone()),
+            ObtainCyclesSource::new("second", fallback.clone()),
+        ];
+
+        let mut attempts = Vec::new();
+        let obtained = obtain_cycles_from_sources(
+            &sources,
+            &RetryPolicy::immediate(4),
+            100,
+            CanisterId::anonymous(),
+            &FundingRoundReport::new(0),
+            &PendingMints::default(),
+            &PendingWithdrawals::default(),
+            |source| attempts.push(source.to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(obtained.source, "second");
+        assert_eq!(attempts, vec!["first", "second"]);
+        assert_eq!(obtained.amount, 100);
+        assert_eq!(failing.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(fallback.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_cycles_retries_before_falling_back() {
+        let retryable = TestObtainCycles::new(Some(true));
+        let fallback = TestObtainCycles::new(Some(false));
+        let sources = vec![
+            ObtainCyclesSource::new("first", retryable.clone()),
+            ObtainCyclesSource::new("second", fallback.clone()),
+        ];
+
+        let mut attempts = Vec::new();
+        let error = obtain_cycles_from_sources(
+            &sources,
+            &RetryPolicy::immediate(4),
+            100,
+            CanisterId::anonymous(),
+            &FundingRoundReport::new(0),
+            &PendingMints::default(),
+            &PendingWithdrawals::default(),
+            |source| attempts.push(source.to_string()),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(retryable.calls.load(Ordering::SeqCst), 4);
+        assert_eq!(fallback.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(error.details, "first: failed; second: failed");
+        // the outcome of the first source is unknown after its retries
+        assert!(error.can_retry);
+        // the intent is kept for the first source, whose cycles may still arrive
+        assert_eq!(attempts, vec!["first"]);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_cycles_uses_source_retry_policy() {
+        let retryable = TestObtainCycles::new(Some(true));
+        let sources = vec![ObtainCyclesSource::new("first", retryable.clone())
+            .with_retry_policy(RetryPolicy::immediate(2))];
+
+        obtain_cycles_from_sources(
+            &sources,
+            &RetryPolicy::immediate(4),
+            100,
+            CanisterId::anonymous(),
+            &FundingRoundReport::new(0),
+            &PendingMints::default(),
+            &PendingWithdrawals::default(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(retryable.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_cycles_skips_source_over_round_limit() {
+        let limited = TestObtainCycles::new(None);
+        let fallback = TestObtainCycles::new(None);
+        let sources = vec![
+            ObtainCyclesSource::new("first", limited.clone()).with_max_cycles_per_round(150),
+            ObtainCyclesSource::new("second", fallback.clone()),
+        ];
+
+        let mut report = FundingRoundReport::new(0);
+        report.obtained_cycles.push(ObtainedCycles {
+            canister_id: CanisterId::anonymous(),
+            source: "first".to_string(),
+            amount: 100,
+            block_index: None,
+            timestamp: 0,
+            mint: None,
+        });
+
+        let obtained = obtain_cycles_from_sources(
+            &sources,
+            &RetryPolicy::immediate(4),
+            100,
+            CanisterId::anonymous(),
+            &report,
+            &PendingMints::default(),
+            &PendingWithdrawals::default(),
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(obtained.source, "second");
+        assert_eq!(limited.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pending_mints_are_kept_by_the_manager() {
+        let cmc = Arc::new(TestCmcCanister {
+            notify_top_up_returns_with: Some(Ok(NotifyTopUpResult::Err(NotifyError::Processing))),
+            ..Default::default()
+        });
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let sources = vec![ObtainCyclesSource::new(
+            "mint",
+            Arc::new(MintCycles::new(cmc, ledger, Subaccount([0u8; 32]))),
+        )];
+        let fund_manager = FundManager::new();
+        let pending_mints = fund_manager.inner.borrow().pending_mints.clone();
+
+        obtain_cycles_from_sources(
+            &sources,
+            &RetryPolicy::new(),
+            1_000_000_000_000,
+            CanisterId::anonymous(),
+            &FundingRoundReport::new(0),
+            &pending_mints,
+            &PendingWithdrawals::default(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+
+        let pending = fund_manager.get_pending_mints();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].block_index, Some(0));
+
+        // after an upgrade the persisted mints are restored into the new manager
+        let mut fund_manager = FundManager::new();
+        fund_manager.with_pending_mints(PendingMints::new(pending.clone(), Vec::new()));
+
+        assert_eq!(fund_manager.get_pending_mints(), pending);
+        assert!(fund_manager.get_stranded_mints().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pending_withdrawals_are_kept_by_the_manager() {
+        let cycles_ledger = Arc::new(TestCyclesLedgerCanister {
+            returns_with: Some(Err(Error::InsufficientLiquidCycleBalance(
+                ic_cdk::call::InsufficientLiquidCycleBalance {
+                    available: 0,
+                    required: 100,
+                },
+            ))),
+            ..Default::default()
+        });
+        let sources = vec![ObtainCyclesSource::new(
+            "withdraw",
+            Arc::new(WithdrawFromCyclesLedger::new(cycles_ledger, None)),
+        )];
+        let fund_manager = FundManager::new();
+        let pending_withdrawals = fund_manager.inner.borrow().pending_withdrawals.clone();
+
+        obtain_cycles_from_sources(
+            &sources,
+            &RetryPolicy::new(),
+            1_000_000_000_000,
+            CanisterId::anonymous(),
+            &FundingRoundReport::new(0),
+            &PendingMints::default(),
+            &pending_withdrawals,
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+
+        let pending = fund_manager.get_pending_withdrawals();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].amount, 1_000_000_000_000);
+
+        // after an upgrade the persisted withdrawals are restored into the new manager
+        let mut fund_manager = FundManager::new();
+        fund_manager.with_pending_withdrawals(PendingWithdrawals::new(pending.clone(), Vec::new()));
+
+        assert_eq!(fund_manager.get_pending_withdrawals(), pending);
+        assert!(fund_manager.get_stranded_withdrawals().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_obtain_cycles_skips_source_without_capacity() {
+        let empty = TestObtainCycles::new(None);
+        let fallback = TestObtainCycles::new(None);
+        let sources = vec![
+            ObtainCyclesSource::new("first", empty.clone()),
+            ObtainCyclesSource::new("second", fallback.clone()),
+        ];
+
+        let mut report = FundingRoundReport::new(0);
+        report.treasury_runway = Some(TreasuryRunway {
+            sources: vec![treasury::SourceCapacity {
+                source: "first".to_string(),
+                available_cycles: Some(50),
+                error: None,
+            }],
+            cycles_per_sec: 0,
+            checked_at: 0,
+        });
+
+        let obtained = obtain_cycles_from_sources(
+            &sources,
+            &RetryPolicy::immediate(4),
+            100,
+            CanisterId::anonymous(),
+            &report,
+            &PendingMints::default(),
+            &PendingWithdrawals::default(),
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(obtained.source, "second");
+        assert_eq!(empty.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_treasury_forecast() {
+        let funded_canister_id = Principal::from_slice(&[1]);
+        let mut fund_manager = FundManager::new();
+        fund_manager
+            .with_options(
+                FundManagerOptions::new()
+                    .with_interval_secs(1)
+                    // keeps the consumption history of the canisters
+                    .with_strategy(FundStrategy::BelowEstimatedRuntime(
+                        EstimatedRuntime::new().with_min_runtime_secs(5),
+                    ))
+                    .with_funder_options(Some(FunderOptions::new())),
+            )
+            .register(funded_canister_id, RegisterOpts::new());
+
+        {
+            let mut core = fund_manager.inner.borrow_mut();
+            // the funding canister consumes 100 cycles per second
+            let funder_record = core.canisters.get_mut(&canister_self()).unwrap();
+            funder_record.set_cycles(CyclesBalance::new(1_000, 0));
+            funder_record.set_cycles(CyclesBalance::new(800, 2_000_000_000));
+            // the funded canister consumes 50 cycles per second
+            let funded_record = core.canisters.get_mut(&funded_canister_id).unwrap();
+            funded_record.set_cycles(CyclesBalance::new(500, 0));
+            funded_record.set_cycles(CyclesBalance::new(400, 2_000_000_000));
+            core.treasury_runway = Some(TreasuryRunway {
+                sources: vec![treasury::SourceCapacity {
+                    source: "ledger".to_string(),
+                    available_cycles: Some(1_200),
+                    error: None,
+                }],
+                cycles_per_sec: 0,
+                checked_at: 0,
+            });
+        }
+
+        let forecast = fund_manager.get_treasury_forecast();
+
+        assert_eq!(forecast.funder_cycles, 800);
+        assert_eq!(forecast.sources_cycles(), 1_200);
+        assert_eq!(forecast.cycles_per_sec, 150);
+        assert_eq!(forecast.runway_secs(), Some(13));
+
+        fund_manager.unregister(funded_canister_id);
+        assert_eq!(fund_manager.get_treasury_forecast().runway_secs(), Some(20));
+    }
+
+    #[test]
+    fn test_with_funder_options_registers_funder() {
+        let strategy = FundStrategy::Always(100);
+        let mut fund_manager = FundManager::new();
+        assert!(fund_manager.get_funder_record().is_none());
+
+        fund_manager.with_options(
+            FundManagerOptions::new()
+                .with_funder_options(Some(FunderOptions::new().with_strategy(strategy.clone()))),
+        );
+
+        let record = fund_manager.get_funder_record().unwrap();
+        assert_eq!(record.get_strategy(), &Some(strategy));
+        assert!(record.get_obtain_cycles_options().is_none());
+    }
+
+    #[test]
+    fn test_group_budget_and_reports() {
+        let mut fund_manager = FundManager::new();
+        fund_manager.with_options(FundManagerOptions::new().with_group(
+            "product",
+            CanisterGroup::new().with_max_cycles_per_round(1_000),
+        ));
+        let first = Principal::from_slice(&[1]);
+        let second = Principal::from_slice(&[2]);
+        let third = Principal::from_slice(&[3]);
+        let other = Principal::from_slice(&[4]);
+        fund_manager
+            .register(
+                first,
+                RegisterOpts::new().with_group("product").with_tag("prod"),
+            )
+            .register(second, RegisterOpts::new().with_group("product"))
+            .register(
+                third,
+                RegisterOpts::new()
+                    .with_group("product")
+                    .with_max_cycles_per_round(2_000),
+            )
+            .register(other, RegisterOpts::new().with_tag("prod"));
+
+        {
+            let mut core = fund_manager.inner.borrow_mut();
+            let mut round = FundingRoundReport::new(100);
+            assert_eq!(core.remaining_budget(first, &round), Some(1_000));
+            assert_eq!(core.remaining_budget(third, &round), Some(2_000));
+            assert_eq!(core.remaining_budget(other, &round), None);
+
+            // Every delivery of the round counts, also several ones to the same canister.
+            core.record_delivered_cycles(first, 300, &mut round);
+            core.record_delivered_cycles(first, 100, &mut round);
+            core.record_delivered_cycles(other, 500, &mut round);
+            assert_eq!(core.remaining_budget(second, &round), Some(600));
+
+            // The budget of the canister overrides the one of its group, and its deliveries still
+            // count towards the group.
+            core.record_delivered_cycles(third, 1_500, &mut round);
+            assert_eq!(core.remaining_budget(third, &round), Some(500));
+            assert_eq!(core.remaining_budget(second, &round), Some(0));
+            assert_eq!(round.group_delivered_cycles.get("product"), Some(&1_900));
+
+            // Deliveries of previous rounds do not count towards the budget of the round.
+            assert_eq!(
+                core.remaining_budget(first, &FundingRoundReport::new(200)),
+                Some(1_000)
+            );
+
+            let record = core.canisters.get_mut(&first).unwrap();
+            record.add_deposited_cycles(CyclesBalance::new(300, 50));
+            record.add_deposited_cycles(CyclesBalance::new(400, 150));
+            core.canisters
+                .get_mut(&other)
+                .unwrap()
+                .add_deposited_cycles(CyclesBalance::new(500, 150));
+        }
+
+        let report = fund_manager.get_group_report("product");
+        assert_eq!(report.canisters, 3);
+        assert_eq!(report.deposited_cycles, 700);
+        assert_eq!(fund_manager.get_tag_report("prod").deposited_cycles, 1_200);
+        assert_eq!(fund_manager.get_group_reports().len(), 1);
+    }
+
+    #[test]
+    fn test_sync_reconciles_registered_canisters() {
+        let mut fund_manager = FundManager::new();
+        let first = Principal::from_slice(&[1]);
+        let second = Principal::from_slice(&[2]);
+        let third = Principal::from_slice(&[3]);
+        fund_manager
+            .register(first, RegisterOpts::new())
+            .register(second, RegisterOpts::new());
+        fund_manager
+            .inner
+            .borrow_mut()
+            .canisters
+            .get_mut(&first)
+            .unwrap()
+            .set_cycles(CyclesBalance::new(500, 0));
+
+        let diff = fund_manager.sync([
+            (
+                first,
+                RegisterOpts::new().with_strategy(FundStrategy::Always(100)),
+            ),
+            (third, RegisterOpts::new()),
+        ]);
+
+        assert_eq!(diff.added, vec![third]);
+        assert_eq!(diff.removed, vec![second]);
+        assert_eq!(diff.updated, vec![first]);
+        assert!(diff.unchanged.is_empty());
+
+        let record = fund_manager.get_canister(first).unwrap();
+        assert_eq!(record.get_strategy(), &Some(FundStrategy::Always(100)));
+        assert_eq!(record.get_cycles().as_ref().unwrap().amount, 500);
+
+        let diff = fund_manager.sync([
+            (
+                first,
+                RegisterOpts::new().with_strategy(FundStrategy::Always(100)),
+            ),
+            (third, RegisterOpts::new()),
+        ]);
+
+        assert!(!diff.has_changes());
+        assert_eq!(diff.unchanged, vec![first, third]);
+    }
+
+    #[test]
+    fn test_sync_compares_sources_by_name_and_options() {
+        let mut fund_manager = FundManager::new();
+        let canister_id = Principal::from_slice(&[1]);
+        let opts = |max_cycles_per_round: u128| {
+            RegisterOpts::new().with_obtain_cycles_options(ObtainCyclesOptions::from_sources(vec![
+                ObtainCyclesSource::new("primary", TestObtainCycles::new(None))
+                    .with_max_cycles_per_round(max_cycles_per_round)
+                    .with_retry_policy(RetryPolicy::immediate(3)),
+            ]))
+        };
+        fund_manager.register(canister_id, opts(1_000));
+
+        // The sources are recreated, e.g. in `post_upgrade`, with the same names and options.
+        let diff = fund_manager.sync([(canister_id, opts(1_000))]);
+
+        assert_eq!(diff.unchanged, vec![canister_id]);
+
+        let diff = fund_manager.sync([(canister_id, opts(2_000))]);
+
+        assert_eq!(diff.updated, vec![canister_id]);
+    }
+
+    #[test]
+    fn test_sync_keeps_configured_funder() {
+        let mut fund_manager = FundManager::new();
+        fund_manager.with_options(
+            FundManagerOptions::new().with_funder_options(Some(FunderOptions::new())),
+        );
+
+        let diff = fund_manager.sync([]);
+
+        assert!(diff.removed.is_empty());
+        assert!(fund_manager.get_funder_record().is_some());
+    }
+
+    #[test]
+    fn test_register_and_unregister_many() {
+        let mut fund_manager = FundManager::new();
+        let first = Principal::from_slice(&[1]);
+        let second = Principal::from_slice(&[2]);
+        fund_manager.register(first, RegisterOpts::new());
+
+        let diff = fund_manager
+            .register_many([(second, RegisterOpts::new()), (first, RegisterOpts::new())]);
+
+        assert_eq!(diff.added, vec![second]);
+        assert_eq!(diff.unchanged, vec![first]);
+
+        let diff = fund_manager.unregister_many([first, Principal::from_slice(&[3])]);
+
+        assert_eq!(diff.removed, vec![first]);
+        assert_eq!(fund_manager.get_canisters().len(), 1);
+    }
+
+    #[test]
+    fn test_reconcile_intent_accounts_settled_movement() {
+        let core = FundManagerCore::new();
+        let mut core = core.borrow_mut();
+        let canister_id = Principal::from_slice(&[1]);
+        core.register(canister_id, RegisterOpts::new());
+        core.canisters
+            .get_mut(&canister_id)
+            .unwrap()
+            .set_cycles(CyclesBalance::new(500, 0));
+
+        core.record_intent(canister_id, FundingIntentKind::Deposit, 1_000);
+        assert_eq!(core.intents.len(), 1);
+
+        let reconciled = core.reconcile_intent(canister_id, &CyclesBalance::new(1_500, 10));
+
+        assert_eq!(reconciled.map(|intent| intent.amount), Some(1_000));
+        assert!(core.intents.is_empty());
+        let record = core.canisters.get(&canister_id).unwrap();
+        assert_eq!(
+            record.get_deposited_cycles().as_ref().unwrap().amount,
+            1_000
+        );
+    }
+
+    #[test]
+    fn test_reconcile_intent_keeps_movement_not_in_balance_until_expired() {
+        let core = FundManagerCore::new();
+        let mut core = core.borrow_mut();
+        let canister_id = Principal::from_slice(&[1]);
+        core.register(canister_id, RegisterOpts::new());
+        core.canisters
+            .get_mut(&canister_id)
+            .unwrap()
+            .set_cycles(CyclesBalance::new(500, 0));
+
+        core.record_intent(canister_id, FundingIntentKind::Deposit, 1_000);
+        let created_at = core.intents[&canister_id].created_at;
+
+        assert!(core
+            .reconcile_intent(canister_id, &CyclesBalance::new(500, created_at + 10))
+            .is_none());
+        assert_eq!(core.intents.len(), 1);
+
+        let expired_at = created_at + FundingIntent::MAX_AGE_SECS * 1_000_000_000;
+        assert!(core
+            .reconcile_intent(canister_id, &CyclesBalance::new(500, expired_at))
+            .is_none());
+        assert!(core.intents.is_empty());
+        assert!(core
+            .canisters
+            .get(&canister_id)
+            .unwrap()
+            .get_deposited_cycles()
+            .is_none());
+    }
+
+    #[test]
+    fn test_obtain_intent_is_reconciled_by_block_index() {
+        let core = FundManagerCore::new();
+        let mut core = core.borrow_mut();
+        let canister_id = Principal::from_slice(&[1]);
+        core.register(canister_id, RegisterOpts::new());
+        core.canisters
+            .get_mut(&canister_id)
+            .unwrap()
+            .set_cycles(CyclesBalance::new(500, 0));
+        let pending_mint = PendingMint {
+            from: Account::from(canister_self()),
+            target_canister_id: canister_id,
+            kind: MintKind::TopUp,
+            icp_amount_e8s: 100,
+            created_at_time: 0,
+            block_index: Some(5),
+            fee_e8s: None,
+        };
+        core.pending_mints = PendingMints::new(vec![pending_mint.clone()], Vec::new());
+
+        // an obtain that failed for sure is not kept
+        core.record_intent(
+            canister_id,
+            FundingIntentKind::Obtain {
+                source: "mint".to_string(),
+            },
+            1_000,
+        );
+        core.settle_failed_obtain_intent(canister_id, false);
+        assert!(core.intents.is_empty());
+
+        // an obtain with an unknown outcome is kept with the block index of its transfer
+        core.record_intent(
+            canister_id,
+            FundingIntentKind::Obtain {
+                source: "mint".to_string(),
+            },
+            1_000,
+        );
+        core.settle_failed_obtain_intent(canister_id, true);
+        assert_eq!(core.intents[&canister_id].block_index, Some(5));
+
+        // the balance does not account obtained cycles, only the completed block index does
+        let created_at = core.intents[&canister_id].created_at;
+        assert!(core
+            .reconcile_intent(canister_id, &CyclesBalance::new(1_500, created_at + 10))
+            .is_none());
+        assert_eq!(core.intents.len(), 1);
+
+        // the transfer is no longer pending, e.g. because the CMC refused to mint its cycles
+        core.pending_mints = PendingMints::new(Vec::new(), vec![pending_mint]);
+        assert!(core
+            .reconcile_intent(canister_id, &CyclesBalance::new(1_500, created_at + 20))
+            .is_none());
+        assert!(core.intents.is_empty());
+        assert!(core
+            .canisters
+            .get(&canister_id)
+            .unwrap()
+            .get_deposited_cycles()
+            .is_none());
+    }
 }
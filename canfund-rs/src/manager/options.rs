@@ -1,10 +1,12 @@
-use std::{collections::HashMap, fmt::Debug, rc::Rc, sync::Arc};
+use std::{cmp, collections::HashMap, fmt::Debug, rc::Rc, sync::Arc};
 
 use candid::Principal;
 use ic_cdk::management_canister::CanisterId;
 use ic_ledger_types::AccountIdentifier;
 
+use crate::api::call::CallWait;
 use crate::operations::obtain::ObtainCycles;
+use crate::operations::retry::RetryPolicy;
 
 use super::record::CanisterRecord;
 
@@ -151,6 +153,173 @@ impl CyclesThreshold {
     }
 }
 
+/// The balance the funding canister keeps for itself when depositing cycles to other canisters.
+///
+/// The reserve is the larger of the absolute cycles and the cycles the funding canister consumes
+/// within the runtime, at its average consumption.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunderReserve {
+    /// The min cycles balance of the funding canister.
+    min_cycles: u128,
+    /// The min runtime in seconds the balance of the funding canister should last.
+    min_runtime_secs: u64,
+}
+
+impl FunderReserve {
+    /// Creates a new FunderReserve that reserves nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the min cycles balance of the funding canister.
+    pub fn with_min_cycles(mut self, min_cycles: u128) -> Self {
+        self.min_cycles = min_cycles;
+        self
+    }
+
+    /// Sets the min runtime in seconds the balance of the funding canister should last.
+    pub fn with_min_runtime_secs(mut self, min_runtime_secs: u64) -> Self {
+        self.min_runtime_secs = min_runtime_secs;
+        self
+    }
+
+    /// Get the min cycles balance of the funding canister.
+    pub fn min_cycles(&self) -> u128 {
+        self.min_cycles
+    }
+
+    /// Get the min runtime in seconds the balance of the funding canister should last.
+    pub fn min_runtime_secs(&self) -> u64 {
+        self.min_runtime_secs
+    }
+
+    /// Returns the reserved cycles for the consumption of the funding canister in cycles per second.
+    pub fn reserved_cycles(&self, cycles_per_sec: u128) -> u128 {
+        cmp::max(
+            self.min_cycles,
+            cycles_per_sec.saturating_mul(self.min_runtime_secs as u128),
+        )
+    }
+
+    /// Returns the cycles that can be deposited from the balance without going below the reserve.
+    pub fn available_cycles(&self, balance: u128, cycles_per_sec: u128) -> u128 {
+        balance.saturating_sub(self.reserved_cycles(cycles_per_sec))
+    }
+}
+
+/// The configuration of the funding canister itself, which is then registered automatically and
+/// monitored first in each round.
+#[derive(Clone, Default)]
+pub struct FunderOptions {
+    /// The strategy to fund the funding canister, which overrides the global strategy.
+    strategy: Option<FundStrategy>,
+    /// The options to obtain cycles for the funding canister, which override the global options.
+    obtain_cycles_options: Option<ObtainCyclesOptions>,
+    /// The balance the funding canister keeps for itself.
+    reserve: Option<FunderReserve>,
+}
+
+impl FunderOptions {
+    /// Creates a new FunderOptions that uses the global strategy and obtain cycles options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the strategy to fund the funding canister.
+    pub fn with_strategy(mut self, strategy: FundStrategy) -> Self {
+        self.strategy = Some(strategy);
+        self
+    }
+
+    /// Sets the options to obtain cycles for the funding canister.
+    pub fn with_obtain_cycles_options(
+        mut self,
+        obtain_cycles_options: ObtainCyclesOptions,
+    ) -> Self {
+        self.obtain_cycles_options = Some(obtain_cycles_options);
+        self
+    }
+
+    /// Sets the balance the funding canister keeps for itself.
+    pub fn with_reserve(mut self, reserve: FunderReserve) -> Self {
+        self.reserve = Some(reserve);
+        self
+    }
+
+    /// Get the strategy to fund the funding canister, if it overrides the global strategy.
+    pub fn strategy(&self) -> Option<&FundStrategy> {
+        self.strategy.as_ref()
+    }
+
+    /// Get the options to obtain cycles for the funding canister, if they override the global options.
+    pub fn obtain_cycles_options(&self) -> Option<&ObtainCyclesOptions> {
+        self.obtain_cycles_options.as_ref()
+    }
+
+    /// Get the balance the funding canister keeps for itself, if any.
+    pub fn reserve(&self) -> Option<&FunderReserve> {
+        self.reserve.as_ref()
+    }
+}
+
+/// The configuration shared by the canisters registered in a group, e.g. of a product or environment.
+///
+/// The strategy and obtain cycles options of a canister override the ones of its group, which
+/// override the global ones.
+#[derive(Clone, Default)]
+pub struct CanisterGroup {
+    /// The strategy to fund the canisters of the group, which overrides the global strategy.
+    strategy: Option<FundStrategy>,
+    /// The options to obtain cycles for the canisters of the group, which override the global options.
+    obtain_cycles_options: Option<ObtainCyclesOptions>,
+    /// The maximum cycles delivered to the canisters of the group per funding round, unlimited by default.
+    max_cycles_per_round: Option<u128>,
+}
+
+impl CanisterGroup {
+    /// Creates a new CanisterGroup that uses the global strategy and obtain cycles options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the strategy to fund the canisters of the group.
+    pub fn with_strategy(mut self, strategy: FundStrategy) -> Self {
+        self.strategy = Some(strategy);
+        self
+    }
+
+    /// Sets the options to obtain cycles for the canisters of the group.
+    pub fn with_obtain_cycles_options(
+        mut self,
+        obtain_cycles_options: ObtainCyclesOptions,
+    ) -> Self {
+        self.obtain_cycles_options = Some(obtain_cycles_options);
+        self
+    }
+
+    /// Sets the maximum cycles delivered to the canisters of the group per funding round.
+    pub fn with_max_cycles_per_round(mut self, max_cycles_per_round: u128) -> Self {
+        self.max_cycles_per_round = Some(max_cycles_per_round);
+        self
+    }
+
+    /// Get the strategy to fund the canisters of the group, if it overrides the global strategy.
+    pub fn strategy(&self) -> Option<&FundStrategy> {
+        self.strategy.as_ref()
+    }
+
+    /// Get the options to obtain cycles for the canisters of the group, if they override the global
+    /// options.
+    pub fn obtain_cycles_options(&self) -> Option<&ObtainCyclesOptions> {
+        self.obtain_cycles_options.as_ref()
+    }
+
+    /// Get the maximum cycles delivered to the canisters of the group per funding round.
+    pub fn max_cycles_per_round(&self) -> Option<u128> {
+        self.max_cycles_per_round
+    }
+}
+
 /// The strategy to use for funding the canister.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FundStrategy {
@@ -169,10 +338,109 @@ impl Default for FundStrategy {
     }
 }
 
+/// A named source to obtain cycles from.
+#[derive(Clone)]
+pub struct ObtainCyclesSource {
+    /// The name of the source used when reporting where cycles were obtained from.
+    name: String,
+    /// How to obtain cycles from the source.
+    obtain_cycles: Arc<dyn ObtainCycles>,
+    /// The maximum cycles to obtain from the source per funding round, unlimited by default.
+    max_cycles_per_round: Option<u128>,
+    /// The policy for retrying to obtain cycles from the source, overriding the policy of the
+    /// implementation and of the fund manager.
+    retry_policy: Option<RetryPolicy>,
+}
+
+impl ObtainCyclesSource {
+    /// Creates a new source without limits.
+    pub fn new(name: impl Into<String>, obtain_cycles: Arc<dyn ObtainCycles>) -> Self {
+        Self {
+            name: name.into(),
+            obtain_cycles,
+            max_cycles_per_round: None,
+            retry_policy: None,
+        }
+    }
+
+    /// Sets the policy for retrying to obtain cycles from the source.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    /// Sets the maximum cycles to obtain from the source per funding round.
+    pub fn with_max_cycles_per_round(mut self, max_cycles_per_round: u128) -> Self {
+        self.max_cycles_per_round = Some(max_cycles_per_round);
+        self
+    }
+
+    /// Get the name of the source.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get how to obtain cycles from the source.
+    pub fn obtain_cycles(&self) -> Arc<dyn ObtainCycles> {
+        self.obtain_cycles.clone()
+    }
+
+    /// Get the maximum cycles to obtain from the source per funding round.
+    pub fn max_cycles_per_round(&self) -> Option<u128> {
+        self.max_cycles_per_round
+    }
+
+    /// Get the policy for retrying to obtain cycles from the source, if overridden.
+    pub fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry_policy
+            .clone()
+            .or_else(|| self.obtain_cycles.retry_policy())
+    }
+}
+
+/// The options to obtain cycles when the funding canister balance gets low.
+///
+/// The sources are tried in order, moving on to the next one when a source fails with an error
+/// that cannot be retried or its limit for the round would be exceeded.
 #[derive(Clone)]
 pub struct ObtainCyclesOptions {
-    /// How to obtain cycles when the funding canister balance gets low.
-    pub obtain_cycles: Arc<dyn ObtainCycles>,
+    sources: Vec<ObtainCyclesSource>,
+}
+
+impl ObtainCyclesOptions {
+    /// The name of the source used by `ObtainCyclesOptions::new`.
+    pub const DEFAULT_SOURCE_NAME: &'static str = "default";
+
+    /// Creates the options with a single source without limits.
+    pub fn new(obtain_cycles: Arc<dyn ObtainCycles>) -> Self {
+        Self::from_sources(vec![ObtainCyclesSource::new(
+            Self::DEFAULT_SOURCE_NAME,
+            obtain_cycles,
+        )])
+    }
+
+    /// Creates the options with the sources to try in order.
+    pub fn from_sources(sources: Vec<ObtainCyclesSource>) -> Self {
+        Self { sources }
+    }
+
+    /// Adds a source to try if obtaining cycles from the previous sources failed.
+    pub fn with_fallback(mut self, source: ObtainCyclesSource) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Get the sources in the order they are tried.
+    pub fn sources(&self) -> &[ObtainCyclesSource] {
+        &self.sources
+    }
+
+    /// Get how to obtain cycles from the first source, which replaces the former public
+    /// `obtain_cycles` field.
+    #[deprecated(note = "the options can have several sources, use `sources` instead")]
+    pub fn obtain_cycles(&self) -> Option<Arc<dyn ObtainCycles>> {
+        self.sources.first().map(ObtainCyclesSource::obtain_cycles)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -201,6 +469,28 @@ pub struct FundManagerOptions {
     obtain_cycles_options: Option<ObtainCyclesOptions>,
     /// Funding callback is executed after a funding round is completed.
     funding_callback: Option<ObserverCallback>,
+    /// The policy for retrying to obtain cycles, unless overridden by the source.
+    obtain_retry_policy: RetryPolicy,
+    /// The policy for retrying to deposit cycles to the funded canisters.
+    deposit_retry_policy: RetryPolicy,
+    /// How long to wait for the management canister when depositing cycles.
+    deposit_call_wait: CallWait,
+    /// The maximum duration in secs of a funding round, after which its lock is considered stale
+    /// and released for the next round. The lock is never released by default.
+    max_round_duration_secs: Option<u64>,
+    /// The balance the funding canister keeps for itself, which caps the cycles deposited to other
+    /// canisters.
+    funder_reserve: Option<FunderReserve>,
+    /// The min cycles to obtain at once for the funding canister per round, if obtaining cycles is
+    /// batched instead of done per canister.
+    obtain_batch_min_cycles: Option<u128>,
+    /// The configuration of the funding canister, if it monitors itself.
+    funder_options: Option<FunderOptions>,
+    /// The runway in secs of the obtain cycles sources below which a warning is logged, if their
+    /// capacity is checked.
+    treasury_min_runway_secs: Option<u64>,
+    /// The configuration of the canister groups, by name.
+    groups: HashMap<String, CanisterGroup>,
 }
 
 impl Default for FundManagerOptions {
@@ -213,6 +503,15 @@ impl Default for FundManagerOptions {
             delayed_start: false,
             obtain_cycles_options: None,
             funding_callback: None,
+            obtain_retry_policy: RetryPolicy::immediate(4),
+            deposit_retry_policy: RetryPolicy::default(),
+            deposit_call_wait: CallWait::default(),
+            max_round_duration_secs: None,
+            funder_reserve: None,
+            obtain_batch_min_cycles: None,
+            funder_options: None,
+            treasury_min_runway_secs: None,
+            groups: HashMap::new(),
         }
     }
 }
@@ -261,6 +560,77 @@ impl FundManagerOptions {
         self
     }
 
+    /// Set the policy for retrying to obtain cycles, unless overridden by the source.
+    pub fn with_obtain_retry_policy(mut self, obtain_retry_policy: RetryPolicy) -> Self {
+        self.obtain_retry_policy = obtain_retry_policy;
+        self
+    }
+
+    /// Set the policy for retrying to deposit cycles to the funded canisters.
+    pub fn with_deposit_retry_policy(mut self, deposit_retry_policy: RetryPolicy) -> Self {
+        self.deposit_retry_policy = deposit_retry_policy;
+        self
+    }
+
+    /// Set how long to wait for the management canister when depositing cycles.
+    ///
+    /// A deposit that times out may or may not have been executed, so it is not retried.
+    pub fn with_deposit_call_wait(mut self, deposit_call_wait: CallWait) -> Self {
+        self.deposit_call_wait = deposit_call_wait;
+        self
+    }
+
+    /// Set the maximum duration in secs of a funding round, after which its lock is considered stale
+    /// and released for the next round, or `None` to never release it.
+    pub fn with_max_round_duration_secs(mut self, max_round_duration_secs: Option<u64>) -> Self {
+        self.max_round_duration_secs = max_round_duration_secs;
+        self
+    }
+
+    /// Set the balance the funding canister keeps for itself.
+    ///
+    /// Deposits are capped so that the balance of the funding canister never drops below the reserve,
+    /// and canisters are partially funded when only part of the needed cycles are available.
+    pub fn with_funder_reserve(mut self, funder_reserve: Option<FunderReserve>) -> Self {
+        self.funder_reserve = funder_reserve;
+        self
+    }
+
+    /// Set the min cycles to obtain at once per round, or `None` to obtain cycles per canister.
+    ///
+    /// With batching, the cycles all canisters are short of are obtained once for the funding canister
+    /// with the global obtain cycles options, and then deposited from its balance. This saves the
+    /// fees and overhead of obtaining cycles for each canister.
+    pub fn with_obtain_batch_min_cycles(mut self, obtain_batch_min_cycles: Option<u128>) -> Self {
+        self.obtain_batch_min_cycles = obtain_batch_min_cycles;
+        self
+    }
+
+    /// Set the configuration of the funding canister, or `None` to not monitor it unless registered.
+    ///
+    /// With a configuration, the funding canister is registered automatically, monitored first in each
+    /// round and reported separately. Its reserve takes precedence over `with_funder_reserve`.
+    pub fn with_funder_options(mut self, funder_options: Option<FunderOptions>) -> Self {
+        self.funder_options = funder_options;
+        self
+    }
+
+    /// Set the runway in secs of the obtain cycles sources below which a warning is logged, or `None`
+    /// to not check their capacity.
+    ///
+    /// With a runway, the capacity of the sources is queried at the start of each round, and sources
+    /// that cannot cover a request are skipped instead of failing the transfer.
+    pub fn with_treasury_min_runway_secs(mut self, treasury_min_runway_secs: Option<u64>) -> Self {
+        self.treasury_min_runway_secs = treasury_min_runway_secs;
+        self
+    }
+
+    /// Set the configuration of the canisters registered in the named group.
+    pub fn with_group(mut self, name: impl Into<String>, group: CanisterGroup) -> Self {
+        self.groups.insert(name.into(), group);
+        self
+    }
+
     /// Get the interval in secs to track the canister balance.
     pub fn interval_secs(&self) -> u64 {
         self.interval_secs
@@ -290,6 +660,85 @@ impl FundManagerOptions {
     pub fn funding_callback(&self) -> Option<ObserverCallback> {
         self.funding_callback.clone()
     }
+
+    /// Get the policy for retrying to obtain cycles, unless overridden by the source.
+    pub fn obtain_retry_policy(&self) -> &RetryPolicy {
+        &self.obtain_retry_policy
+    }
+
+    /// Get the policy for retrying to deposit cycles to the funded canisters.
+    pub fn deposit_retry_policy(&self) -> &RetryPolicy {
+        &self.deposit_retry_policy
+    }
+
+    /// Get how long to wait for the management canister when depositing cycles.
+    pub fn deposit_call_wait(&self) -> CallWait {
+        self.deposit_call_wait
+    }
+
+    /// Get the maximum duration in secs of a funding round.
+    pub fn max_round_duration_secs(&self) -> Option<u64> {
+        self.max_round_duration_secs
+    }
+
+    /// Get the min cycles to obtain at once per round, if obtaining cycles is batched.
+    pub fn obtain_batch_min_cycles(&self) -> Option<u128> {
+        self.obtain_batch_min_cycles
+    }
+
+    /// Get the balance the funding canister keeps for itself, if any.
+    pub fn funder_reserve(&self) -> Option<&FunderReserve> {
+        self.funder_options
+            .as_ref()
+            .and_then(|funder_options| funder_options.reserve())
+            .or(self.funder_reserve.as_ref())
+    }
+
+    /// Get the configuration of the funding canister, if it monitors itself.
+    pub fn funder_options(&self) -> Option<&FunderOptions> {
+        self.funder_options.as_ref()
+    }
+
+    /// Get the runway in secs of the obtain cycles sources below which a warning is logged, if
+    /// their capacity is checked.
+    pub fn treasury_min_runway_secs(&self) -> Option<u64> {
+        self.treasury_min_runway_secs
+    }
+
+    /// Get the configuration of the named group, if configured.
+    pub fn group(&self, name: &str) -> Option<&CanisterGroup> {
+        self.groups.get(name)
+    }
+
+    /// Get the configuration of the canister groups, by name.
+    pub fn groups(&self) -> &HashMap<String, CanisterGroup> {
+        &self.groups
+    }
+
+    /// Get the strategy of the canister, from the canister, its group or the global strategy.
+    pub fn canister_strategy<'a>(&'a self, record: &'a CanisterRecord) -> &'a FundStrategy {
+        record
+            .get_strategy()
+            .as_ref()
+            .or_else(|| self.record_group(record)?.strategy())
+            .unwrap_or(&self.strategy)
+    }
+
+    /// Get the obtain cycles options of the canister or its group, which override the global options.
+    pub fn canister_obtain_cycles_options(
+        &self,
+        record: &CanisterRecord,
+    ) -> Option<ObtainCyclesOptions> {
+        record
+            .get_obtain_cycles_options()
+            .as_ref()
+            .or_else(|| self.record_group(record)?.obtain_cycles_options())
+            .cloned()
+    }
+
+    fn record_group(&self, record: &CanisterRecord) -> Option<&CanisterGroup> {
+        self.group(record.get_group().as_deref()?)
+    }
 }
 
 #[cfg(test)]
@@ -310,6 +759,8 @@ mod tests {
         let options = FundManagerOptions::default();
         assert_eq!(options.interval_secs, 60 * 60 * 24);
         assert_eq!(options.strategy, FundStrategy::default());
+        assert_eq!(options.deposit_call_wait, CallWait::Unbounded);
+        assert_eq!(options.max_round_duration_secs, None);
     }
 
     #[test]
@@ -367,4 +818,70 @@ mod tests {
         assert_eq!(threshold.min_cycles, 100_000_000_000);
         assert_eq!(threshold.fund_cycles, 200_000_000_000);
     }
+
+    #[test]
+    fn test_funder_reserve_available_cycles() {
+        let reserve = FunderReserve::new()
+            .with_min_cycles(1_000)
+            .with_min_runtime_secs(100);
+
+        assert_eq!(reserve.reserved_cycles(5), 1_000);
+        assert_eq!(reserve.reserved_cycles(20), 2_000);
+        assert_eq!(reserve.available_cycles(1_500, 5), 500);
+        assert_eq!(reserve.available_cycles(1_500, 20), 0);
+    }
+
+    #[test]
+    fn test_funder_options_reserve_takes_precedence() {
+        let options = FundManagerOptions::new()
+            .with_funder_reserve(Some(FunderReserve::new().with_min_cycles(100)));
+        assert_eq!(options.funder_reserve().map(|r| r.min_cycles()), Some(100));
+
+        let options = options.with_funder_options(Some(
+            FunderOptions::new().with_reserve(FunderReserve::new().with_min_cycles(200)),
+        ));
+        assert_eq!(options.funder_reserve().map(|r| r.min_cycles()), Some(200));
+
+        let options = options.with_funder_options(Some(FunderOptions::new()));
+        assert_eq!(options.funder_reserve().map(|r| r.min_cycles()), Some(100));
+    }
+
+    #[test]
+    fn test_canister_strategy_overrides_group_strategy() {
+        let options = FundManagerOptions::new()
+            .with_strategy(FundStrategy::Always(1))
+            .with_group(
+                "product",
+                CanisterGroup::new().with_strategy(FundStrategy::Always(2)),
+            );
+        let fetcher =
+            Arc::new(crate::operations::fetch::FetchCyclesBalanceFromCanisterStatus::new());
+        let record = |strategy: Option<FundStrategy>, group: Option<&str>| {
+            CanisterRecord::new(fetcher.clone(), strategy, None, 0)
+                .with_group(group.map(str::to_string))
+        };
+
+        let ungrouped = record(None, None);
+        let grouped = record(None, Some("product"));
+        let unknown_group = record(None, Some("unknown"));
+        let own_strategy = record(Some(FundStrategy::Always(3)), Some("product"));
+
+        assert_eq!(
+            options.canister_strategy(&ungrouped),
+            &FundStrategy::Always(1)
+        );
+        assert_eq!(
+            options.canister_strategy(&grouped),
+            &FundStrategy::Always(2)
+        );
+        assert_eq!(
+            options.canister_strategy(&unknown_group),
+            &FundStrategy::Always(1)
+        );
+        assert_eq!(
+            options.canister_strategy(&own_strategy),
+            &FundStrategy::Always(3)
+        );
+        assert!(options.canister_obtain_cycles_options(&grouped).is_none());
+    }
 }
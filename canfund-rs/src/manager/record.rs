@@ -2,7 +2,7 @@ use crate::manager::options::ObtainCyclesOptions;
 use crate::operations::fetch::FetchCyclesBalance;
 use std::sync::Arc;
 
-use super::{history::ConsumptionHistory, options::FundStrategy};
+use super::{history::ConsumptionHistory, options::FundStrategy, RegisterOpts};
 
 #[derive(Clone)]
 pub struct CanisterRecord {
@@ -24,6 +24,13 @@ pub struct CanisterRecord {
     obtain_cycles_options: Option<ObtainCyclesOptions>,
     /// Tracks the state of funding failures for the canister.
     funding_failure: Option<FundingFailure>,
+    /// The group the canister belongs to, if any.
+    group: Option<String>,
+    /// The maximum cycles delivered to the canister per funding round, which overrides the budget
+    /// of its group.
+    max_cycles_per_round: Option<u128>,
+    /// The tags of the canister.
+    tags: Vec<String>,
 }
 
 impl CanisterRecord {
@@ -43,9 +50,41 @@ impl CanisterRecord {
             strategy,
             obtain_cycles_options,
             funding_failure: None,
+            group: None,
+            max_cycles_per_round: None,
+            tags: Vec::new(),
         }
     }
 
+    /// Sets the group the canister belongs to.
+    pub fn with_group(mut self, group: Option<String>) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// Sets the maximum cycles delivered to the canister per funding round.
+    pub fn with_max_cycles_per_round(mut self, max_cycles_per_round: Option<u128>) -> Self {
+        self.max_cycles_per_round = max_cycles_per_round;
+        self
+    }
+
+    /// Sets the tags of the canister.
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Replaces the registration options of the canister, keeping its balances and consumption history.
+    pub fn update(&mut self, opts: RegisterOpts, history_window_size: usize) {
+        self.cycles_fetcher = opts.cycles_fetcher;
+        self.strategy = opts.strategy;
+        self.obtain_cycles_options = opts.obtain_cycles_options;
+        self.group = opts.group;
+        self.max_cycles_per_round = opts.max_cycles_per_round;
+        self.tags = opts.tags;
+        self.consumption_history.resize(history_window_size);
+    }
+
     pub fn set_cycles(&mut self, cycles: CyclesBalance) {
         if let Some(previous_cycles) = self.cycles.as_ref() {
             self.previous_cycles = Some(previous_cycles.clone());
@@ -114,6 +153,27 @@ impl CanisterRecord {
         self.consumption_history.average()
     }
 
+    /// Returns the group the canister belongs to, if any.
+    pub fn get_group(&self) -> &Option<String> {
+        &self.group
+    }
+
+    /// Returns the maximum cycles delivered to the canister per funding round, if it overrides the
+    /// budget of its group.
+    pub fn get_max_cycles_per_round(&self) -> Option<u128> {
+        self.max_cycles_per_round
+    }
+
+    /// Returns the tags of the canister.
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Returns whether the canister is tagged with the tag.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|known| known == tag)
+    }
+
     pub fn get_funding_failure(&self) -> Option<&FundingFailure> {
         self.funding_failure.as_ref()
     }
@@ -125,6 +185,15 @@ impl CanisterRecord {
         });
     }
 
+    /// Returns the cycles that could not be deposited in the last round if the canister was
+    /// partially funded.
+    pub fn get_funding_shortfall(&self) -> Option<u128> {
+        match self.funding_failure.as_ref()?.error_code {
+            FundingErrorCode::PartiallyFunded { shortfall } => Some(shortfall),
+            _ => None,
+        }
+    }
+
     pub fn reset_funding_failure(&mut self) {
         self.funding_failure = None;
     }
@@ -162,7 +231,13 @@ pub enum FundingErrorCode {
     DepositFailed,      // The deposit of cycles failed
     ObtainCyclesFailed, // Obtaining cycles failed
     BalanceCheckFailed, // Fetching cycles balance failed
-    Other(String),      // Other errors with a custom message
+    /// Only part of the needed cycles were deposited.
+    PartiallyFunded {
+        shortfall: u128,
+    },
+    /// The budget of the canister or its group for the round is used up.
+    GroupBudgetExceeded,
+    Other(String), // Other errors with a custom message
 }
 
 impl FundingErrorCode {
@@ -177,6 +252,13 @@ impl FundingErrorCode {
                 "Obtaining cycles for the canister failed.".to_string()
             }
             FundingErrorCode::BalanceCheckFailed => "Fetching cycles balance failed.".to_string(),
+            FundingErrorCode::PartiallyFunded { shortfall } => {
+                format!("The canister was partially funded, {shortfall} cycles short.")
+            }
+            FundingErrorCode::GroupBudgetExceeded => {
+                "The budget of the canister or its group for the funding round is used up."
+                    .to_string()
+            }
             FundingErrorCode::Other(msg) => msg.clone(),
         }
     }
@@ -268,4 +350,23 @@ mod tests {
         assert_eq!(failure.error_code, error_code);
         assert_eq!(failure.timestamp, timestamp);
     }
+
+    #[test]
+    fn test_funding_shortfall() {
+        let mut record = CanisterRecord::new(
+            Arc::new(FetchCyclesBalanceFromCanisterStatus::new()),
+            None,
+            None,
+            0,
+        );
+
+        record.set_funding_failure(FundingErrorCode::InsufficientCycles, 1);
+        assert_eq!(record.get_funding_shortfall(), None);
+
+        record.set_funding_failure(FundingErrorCode::PartiallyFunded { shortfall: 100 }, 2);
+        assert_eq!(record.get_funding_shortfall(), Some(100));
+
+        record.reset_funding_failure();
+        assert_eq!(record.get_funding_shortfall(), None);
+    }
 }
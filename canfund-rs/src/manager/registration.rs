@@ -0,0 +1,30 @@
+use ic_cdk::management_canister::CanisterId;
+
+/// The changes to the registered canisters made by a bulk registration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegistrationDiff {
+    /// The canisters that were registered.
+    pub added: Vec<CanisterId>,
+    /// The canisters that were unregistered.
+    pub removed: Vec<CanisterId>,
+    /// The registered canisters whose strategy, obtain cycles options, group or tags changed.
+    pub updated: Vec<CanisterId>,
+    /// The registered canisters that were left as they were.
+    pub unchanged: Vec<CanisterId>,
+}
+
+impl RegistrationDiff {
+    /// Returns whether the registered canisters or their options changed.
+    pub fn has_changes(&self) -> bool {
+        !self.added.is_empty() || !self.removed.is_empty() || !self.updated.is_empty()
+    }
+
+    /// Sorts the canisters of each change, so that the diff does not depend on the registration order.
+    pub(crate) fn sorted(mut self) -> Self {
+        self.added.sort();
+        self.removed.sort();
+        self.updated.sort();
+        self.unchanged.sort();
+        self
+    }
+}
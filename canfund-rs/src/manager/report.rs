@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use ic_cdk::management_canister::CanisterId;
+
+use super::{
+    cost::RoundCost,
+    intent::FundingIntent,
+    record::{CanisterRecord, CyclesBalance},
+    treasury::TreasuryRunway,
+};
+use crate::operations::obtain::MintReceipt;
+
+/// The report of a funding round.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FundingRoundReport {
+    /// The timestamp when the round started.
+    pub started_at: u64,
+    /// The timestamp when the round completed.
+    pub completed_at: u64,
+    /// The cycles obtained during the round.
+    pub obtained_cycles: Vec<ObtainedCycles>,
+    /// The cycles movements of previous rounds that were accounted during the round.
+    pub reconciled_intents: Vec<FundingIntent>,
+    /// The cycles balance of the funding canister at the end of the round, if it is monitored.
+    pub funder_cycles: Option<CyclesBalance>,
+    /// The capacity of the obtain cycles sources at the start of the round, if it was checked.
+    pub treasury_runway: Option<TreasuryRunway>,
+    /// The cycles deposited from the funding canister during the round.
+    pub deposited_cycles: u128,
+    /// The cycles delivered to each canister during the round, deposited or obtained for it.
+    pub delivered_cycles: HashMap<CanisterId, u128>,
+    /// The cycles delivered to the canisters of each group during the round.
+    pub group_delivered_cycles: HashMap<String, u128>,
+    /// The cycles spent on the operations of the round, once it completed.
+    pub cost: Option<RoundCost>,
+}
+
+impl FundingRoundReport {
+    /// Constructs a new report for a round started at the specified timestamp.
+    pub fn new(started_at: u64) -> Self {
+        Self {
+            started_at,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the total cycles obtained from the source during the round.
+    pub fn obtained_cycles_from(&self, source: &str) -> u128 {
+        self.obtained_cycles
+            .iter()
+            .filter(|obtained| obtained.source == source)
+            .map(|obtained| obtained.amount)
+            .sum()
+    }
+
+    /// Returns the total cycles obtained for the canister during the round.
+    pub fn obtained_cycles_for(&self, canister_id: CanisterId) -> u128 {
+        self.obtained_cycles
+            .iter()
+            .filter(|obtained| obtained.canister_id == canister_id)
+            .map(|obtained| obtained.amount)
+            .sum()
+    }
+
+    /// Returns the cycles that can still be obtained from the source during the round, if its
+    /// capacity was checked.
+    pub fn remaining_capacity_of(&self, source: &str) -> Option<u128> {
+        let available_cycles = self.treasury_runway.as_ref()?.available_cycles_of(source)?;
+
+        Some(available_cycles.saturating_sub(self.obtained_cycles_from(source)))
+    }
+}
+
+/// The record of cycles obtained from a source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObtainedCycles {
+    /// The canister the cycles were obtained for.
+    pub canister_id: CanisterId,
+    /// The name of the source the cycles were obtained from.
+    pub source: String,
+    /// The amount of cycles obtained.
+    pub amount: u128,
+    /// The block index of the ledger transaction the cycles were obtained with, if known.
+    pub block_index: Option<u64>,
+    /// The timestamp when the cycles were obtained.
+    pub timestamp: u64,
+    /// The ICP spent if the cycles were minted.
+    pub mint: Option<MintReceipt>,
+}
+
+/// The aggregate of the canisters in a group or with a tag.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GroupReport {
+    /// The number of canisters.
+    pub canisters: usize,
+    /// The sum of the last fetched cycles balances of the canisters.
+    pub cycles: u128,
+    /// The total of cycles deposited to the canisters.
+    pub deposited_cycles: u128,
+    /// The sum of the average consumption of the canisters in cycles per second.
+    pub cycles_per_sec: u128,
+}
+
+impl GroupReport {
+    /// Aggregates the records of the canisters.
+    pub fn from_records<'a>(records: impl IntoIterator<Item = &'a CanisterRecord>) -> Self {
+        records
+            .into_iter()
+            .fold(Self::default(), |mut report, record| {
+                report.canisters += 1;
+                report.cycles = report.cycles.saturating_add(
+                    record
+                        .get_cycles()
+                        .as_ref()
+                        .map_or(0, |cycles| cycles.amount),
+                );
+                report.deposited_cycles = report.deposited_cycles.saturating_add(
+                    record
+                        .get_deposited_cycles()
+                        .as_ref()
+                        .map_or(0, |deposited| deposited.amount),
+                );
+                report.cycles_per_sec = report
+                    .cycles_per_sec
+                    .saturating_add(record.get_average_consumption() as u128);
+                report
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_obtained_cycles_from() {
+        let mut report = FundingRoundReport::new(100);
+
+        for (source, amount) in [("ledger", 10), ("mint", 20), ("ledger", 30)] {
+            report.obtained_cycles.push(ObtainedCycles {
+                canister_id: Principal::anonymous(),
+                source: source.to_string(),
+                amount,
+                block_index: None,
+                timestamp: 100,
+                mint: None,
+            });
+        }
+
+        assert_eq!(report.obtained_cycles_from("ledger"), 40);
+        assert_eq!(report.obtained_cycles_from("mint"), 20);
+        assert_eq!(report.obtained_cycles_from("parent"), 0);
+        assert_eq!(report.obtained_cycles_for(Principal::anonymous()), 60);
+        assert_eq!(
+            report.obtained_cycles_for(Principal::management_canister()),
+            0
+        );
+    }
+}
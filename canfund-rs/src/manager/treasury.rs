@@ -0,0 +1,225 @@
+use super::options::ObtainCyclesSource;
+
+/// The seconds of a day.
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// The cycles that can be obtained from a source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceCapacity {
+    /// The name of the source.
+    pub source: String,
+    /// The cycles that can be obtained from the source, if known.
+    pub available_cycles: Option<u128>,
+    /// The error of querying the capacity of the source, if any.
+    pub error: Option<String>,
+}
+
+/// The capacity of the obtain cycles sources and how long it lasts at the current consumption.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreasuryRunway {
+    /// The capacity of each source.
+    pub sources: Vec<SourceCapacity>,
+    /// The sum of the average consumption of the monitored canisters in cycles per second.
+    pub cycles_per_sec: u128,
+    /// The timestamp when the capacity was checked.
+    pub checked_at: u64,
+}
+
+impl TreasuryRunway {
+    /// Queries the capacity of the sources.
+    pub async fn check(sources: &[ObtainCyclesSource], cycles_per_sec: u128, now: u64) -> Self {
+        let mut capacities = Vec::new();
+        for source in sources {
+            let (available_cycles, error) = match source.obtain_cycles().available_cycles().await {
+                Ok(available_cycles) => (available_cycles, None),
+                Err(err) => (None, Some(err.details)),
+            };
+
+            capacities.push(SourceCapacity {
+                source: source.name().to_string(),
+                available_cycles,
+                error,
+            });
+        }
+
+        Self {
+            sources: capacities,
+            cycles_per_sec,
+            checked_at: now,
+        }
+    }
+
+    /// Returns the cycles that can be obtained from the source, if known.
+    pub fn available_cycles_of(&self, source: &str) -> Option<u128> {
+        self.sources
+            .iter()
+            .find(|capacity| capacity.source == source)
+            .and_then(|capacity| capacity.available_cycles)
+    }
+
+    /// Returns the cycles that can be obtained from all sources with a known capacity.
+    pub fn available_cycles(&self) -> u128 {
+        self.sources
+            .iter()
+            .filter_map(|capacity| capacity.available_cycles)
+            .fold(0, u128::saturating_add)
+    }
+
+    /// Returns the seconds until the sources run dry at the current consumption, or `None` if
+    /// nothing is consumed.
+    pub fn runway_secs(&self) -> Option<u64> {
+        if self.cycles_per_sec == 0 {
+            return None;
+        }
+
+        Some(u64::try_from(self.available_cycles() / self.cycles_per_sec).unwrap_or(u64::MAX))
+    }
+}
+
+/// A forecast of how long the whole funding system lasts, combining the balance of the funding
+/// canister with the capacity of the obtain cycles sources.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreasuryForecast {
+    /// The cycles balance of the funding canister.
+    pub funder_cycles: u128,
+    /// The capacity of each source when it was last checked, empty if it is not checked.
+    pub sources: Vec<SourceCapacity>,
+    /// The sum of the average consumption of all monitored canisters in cycles per second.
+    pub cycles_per_sec: u128,
+    /// The timestamp of the forecast.
+    pub forecast_at: u64,
+}
+
+impl TreasuryForecast {
+    /// Returns the cycles that can be obtained from all sources with a known capacity.
+    pub fn sources_cycles(&self) -> u128 {
+        self.sources
+            .iter()
+            .filter_map(|capacity| capacity.available_cycles)
+            .fold(0, u128::saturating_add)
+    }
+
+    /// Returns the cycles of the funding canister and all sources with a known capacity.
+    pub fn total_cycles(&self) -> u128 {
+        self.funder_cycles.saturating_add(self.sources_cycles())
+    }
+
+    /// Returns the seconds until the funding system runs dry at the current consumption, or `None`
+    /// if nothing is consumed.
+    pub fn runway_secs(&self) -> Option<u64> {
+        if self.cycles_per_sec == 0 {
+            return None;
+        }
+
+        Some(u64::try_from(self.total_cycles() / self.cycles_per_sec).unwrap_or(u64::MAX))
+    }
+
+    /// Returns the whole days until the funding system runs dry at the current consumption.
+    pub fn runway_days(&self) -> Option<u64> {
+        self.runway_secs().map(|runway_secs| runway_secs / DAY_SECS)
+    }
+
+    /// Returns the timestamp when the funding system runs dry at the current consumption.
+    pub fn exhausted_at(&self) -> Option<u64> {
+        self.runway_secs().map(|runway_secs| {
+            self.forecast_at
+                .saturating_add(runway_secs.saturating_mul(1_000_000_000))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::obtain::{ObtainCycles, ObtainCyclesError};
+    use async_trait::async_trait;
+    use candid::Principal;
+    use std::sync::Arc;
+
+    struct TestCapacity(Result<Option<u128>, String>);
+
+    #[async_trait]
+    impl ObtainCycles for TestCapacity {
+        async fn obtain_cycles(
+            &self,
+            amount: u128,
+            _target_canister_id: Principal,
+        ) -> Result<u128, ObtainCyclesError> {
+            Ok(amount)
+        }
+
+        async fn available_cycles(&self) -> Result<Option<u128>, ObtainCyclesError> {
+            self.0.clone().map_err(|details| ObtainCyclesError {
+                details,
+                can_retry: true,
+            })
+        }
+    }
+
+    fn source(name: &str, capacity: Result<Option<u128>, String>) -> ObtainCyclesSource {
+        ObtainCyclesSource::new(name, Arc::new(TestCapacity(capacity)))
+    }
+
+    #[tokio::test]
+    async fn test_check_sources_capacity() {
+        let sources = vec![
+            source("ledger", Ok(Some(1_000))),
+            source("mint", Ok(Some(500))),
+            source("parent", Ok(None)),
+            source("broken", Err("unreachable".to_string())),
+        ];
+
+        let runway = TreasuryRunway::check(&sources, 10, 100).await;
+
+        assert_eq!(runway.checked_at, 100);
+        assert_eq!(runway.available_cycles(), 1_500);
+        assert_eq!(runway.available_cycles_of("mint"), Some(500));
+        assert_eq!(runway.available_cycles_of("parent"), None);
+        assert_eq!(runway.sources[3].error, Some("unreachable".to_string()));
+        assert_eq!(runway.runway_secs(), Some(150));
+    }
+
+    #[test]
+    fn test_runway_without_consumption() {
+        let runway = TreasuryRunway {
+            sources: vec![SourceCapacity {
+                source: "ledger".to_string(),
+                available_cycles: Some(1_000),
+                error: None,
+            }],
+            cycles_per_sec: 0,
+            checked_at: 0,
+        };
+
+        assert_eq!(runway.runway_secs(), None);
+    }
+
+    #[test]
+    fn test_forecast_combines_funder_and_sources() {
+        let forecast = TreasuryForecast {
+            funder_cycles: 2 * DAY_SECS as u128 * 100,
+            sources: vec![
+                SourceCapacity {
+                    source: "ledger".to_string(),
+                    available_cycles: Some(DAY_SECS as u128 * 100),
+                    error: None,
+                },
+                SourceCapacity {
+                    source: "parent".to_string(),
+                    available_cycles: None,
+                    error: None,
+                },
+            ],
+            cycles_per_sec: 100,
+            forecast_at: 10,
+        };
+
+        assert_eq!(forecast.sources_cycles(), DAY_SECS as u128 * 100);
+        assert_eq!(forecast.runway_secs(), Some(3 * DAY_SECS));
+        assert_eq!(forecast.runway_days(), Some(3));
+        assert_eq!(
+            forecast.exhausted_at(),
+            Some(10 + 3 * DAY_SECS * 1_000_000_000)
+        );
+    }
+}
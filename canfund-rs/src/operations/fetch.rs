@@ -1,10 +1,11 @@
 use crate::{
+    api::call::CallWait,
     errors::Error,
     types::{HttpRequest, HttpResponse},
     utils::{cycles_nat_to_u128, cycles_str_to_u128},
 };
 use candid::Principal;
-use ic_cdk::call::{Call, RejectCode};
+use ic_cdk::call::RejectCode;
 use ic_cdk::management_canister::{CanisterId, CanisterStatusArgs, CanisterStatusResult};
 
 /// The trait for fetching the canister cycles balance.
@@ -21,6 +22,8 @@ pub trait FetchCyclesBalance: Sync + Send {
 pub struct FetchCyclesBalanceFromCanisterStatus {
     canister: Principal,
     method: String,
+    /// How long to wait for the balance.
+    call_wait: CallWait,
 }
 
 impl FetchCyclesBalanceFromCanisterStatus {
@@ -28,9 +31,17 @@ impl FetchCyclesBalanceFromCanisterStatus {
         Self {
             canister: Principal::management_canister(),
             method: "canister_status".to_string(),
+            call_wait: CallWait::default(),
         }
     }
 
+    /// Sets how long to wait for the balance, e.g. a bounded wait so that an unresponsive canister
+    /// cannot stall the funding round.
+    pub fn with_call_wait(mut self, call_wait: CallWait) -> Self {
+        self.call_wait = call_wait;
+        self
+    }
+
     pub fn with_proxy(mut self, proxy: Principal) -> Self {
         self.canister = proxy;
         self
@@ -51,7 +62,9 @@ impl Default for FetchCyclesBalanceFromCanisterStatus {
 #[async_trait::async_trait]
 impl FetchCyclesBalance for FetchCyclesBalanceFromCanisterStatus {
     async fn fetch_cycles_balance(&self, canister_id: CanisterId) -> Result<u128, Error> {
-        let response = Call::unbounded_wait(self.canister, &self.method)
+        let response = self
+            .call_wait
+            .call(self.canister, &self.method)
             .with_arg(&CanisterStatusArgs { canister_id });
 
         match response.await {
@@ -102,21 +115,34 @@ pub struct FetchCyclesBalanceFromPrometheusMetrics {
     path: String,
     /// The metric name for the canister cycles balance.
     metric_name: String,
+    /// How long to wait for the metrics.
+    call_wait: CallWait,
 }
 
 impl Default for FetchCyclesBalanceFromPrometheusMetrics {
     fn default() -> Self {
-        FetchCyclesBalanceFromPrometheusMetrics {
-            path: "/metrics".to_string(),
-            metric_name: "canister_cycles".to_string(),
-        }
+        FetchCyclesBalanceFromPrometheusMetrics::new(
+            "/metrics".to_string(),
+            "canister_cycles".to_string(),
+        )
     }
 }
 
 impl FetchCyclesBalanceFromPrometheusMetrics {
     /// Creates a new fetcher with the specified path and metric name.
     pub fn new(path: String, metric_name: String) -> Self {
-        Self { path, metric_name }
+        Self {
+            path,
+            metric_name,
+            call_wait: CallWait::default(),
+        }
+    }
+
+    /// Sets how long to wait for the metrics, e.g. a bounded wait so that an unresponsive canister
+    /// cannot stall the funding round.
+    pub fn with_call_wait(mut self, call_wait: CallWait) -> Self {
+        self.call_wait = call_wait;
+        self
     }
 
     /// Sets the path to the prometheus metrics endpoint.
@@ -144,7 +170,9 @@ impl FetchCyclesBalanceFromPrometheusMetrics {
 impl FetchCyclesBalance for FetchCyclesBalanceFromPrometheusMetrics {
     async fn fetch_cycles_balance(&self, canister_id: CanisterId) -> Result<u128, Error> {
         // Send the HTTP request to fetch the prometheus metrics.
-        let response: Result<HttpResponse, _> = Call::unbounded_wait(canister_id, "http_request")
+        let response: Result<HttpResponse, _> = self
+            .call_wait
+            .call(canister_id, "http_request")
             .with_arg(HttpRequest {
                 method: "GET".to_string(),
                 url: self.path.clone(),
@@ -224,6 +252,78 @@ fn calc_freezing_balance(freezing_threshold: u128, idle_cycles_burned_per_day: u
     idle_cycles_burned_per_day * freezing_threshold / 86_400
 }
 
+/// Mock cycles balance fetcher for unit tests, available with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+pub mod test {
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use tokio::sync::RwLock;
+
+    /// Replies with scripted balances or failures per canister.
+    ///
+    /// Each fetch consumes the next scripted result of the canister, the last one is repeated
+    /// once the script is exhausted. Fetching a canister without a script fails.
+    #[derive(Default)]
+    pub struct TestFetchCyclesBalance {
+        pub fetch_called_with: Arc<RwLock<Vec<CanisterId>>>,
+        scripts: Mutex<HashMap<CanisterId, VecDeque<Result<u128, Error>>>>,
+    }
+
+    impl TestFetchCyclesBalance {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Appends the balances to the script of the canister.
+        pub fn with_balances(
+            mut self,
+            canister_id: CanisterId,
+            balances: impl IntoIterator<Item = u128>,
+        ) -> Self {
+            self.script(canister_id)
+                .extend(balances.into_iter().map(Ok));
+            self
+        }
+
+        /// Appends a failure to the script of the canister.
+        pub fn with_failure(mut self, canister_id: CanisterId, error: Error) -> Self {
+            self.script(canister_id).push_back(Err(error));
+            self
+        }
+
+        fn script(&mut self, canister_id: CanisterId) -> &mut VecDeque<Result<u128, Error>> {
+            self.scripts
+                .get_mut()
+                .expect("scripts lock poisoned")
+                .entry(canister_id)
+                .or_default()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl FetchCyclesBalance for TestFetchCyclesBalance {
+        async fn fetch_cycles_balance(&self, canister_id: CanisterId) -> Result<u128, Error> {
+            self.fetch_called_with.write().await.push(canister_id);
+
+            let mut scripts = self.scripts.lock().expect("scripts lock poisoned");
+            let script = scripts.get_mut(&canister_id).filter(|s| !s.is_empty());
+
+            match script {
+                Some(script) if script.len() > 1 => script.pop_front().unwrap(),
+                Some(script) => script[0].clone(),
+                None => Err(Error::GetCanisterCycleBalanceFailed {
+                    rejection_code: RejectCode::DestinationInvalid,
+                    rejection_message: format!("no balance scripted for canister {canister_id}"),
+                }),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -308,4 +408,53 @@ mod tests {
         assert_eq!(calc_freezing_balance(10 * 24 * 60 * 60, 50_000), 500_000);
         assert_eq!(calc_freezing_balance(30 * 24 * 60 * 60, 123456), 3_703_680);
     }
+
+    #[test]
+    fn test_fetchers_wait_unbounded_by_default() {
+        assert_eq!(
+            FetchCyclesBalanceFromCanisterStatus::new().call_wait,
+            CallWait::Unbounded
+        );
+        assert_eq!(
+            FetchCyclesBalanceFromPrometheusMetrics::default().call_wait,
+            CallWait::Unbounded
+        );
+
+        assert_eq!(
+            FetchCyclesBalanceFromCanisterStatus::new()
+                .with_call_wait(CallWait::bounded(60))
+                .call_wait,
+            CallWait::Bounded { timeout_secs: 60 }
+        );
+        assert_eq!(
+            FetchCyclesBalanceFromPrometheusMetrics::default()
+                .with_call_wait(CallWait::bounded(60))
+                .call_wait,
+            CallWait::Bounded { timeout_secs: 60 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scripted_fetcher() {
+        let canister_id = Principal::from_slice(&[1]);
+        let fetcher = test::TestFetchCyclesBalance::new()
+            .with_balances(canister_id, [100, 50])
+            .with_failure(canister_id, Error::MetricsResponseDeserializationFailed)
+            .with_balances(canister_id, [10]);
+
+        assert_eq!(fetcher.fetch_cycles_balance(canister_id).await, Ok(100));
+        assert_eq!(fetcher.fetch_cycles_balance(canister_id).await, Ok(50));
+        assert_eq!(
+            fetcher.fetch_cycles_balance(canister_id).await,
+            Err(Error::MetricsResponseDeserializationFailed)
+        );
+        assert_eq!(fetcher.fetch_cycles_balance(canister_id).await, Ok(10));
+        assert_eq!(fetcher.fetch_cycles_balance(canister_id).await, Ok(10));
+
+        assert!(fetcher
+            .fetch_cycles_balance(Principal::anonymous())
+            .await
+            .is_err());
+        assert_eq!(fetcher.fetch_called_with.read().await.len(), 6);
+    }
 }
@@ -2,3 +2,6 @@
 
 pub mod fetch;
 pub mod obtain;
+pub mod price;
+pub mod retry;
+pub mod serve;
@@ -1,17 +1,45 @@
-use std::sync::Arc;
+use std::{
+    cmp,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
 
-use crate::api::cmc::GetIcpXdrResult;
+use crate::api::cmc::{GetIcpXdrResult, NotifyMintCyclesResult, NotifyMintCyclesSuccess};
+use crate::api::funder::FundingCanister;
 use crate::api::ledger::WithdrawableLedgerCanister;
 use crate::api::{
     cmc::{CyclesMintingCanister, NotifyError, NotifyTopUpResult},
     ledger::LedgerCanister,
 };
-use crate::types::{WithdrawArgs, WithdrawError};
+use crate::operations::price::IcpPriceGuard;
+use crate::operations::retry::{RetryOperation, RetryPolicy, RetryableError};
+use crate::types::{
+    RequestCyclesArgs, RequestCyclesError, WithdrawArgs, WithdrawError, WithdrawFromArgs,
+    WithdrawFromError,
+};
+use crate::utils::{canister_self, cycles_nat_to_u128, time};
 use async_trait::async_trait;
-use candid::Principal;
-use ic_ledger_types::{Memo, Subaccount, Tokens, TransferArgs};
-use icrc_ledger_types::icrc1::account;
-use icrc_ledger_types::icrc1::transfer::BlockIndex;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::call::{CallErrorExt, CallResult};
+use ic_ledger_types::{
+    AccountBalanceArgs, AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs,
+};
+use icrc_ledger_types::icrc1::account::{self, Account};
+use icrc_ledger_types::icrc1::transfer::{BlockIndex, TransferArg};
+use icrc_ledger_types::icrc2::allowance::{Allowance, AllowanceArgs};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+
+/// The fee charged by the ICP ledger for a transfer.
+pub const ICP_TRANSFER_FEE_E8S: u64 = 10_000;
+
+/// The memo the CMC expects on ICP transfers for topping up a canister.
+const MEMO_TOP_UP_CANISTER: u64 = 0x5055_5054;
+
+/// The memo the CMC expects on ICP transfers for minting cycles into the cycles ledger.
+const MEMO_MINT_CYCLES: u64 = 0x544e_494d;
+
+/// The fee charged by the cycles ledger for a withdrawal.
+pub const CYCLES_LEDGER_WITHDRAW_FEE: u128 = 100_000_000;
 
 #[derive(Debug)]
 pub struct ObtainCyclesError {
@@ -21,6 +49,42 @@ pub struct ObtainCyclesError {
     pub can_retry: bool,
 }
 
+/// The cycles obtained by an `ObtainCycles` implementation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObtainCyclesReceipt {
+    /// The amount of cycles obtained.
+    pub cycles: u128,
+    /// The block index of the ledger transaction the cycles were obtained with, if known.
+    pub block_index: Option<u64>,
+    /// The ICP spent if the cycles were minted.
+    pub mint: Option<MintReceipt>,
+}
+
+/// The ICP spent to mint cycles.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MintReceipt {
+    /// The ICP in e8s transferred to the CMC.
+    pub icp_spent_e8s: u64,
+    /// The ledger fee in e8s paid for the transfer.
+    pub fee_e8s: u64,
+    /// The cycles minted.
+    pub cycles: u128,
+    /// The block index of the transfer to the CMC.
+    pub block_index: u64,
+}
+
+impl MintReceipt {
+    /// Returns the cycles received per ICP, including the fee.
+    pub fn effective_cycles_per_icp(&self) -> u128 {
+        let total_e8s = self.icp_spent_e8s as u128 + self.fee_e8s as u128;
+        if total_e8s == 0 {
+            return 0;
+        }
+
+        self.cycles.saturating_mul(100_000_000) / total_e8s
+    }
+}
+
 /// Trait to top up the funding canister balance if it is too low.
 /// Example sources are minting from or swapping for ICP.
 #[async_trait]
@@ -30,12 +94,243 @@ pub trait ObtainCycles: Send + Sync {
         amount: u128,
         target_canister_id: Principal,
     ) -> Result<u128, ObtainCyclesError>;
+
+    /// Obtains the cycles like `obtain_cycles` and returns the ledger transaction they were obtained
+    /// with, if the implementation knows it.
+    ///
+    /// ICP transfers to the CMC whose cycles are not minted yet are kept in `pending_mints`, and
+    /// withdrawals from the cycles ledger whose outcome is unknown in `pending_withdrawals`. The fund
+    /// manager keeps both in its state, so that they are completed instead of spending the ICP or the
+    /// cycles again.
+    async fn obtain_cycles_with_receipt(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+        _pending_mints: &PendingMints,
+        _pending_withdrawals: &PendingWithdrawals,
+    ) -> Result<ObtainCyclesReceipt, ObtainCyclesError> {
+        Ok(ObtainCyclesReceipt {
+            cycles: self.obtain_cycles(amount, target_canister_id).await?,
+            block_index: None,
+            mint: None,
+        })
+    }
+
+    /// Completes the operations left pending by previous attempts, e.g. the CMC notifications of the
+    /// pending mints, and returns the cycles obtained for each target canister with the ledger
+    /// transaction they were obtained with.
+    async fn resume_pending(
+        &self,
+        _pending_mints: &PendingMints,
+    ) -> Vec<(Principal, ObtainCyclesReceipt)> {
+        Vec::new()
+    }
+
+    /// Returns the cycles that can currently be obtained from this implementation, e.g. the cycles
+    /// the ICP balance can mint at the current rate, or `None` if the capacity is not known.
+    async fn available_cycles(&self) -> Result<Option<u128>, ObtainCyclesError> {
+        Ok(None)
+    }
+
+    /// Returns the policy for retrying to obtain cycles from this implementation, overriding the
+    /// policy of the fund manager.
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        None
+    }
 }
 
 pub struct MintCycles {
     pub cmc: Arc<dyn CyclesMintingCanister>,
     pub ledger: Arc<dyn LedgerCanister>,
     pub from_subaccount: Subaccount,
+    /// The ledger endpoint used to transfer the ICP to the CMC.
+    pub transfer_method: IcpTransferMethod,
+    /// The mints of the direct calls to `obtain_cycles` and `mint`, the fund manager passes its own.
+    pending_mints: PendingMints,
+    /// The policy for retrying the notification of the CMC.
+    notify_retry_policy: RetryPolicy,
+    /// The sanity checks of the ICP/XDR rate and the limits of the ICP spent.
+    price_guard: Option<IcpPriceGuard>,
+    /// The fee of the ICP transfers to the CMC.
+    fee: IcpFee,
+}
+
+/// An ICP transfer to the CMC whose cycles have not been minted yet.
+///
+/// Retrying the mint reuses the amount and `created_at_time` of the transfer, so that the ledger
+/// deduplicates it instead of transferring the ICP again, and only notifies the CMC once the block
+/// index of the transfer is known.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingMint {
+    /// The account the ICP is transferred from.
+    pub from: Account,
+    /// The canister the cycles are minted for.
+    pub target_canister_id: Principal,
+    /// What the cycles are minted into.
+    pub kind: MintKind,
+    /// The amount of ICP transferred to the CMC.
+    pub icp_amount_e8s: u64,
+    /// The `created_at_time` of the transfer in nanoseconds.
+    pub created_at_time: u64,
+    /// The block index of the transfer, once it is known.
+    pub block_index: Option<u64>,
+    /// The fee of the transfer in e8s, the default ICP transfer fee if not set.
+    pub fee_e8s: Option<u64>,
+}
+
+impl PendingMint {
+    /// Returns the fee of the transfer in e8s.
+    pub fn transfer_fee_e8s(&self) -> u64 {
+        self.fee_e8s.unwrap_or(ICP_TRANSFER_FEE_E8S)
+    }
+
+    /// Returns the ICP in e8s spent by the transfer, including the fee.
+    pub fn total_e8s(&self) -> u64 {
+        self.icp_amount_e8s.saturating_add(self.transfer_fee_e8s())
+    }
+
+    /// Returns whether this is the mint of the kind from the account for the target canister.
+    fn is_mint_of(&self, from: &Account, kind: &MintKind, target_canister_id: &Principal) -> bool {
+        self.from == *from && self.kind == *kind && self.target_canister_id == *target_canister_id
+    }
+}
+
+/// What the cycles of a mint are minted into.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MintKind {
+    /// The cycles top up the target canister.
+    #[default]
+    TopUp,
+    /// The cycles are deposited into the cycles ledger account of the target canister.
+    CyclesLedger {
+        subaccount: Option<account::Subaccount>,
+    },
+}
+
+impl MintKind {
+    /// Returns the memo of the ICP transfer telling the CMC what to mint.
+    fn memo(&self) -> u64 {
+        match self {
+            MintKind::TopUp => MEMO_TOP_UP_CANISTER,
+            MintKind::CyclesLedger { .. } => MEMO_MINT_CYCLES,
+        }
+    }
+}
+
+/// The ICP transfers to the CMC whose cycles have not been minted yet, and the ones the CMC refused
+/// to mint.
+///
+/// The fund manager keeps them in its state and passes them to the obtain cycles sources, so that the
+/// pending mints of every source are completed by the next round and can be persisted across
+/// upgrades. Clones share the same mints.
+#[derive(Clone, Debug, Default)]
+pub struct PendingMints {
+    inner: Arc<Mutex<PendingMintsState>>,
+}
+
+#[derive(Debug, Default)]
+struct PendingMintsState {
+    pending: Vec<PendingMint>,
+    stranded: Vec<PendingMint>,
+}
+
+impl PendingMints {
+    /// Creates the mints from persisted ones, e.g. after an upgrade.
+    pub fn new(pending: Vec<PendingMint>, stranded: Vec<PendingMint>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(PendingMintsState { pending, stranded })),
+        }
+    }
+
+    /// Returns the mints that have not completed yet.
+    pub fn pending(&self) -> Vec<PendingMint> {
+        lock(&self.inner).pending.clone()
+    }
+
+    /// Returns the mints whose cycles can no longer be minted and need to be recovered manually.
+    pub fn stranded(&self) -> Vec<PendingMint> {
+        lock(&self.inner).stranded.clone()
+    }
+
+    /// Removes a stranded mint once its ICP has been recovered, or once a transfer without a known
+    /// block index is confirmed to not have been executed.
+    pub fn remove_stranded(&self, stranded_mint: &PendingMint) -> Option<PendingMint> {
+        let stranded = &mut lock(&self.inner).stranded;
+        let position = stranded.iter().position(|mint| mint == stranded_mint)?;
+        Some(stranded.remove(position))
+    }
+
+    /// Returns the pending mint of the kind from the account for the target canister.
+    fn get(
+        &self,
+        from: &Account,
+        kind: &MintKind,
+        target_canister_id: &Principal,
+    ) -> Option<PendingMint> {
+        lock(&self.inner)
+            .pending
+            .iter()
+            .find(|mint| mint.is_mint_of(from, kind, target_canister_id))
+            .cloned()
+    }
+
+    /// Adds the pending mint, replacing the previous state of the same mint.
+    fn set(&self, pending_mint: PendingMint) {
+        let pending = &mut lock(&self.inner).pending;
+        pending.retain(|mint| {
+            !mint.is_mint_of(
+                &pending_mint.from,
+                &pending_mint.kind,
+                &pending_mint.target_canister_id,
+            )
+        });
+        pending.push(pending_mint);
+    }
+
+    /// Removes the pending mint, keeping it as stranded if its cycles can no longer be minted.
+    fn remove(&self, pending_mint: &PendingMint, stranded: bool) {
+        let mut state = lock(&self.inner);
+        let Some(position) = state.pending.iter().position(|mint| {
+            mint.is_mint_of(
+                &pending_mint.from,
+                &pending_mint.kind,
+                &pending_mint.target_canister_id,
+            )
+        }) else {
+            return;
+        };
+
+        let removed = state.pending.remove(position);
+        if stranded {
+            state.stranded.push(removed);
+        }
+    }
+}
+
+/// The fee of the ICP transfers to the CMC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcpFee {
+    /// A fixed fee in e8s.
+    Fixed(u64),
+    /// The fee queried from the ledger before each new mint.
+    Queried,
+}
+
+impl Default for IcpFee {
+    /// The default is the fixed fee of the ICP ledger.
+    fn default() -> Self {
+        IcpFee::Fixed(ICP_TRANSFER_FEE_E8S)
+    }
+}
+
+/// The ledger endpoint used to transfer ICP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IcpTransferMethod {
+    /// The legacy `transfer` endpoint addressing accounts by account identifier.
+    #[default]
+    Legacy,
+    /// The ICRC-1 `icrc1_transfer` endpoint addressing accounts by principal and subaccount.
+    Icrc1,
 }
 
 #[async_trait]
@@ -45,260 +340,2543 @@ impl ObtainCycles for MintCycles {
         amount: u128,
         target_canister_id: candid::Principal,
     ) -> Result<u128, ObtainCyclesError> {
-        // get ICP/XDR rate from CMC
-        let price = self.get_icp_xdr_price().await?;
-
-        // convert cycle amount to ICP amount
-        let icp_amount =
-            self.calculate_icp_amount(amount, price.data.xdr_permyriad_per_icp as u128);
+        Ok(self.mint(amount, target_canister_id).await?.cycles)
+    }
 
-        // transfer ICP to ledger account of CMC
-        let block_index = self
-            .transfer_icp_to_cmc(icp_amount, target_canister_id)
+    async fn obtain_cycles_with_receipt(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+        pending_mints: &PendingMints,
+        _pending_withdrawals: &PendingWithdrawals,
+    ) -> Result<ObtainCyclesReceipt, ObtainCyclesError> {
+        let mint = self
+            .minter()
+            .mint(amount, target_canister_id, pending_mints)
             .await?;
 
-        // notify the CMC canister about the transfer so it can mint cycles
-        // retry if the transaction is still processing
-        self.notify_cmc_top_up(block_index, target_canister_id)
-            .await
+        Ok(mint.into())
     }
-}
 
-impl MintCycles {
-    async fn get_icp_xdr_price(&self) -> Result<GetIcpXdrResult, ObtainCyclesError> {
-        self.cmc
-            .get_icp_xdr()
-            .await
-            .map_err(|err| ObtainCyclesError {
-                details: format!("Error getting ICP/XDR price from CMC: message={}", err),
-                can_retry: true,
-            })
+    async fn available_cycles(&self) -> Result<Option<u128>, ObtainCyclesError> {
+        Ok(Some(self.minter().available_cycles().await?))
     }
 
-    fn calculate_icp_amount(&self, cycles_amount: u128, xdr_permyriad_per_icp: u128) -> u128 {
-        let cycles_per_xdr: u128 = 1_000_000_000_000; // 1 trillion cycles per XDR
-        let cycles_per_icp: u128 = xdr_permyriad_per_icp * cycles_per_xdr / 10_000u128;
-        cycles_amount * 100_000_000u128 / cycles_per_icp
+    async fn resume_pending(
+        &self,
+        pending_mints: &PendingMints,
+    ) -> Vec<(Principal, ObtainCyclesReceipt)> {
+        self.minter().resume(pending_mints).await
     }
+}
 
-    async fn transfer_icp_to_cmc(
+impl MintCycles {
+    /// Creates a new minting strategy transferring the ICP from the subaccount of the funding canister.
+    pub fn new(
+        cmc: Arc<dyn CyclesMintingCanister>,
+        ledger: Arc<dyn LedgerCanister>,
+        from_subaccount: Subaccount,
+    ) -> Self {
+        Self {
+            cmc,
+            ledger,
+            from_subaccount,
+            transfer_method: IcpTransferMethod::default(),
+            pending_mints: PendingMints::default(),
+            notify_retry_policy: default_notify_retry_policy(),
+            price_guard: None,
+            fee: IcpFee::default(),
+        }
+    }
+
+    /// Mints the cycles for the target canister from ICP, and returns the ICP spent.
+    ///
+    /// The ICP amount is rounded up, so that at least the requested cycles are minted.
+    pub async fn mint(
         &self,
-        icp_amount_e8s: u128,
+        amount: u128,
         target_canister_id: Principal,
-    ) -> Result<u64, ObtainCyclesError> {
-        let call_result = self
-            .ledger
-            .transfer(TransferArgs {
-                memo: Memo(0x5055_5054),
-                amount: Tokens::from_e8s(icp_amount_e8s as u64),
-                fee: Tokens::from_e8s(10_000),
-                from_subaccount: Some(self.from_subaccount),
-                to: self.cmc.get_top_up_address(target_canister_id),
-                created_at_time: None,
-            })
+    ) -> Result<MintReceipt, ObtainCyclesError> {
+        self.minter()
+            .mint(amount, target_canister_id, &self.pending_mints)
             .await
-            .map_err(|err| ObtainCyclesError {
-                details: format!("Error transferring ICP to CMC account: message={}", err),
-                can_retry: true,
-            })?;
+    }
 
-        call_result.map_err(|err| ObtainCyclesError {
-            can_retry: matches!(&err, ic_ledger_types::TransferError::TxCreatedInFuture),
-            details: format!("Error transferring ICP to CMC account: {err}"),
-        })
+    /// Sets the fee of the ICP transfers to the CMC.
+    pub fn with_fee(mut self, fee: IcpFee) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// Sets the sanity checks of the ICP/XDR rate and the limits of the ICP spent, which refuse to
+    /// mint cycles when violated.
+    pub fn with_price_guard(mut self, price_guard: IcpPriceGuard) -> Self {
+        self.price_guard = Some(price_guard);
+        self
+    }
+
+    /// Sets the policy for retrying the notification of the CMC.
+    pub fn with_notify_retry_policy(mut self, notify_retry_policy: RetryPolicy) -> Self {
+        self.notify_retry_policy = notify_retry_policy;
+        self
+    }
+
+    /// Sets the pending mints of the direct calls to `obtain_cycles` and `mint`, e.g. to restore them
+    /// after an upgrade. The fund manager passes its own pending mints instead.
+    pub fn with_pending_mints(mut self, pending_mints: PendingMints) -> Self {
+        self.pending_mints = pending_mints;
+        self
     }
 
-    async fn notify_cmc_top_up(
+    /// Returns the pending mints of the direct calls to `obtain_cycles` and `mint`.
+    pub fn pending_mints(&self) -> PendingMints {
+        self.pending_mints.clone()
+    }
+
+    /// Sets the ledger endpoint used to transfer the ICP to the CMC.
+    pub fn with_transfer_method(mut self, transfer_method: IcpTransferMethod) -> Self {
+        self.transfer_method = transfer_method;
+        self
+    }
+
+    fn minter(&self) -> IcpMinter<'_> {
+        IcpMinter {
+            cmc: self.cmc.as_ref(),
+            ledger: self.ledger.as_ref(),
+            source: IcpSource::Subaccount(self.from_subaccount, self.transfer_method),
+            kind: MintKind::TopUp,
+            fee: self.fee,
+            price_guard: self.price_guard.as_ref(),
+            notify_retry_policy: &self.notify_retry_policy,
+        }
+    }
+}
+
+impl From<MintReceipt> for ObtainCyclesReceipt {
+    fn from(mint: MintReceipt) -> Self {
+        ObtainCyclesReceipt {
+            cycles: mint.cycles,
+            block_index: Some(mint.block_index),
+            mint: Some(mint),
+        }
+    }
+}
+
+/// The account the ICP of a mint is transferred from.
+#[derive(Clone, Copy)]
+enum IcpSource {
+    /// A subaccount of the funding canister, transferred from with the transfer method.
+    Subaccount(Subaccount, IcpTransferMethod),
+    /// An account that approved the funding canister as a spender (ICRC-2).
+    Allowance {
+        from: Account,
+        spender_subaccount: Option<account::Subaccount>,
+    },
+}
+
+/// Mints cycles from ICP for the minting sources, which keeps the ICP transfers pending until their
+/// cycles are minted, so that a retried mint never transfers the ICP twice.
+struct IcpMinter<'a> {
+    cmc: &'a dyn CyclesMintingCanister,
+    ledger: &'a dyn LedgerCanister,
+    source: IcpSource,
+    kind: MintKind,
+    fee: IcpFee,
+    price_guard: Option<&'a IcpPriceGuard>,
+    notify_retry_policy: &'a RetryPolicy,
+}
+
+impl IcpMinter<'_> {
+    /// Mints the cycles for the target canister, completing the pending mint of the same kind from
+    /// the same account for the canister instead if there is one.
+    async fn mint(
         &self,
-        block_index: u64,
+        amount: u128,
         target_canister_id: Principal,
-    ) -> Result<u128, ObtainCyclesError> {
-        let mut retries_left = 10;
+        pending_mints: &PendingMints,
+    ) -> Result<MintReceipt, ObtainCyclesError> {
+        let from = self.source_account();
+        let (pending_mint, reused) = match pending_mints.get(&from, &self.kind, &target_canister_id)
+        {
+            Some(pending_mint) => (pending_mint, true),
+            None => {
+                // get ICP/XDR rate from CMC
+                let price = get_icp_xdr_price(self.cmc).await?;
+                if let Some(price_guard) = self.price_guard {
+                    price_guard.check_rate(&price, time())?;
+                }
 
-        loop {
-            retries_left -= 1;
+                // convert cycle amount to ICP amount
+                let icp_amount = calculate_icp_amount(amount, price.data.xdr_permyriad_per_icp)?;
 
-            match self
-                .cmc
-                .notify_top_up(block_index, target_canister_id)
-                .await
-            {
-                Err(err) => {
-                    if retries_left == 0 {
-                        return Err(ObtainCyclesError {
-                            details: format!("Error notifying CMC about top-up: message={}", err),
-                            can_retry: false,
-                        });
-                    } else {
-                        continue;
-                    }
+                let pending_mint = PendingMint {
+                    from,
+                    target_canister_id,
+                    kind: self.kind,
+                    icp_amount_e8s: icp_amount,
+                    created_at_time: time(),
+                    block_index: None,
+                    fee_e8s: Some(self.transfer_fee_e8s().await?),
+                };
+                if let IcpSource::Allowance { .. } = self.source {
+                    let allowance = self.allowance().await?;
+                    check_allowance(&allowance, u128::from(pending_mint.total_e8s()), time())?;
                 }
-                Ok(NotifyTopUpResult::Ok(cycles)) => return Ok(cycles),
-                Ok(NotifyTopUpResult::Err(err)) => match &err {
-                    NotifyError::Refunded {
-                        reason,
-                        block_index,
-                    } => {
-                        return Err(ObtainCyclesError {
-                            details: format!(
-                                "Top-up transaction refunded: reason={reason}, block_index={block_index:?}"
-                            ),
-                            can_retry: true,
+                if let Some(price_guard) = self.price_guard {
+                    price_guard.reserve_icp(pending_mint.total_e8s(), time())?;
+                }
+
+                (pending_mint, false)
+            }
+        };
+
+        let block_index = match pending_mint.block_index {
+            Some(block_index) => block_index,
+            None => {
+                pending_mints.set(pending_mint.clone());
+
+                // transfer ICP to ledger account of CMC, a retried transfer is deduplicated by the ledger
+                match self.transfer_icp_to_cmc(&pending_mint).await {
+                    Ok(block_index) => {
+                        pending_mints.set(PendingMint {
+                            block_index: Some(block_index),
+                            ..pending_mint.clone()
                         });
+                        block_index
                     }
-                    NotifyError::Processing => {
-                        if retries_left == 0 {
-                            return Err(ObtainCyclesError {
-                                details: "Top-up transaction still processing after retries."
-                                    .to_owned(),
-                                can_retry: false,
-                            });
+                    Err(err) => {
+                        if err.error.can_retry {
+                            return Err(err.error);
                         }
-                        continue;
-                    }
-                    NotifyError::TransactionTooOld(_) => {
-                        return Err(ObtainCyclesError {
-                            details: "Top-up transaction too old.".to_owned(),
-                            can_retry: false,
-                        });
-                    }
-                    NotifyError::InvalidTransaction(message) => {
-                        return Err(ObtainCyclesError {
-                            details: format!("Invalid top-up transaction: {message}"),
-                            can_retry: false,
-                        });
-                    }
-                    NotifyError::Other {
-                        error_code,
-                        error_message,
-                    } => {
-                        if retries_left == 0 {
-                            return Err(ObtainCyclesError {
-                                details: format!(
-                                    "Error notifying CMC about top-up: code={error_code}, message={error_message}"
-                                ),
-                                can_retry: false,
-                            });
+
+                        // a reused transfer may have been executed by a previous attempt whose reply
+                        // was lost, e.g. if it is now too old to be deduplicated, so it is only known
+                        // to have failed if the ledger rejected it
+                        if !reused || err.rejected {
+                            pending_mints.remove(&pending_mint, false);
+                            if let Some(price_guard) = self.price_guard {
+                                price_guard.release_icp(pending_mint.total_e8s());
+                            }
+                        } else {
+                            pending_mints.remove(&pending_mint, true);
                         }
-                        continue;
+                        return Err(err.error);
                     }
-                },
+                }
+            }
+        };
+
+        // notify the CMC canister about the transfer so it can mint cycles
+        // retry if the transaction is still processing
+        let cycles = self
+            .notify_pending_mint(pending_mints, &pending_mint, block_index)
+            .await?;
+
+        Ok(MintReceipt {
+            icp_spent_e8s: pending_mint.icp_amount_e8s,
+            fee_e8s: pending_mint.transfer_fee_e8s(),
+            cycles,
+            block_index,
+        })
+    }
+
+    /// Completes the pending mints of the kind from the account whose transfer is known, and
+    /// returns the mint of each target canister.
+    ///
+    /// The cycles deposited into the cycles ledger are not reported, as no canister received them.
+    async fn resume(&self, pending_mints: &PendingMints) -> Vec<(Principal, ObtainCyclesReceipt)> {
+        let from = self.source_account();
+        let mut obtained = Vec::new();
+        for pending_mint in pending_mints.pending() {
+            if pending_mint.from != from || pending_mint.kind != self.kind {
+                continue;
+            }
+            let Some(block_index) = pending_mint.block_index else {
+                continue;
+            };
+
+            if let Ok(cycles) = self
+                .notify_pending_mint(pending_mints, &pending_mint, block_index)
+                .await
+            {
+                if pending_mint.kind == MintKind::TopUp {
+                    obtained.push((
+                        pending_mint.target_canister_id,
+                        MintReceipt {
+                            icp_spent_e8s: pending_mint.icp_amount_e8s,
+                            fee_e8s: pending_mint.transfer_fee_e8s(),
+                            cycles,
+                            block_index,
+                        }
+                        .into(),
+                    ));
+                }
             }
         }
+
+        obtained
     }
-}
 
-pub struct WithdrawFromCyclesLedger {
-    pub ledger: Arc<dyn WithdrawableLedgerCanister>,
-    pub from_subaccount: Option<account::Subaccount>,
-}
+    /// Returns the cycles the ICP of the account can mint at the current rate, after paying the fee.
+    async fn available_cycles(&self) -> Result<u128, ObtainCyclesError> {
+        let balance_e8s = match self.source {
+            IcpSource::Subaccount(subaccount, _) => {
+                icp_account_balance(
+                    self.ledger,
+                    AccountIdentifier::new(&canister_self(), &subaccount),
+                )
+                .await?
+            }
+            IcpSource::Allowance { from, .. } => {
+                let allowance = self.allowance().await?;
+                if allowance
+                    .expires_at
+                    .is_some_and(|expires_at| expires_at <= time())
+                {
+                    return Ok(0);
+                }
 
-#[async_trait]
-impl ObtainCycles for WithdrawFromCyclesLedger {
-    async fn obtain_cycles(
+                let allowance = cycles_nat_to_u128(allowance.allowance).unwrap_or(u128::MAX);
+                let balance = icrc1_balance(self.ledger, from).await?;
+                u64::try_from(cmp::min(allowance, balance)).unwrap_or(u64::MAX)
+            }
+        };
+        let fee_e8s = self.transfer_fee_e8s().await?;
+
+        mintable_cycles(self.cmc, balance_e8s, fee_e8s).await
+    }
+
+    /// Returns the account the ICP is transferred from.
+    fn source_account(&self) -> Account {
+        match self.source {
+            IcpSource::Subaccount(subaccount, _) => Account {
+                owner: canister_self(),
+                subaccount: Some(subaccount.0),
+            },
+            IcpSource::Allowance { from, .. } => from,
+        }
+    }
+
+    /// Returns the fee in e8s of a new ICP transfer to the CMC.
+    async fn transfer_fee_e8s(&self) -> Result<u64, ObtainCyclesError> {
+        match self.fee {
+            IcpFee::Fixed(fee_e8s) => Ok(fee_e8s),
+            IcpFee::Queried => {
+                let fee = self
+                    .ledger
+                    .icrc1_fee()
+                    .await
+                    .map_err(|err| ObtainCyclesError {
+                        details: format!("Error getting the ICP ledger fee: message={}", err),
+                        can_retry: true,
+                    })?;
+
+                u64::try_from(fee.0.clone()).map_err(|_| ObtainCyclesError {
+                    details: format!("The ICP ledger fee {fee} does not fit into u64"),
+                    can_retry: false,
+                })
+            }
+        }
+    }
+
+    /// Returns the allowance the account approved for the funding canister.
+    async fn allowance(&self) -> Result<Allowance, ObtainCyclesError> {
+        let IcpSource::Allowance {
+            from,
+            spender_subaccount,
+        } = self.source
+        else {
+            return Ok(Allowance {
+                allowance: u128::MAX.into(),
+                expires_at: None,
+            });
+        };
+
+        self.ledger
+            .allowance(AllowanceArgs {
+                account: from,
+                spender: Account {
+                    owner: canister_self(),
+                    subaccount: spender_subaccount,
+                },
+            })
+            .await
+            .map_err(|err| ObtainCyclesError {
+                details: format!("Error getting allowance from ICP ledger: message={}", err),
+                can_retry: true,
+            })
+    }
+
+    /// Notifies the CMC about the transfer of a pending mint, so it can mint cycles, and settles the
+    /// pending mint unless the notification can be retried later. Returns the minted cycles.
+    async fn notify_pending_mint(
         &self,
-        amount: u128,
-        target_canister_id: Principal,
+        pending_mints: &PendingMints,
+        pending_mint: &PendingMint,
+        block_index: u64,
     ) -> Result<u128, ObtainCyclesError> {
-        self.withdraw(amount, target_canister_id).await?;
-        Ok(amount)
+        let result = match pending_mint.kind {
+            MintKind::TopUp => {
+                notify_cmc_top_up(
+                    self.cmc,
+                    self.notify_retry_policy,
+                    block_index,
+                    pending_mint.target_canister_id,
+                )
+                .await
+            }
+            MintKind::CyclesLedger { subaccount } => {
+                notify_cmc_mint_cycles(self.cmc, self.notify_retry_policy, block_index, subaccount)
+                    .await
+                    .map(|minted| cycles_nat_to_u128(minted.minted).unwrap_or(u128::MAX))
+            }
+        };
+
+        match &result {
+            Ok(_) => pending_mints.remove(pending_mint, false),
+            Err(err) if err.transfer != TransferState::Pending => {
+                pending_mints.remove(pending_mint, err.transfer == TransferState::Stranded);
+            }
+            Err(_) => {}
+        }
+
+        Ok(result?)
     }
-}
 
-impl WithdrawFromCyclesLedger {
-    /// # Errors
-    /// Returns an error if the withdrawal fails.
-    pub async fn withdraw(
+    async fn transfer_icp_to_cmc(
         &self,
-        amount: u128,
-        to: Principal,
-    ) -> Result<BlockIndex, ObtainCyclesError> {
+        pending_mint: &PendingMint,
+    ) -> Result<u64, TransferIcpError> {
+        match self.source {
+            IcpSource::Subaccount(subaccount, IcpTransferMethod::Legacy) => {
+                self.legacy_transfer_icp_to_cmc(subaccount, pending_mint)
+                    .await
+            }
+            IcpSource::Subaccount(subaccount, IcpTransferMethod::Icrc1) => {
+                self.icrc1_transfer_icp_to_cmc(subaccount, pending_mint)
+                    .await
+            }
+            IcpSource::Allowance {
+                from,
+                spender_subaccount,
+            } => {
+                self.transfer_icp_from_to_cmc(from, spender_subaccount, pending_mint)
+                    .await
+            }
+        }
+    }
+
+    async fn legacy_transfer_icp_to_cmc(
+        &self,
+        from_subaccount: Subaccount,
+        pending_mint: &PendingMint,
+    ) -> Result<u64, TransferIcpError> {
         let call_result = self
             .ledger
-            .withdraw(WithdrawArgs {
-                amount: amount.into(),
-                from_subaccount: self.from_subaccount,
-                to,
-                created_at_time: None,
+            .transfer(TransferArgs {
+                memo: Memo(pending_mint.kind.memo()),
+                amount: Tokens::from_e8s(pending_mint.icp_amount_e8s),
+                fee: Tokens::from_e8s(pending_mint.transfer_fee_e8s()),
+                from_subaccount: Some(from_subaccount),
+                to: self.cmc.get_top_up_address(pending_mint.target_canister_id),
+                created_at_time: Some(ic_ledger_types::Timestamp {
+                    timestamp_nanos: pending_mint.created_at_time,
+                }),
             })
             .await
-            .map_err(|err| ObtainCyclesError {
-                details: format!("error: {}", err),
-                can_retry: true,
+            .map_err(|err| {
+                TransferIcpError::unknown(ObtainCyclesError {
+                    details: format!("Error transferring ICP to CMC account: message={}", err),
+                    can_retry: true,
+                })
             })?;
 
-        call_result.map_err(|err| ObtainCyclesError {
-            details: match &err {
-                WithdrawError::BadFee { expected_fee } => {
-                    format!("Bad fee, expected: {expected_fee}")
-                }
-                WithdrawError::InsufficientFunds { balance } => {
-                    format!("Insufficient balance, balance: {balance}")
-                }
-                WithdrawError::TooOld => "Tx too old".to_string(),
-                WithdrawError::CreatedInFuture { .. } => "Tx created in future".to_string(),
-                WithdrawError::Duplicate { duplicate_of } => {
-                    format!("Tx duplicate, duplicate_of: {duplicate_of}")
-                }
-                WithdrawError::FailedToWithdraw {
-                    rejection_code,
-                    rejection_reason,
-                    ..
-                } => {
-                    format!(
-                        "Failed to withdraw. Code:{rejection_code:?}, reason:{rejection_reason}"
-                    )
-                }
-                WithdrawError::TemporarilyUnavailable => {
-                    "Ledger temporarily unavailable".to_string()
-                }
-                WithdrawError::GenericError {
-                    error_code,
-                    message,
-                } => {
-                    format!("Error occurred. Code: {error_code}, message: {message}")
-                }
-                WithdrawError::InvalidReceiver { receiver } => {
-                    format!("Invalid receiver: {receiver}")
-                }
-            },
-            can_retry: matches!(&err, WithdrawError::CreatedInFuture { .. }),
+        match call_result {
+            Ok(block_index) => Ok(block_index),
+            // the transfer was already executed by a previous attempt
+            Err(ic_ledger_types::TransferError::TxDuplicate { duplicate_of }) => Ok(duplicate_of),
+            Err(err) => Err(TransferIcpError {
+                rejected: matches!(
+                    &err,
+                    ic_ledger_types::TransferError::BadFee { .. }
+                        | ic_ledger_types::TransferError::InsufficientFunds { .. }
+                ),
+                error: ObtainCyclesError {
+                    can_retry: matches!(&err, ic_ledger_types::TransferError::TxCreatedInFuture),
+                    details: format!("Error transferring ICP to CMC account: {err}"),
+                },
+            }),
+        }
+    }
+
+    async fn icrc1_transfer_icp_to_cmc(
+        &self,
+        from_subaccount: Subaccount,
+        pending_mint: &PendingMint,
+    ) -> Result<u64, TransferIcpError> {
+        let call_result = self
+            .ledger
+            .icrc1_transfer(TransferArg {
+                from_subaccount: Some(from_subaccount.0),
+                to: self.cmc.get_top_up_account(pending_mint.target_canister_id),
+                fee: Some(pending_mint.transfer_fee_e8s().into()),
+                created_at_time: Some(pending_mint.created_at_time),
+                memo: Some(icrc1_memo(pending_mint.kind.memo())),
+                amount: pending_mint.icp_amount_e8s.into(),
+            })
+            .await
+            .map_err(|err| {
+                TransferIcpError::unknown(ObtainCyclesError {
+                    details: format!("Error transferring ICP to CMC account: message={}", err),
+                    can_retry: true,
+                })
+            })?;
+
+        let block_index = match call_result {
+            Ok(block_index) => block_index,
+            // the transfer was already executed by a previous attempt
+            Err(icrc_ledger_types::icrc1::transfer::TransferError::Duplicate { duplicate_of }) => {
+                duplicate_of
+            }
+            Err(err) => {
+                return Err(TransferIcpError {
+                    rejected: matches!(
+                        &err,
+                        icrc_ledger_types::icrc1::transfer::TransferError::BadFee { .. }
+                            | icrc_ledger_types::icrc1::transfer::TransferError::BadBurn { .. }
+                            | icrc_ledger_types::icrc1::transfer::TransferError::InsufficientFunds { .. }
+                    ),
+                    error: ObtainCyclesError {
+                        can_retry: matches!(
+                            &err,
+                            icrc_ledger_types::icrc1::transfer::TransferError::CreatedInFuture { .. }
+                                | icrc_ledger_types::icrc1::transfer::TransferError::TemporarilyUnavailable
+                        ),
+                        details: format!("Error transferring ICP to CMC account: {err}"),
+                    },
+                })
+            }
+        };
+
+        block_index_to_u64(block_index).map_err(TransferIcpError::unknown)
+    }
+
+    async fn transfer_icp_from_to_cmc(
+        &self,
+        from: Account,
+        spender_subaccount: Option<account::Subaccount>,
+        pending_mint: &PendingMint,
+    ) -> Result<u64, TransferIcpError> {
+        let call_result = self
+            .ledger
+            .transfer_from(TransferFromArgs {
+                spender_subaccount,
+                from,
+                to: self.cmc.get_top_up_account(pending_mint.target_canister_id),
+                amount: pending_mint.icp_amount_e8s.into(),
+                fee: Some(pending_mint.transfer_fee_e8s().into()),
+                memo: Some(icrc1_memo(pending_mint.kind.memo())),
+                created_at_time: Some(pending_mint.created_at_time),
+            })
+            .await
+            .map_err(|err| {
+                TransferIcpError::unknown(ObtainCyclesError {
+                    details: format!("Error transferring ICP to CMC account: message={}", err),
+                    can_retry: true,
+                })
+            })?;
+
+        let block_index = match call_result {
+            Ok(block_index) => block_index,
+            // the transfer was already executed by a previous attempt
+            Err(TransferFromError::Duplicate { duplicate_of }) => duplicate_of,
+            Err(err) => {
+                return Err(TransferIcpError {
+                    rejected: matches!(
+                        &err,
+                        TransferFromError::BadFee { .. }
+                            | TransferFromError::BadBurn { .. }
+                            | TransferFromError::InsufficientFunds { .. }
+                            | TransferFromError::InsufficientAllowance { .. }
+                    ),
+                    error: ObtainCyclesError {
+                        can_retry: matches!(
+                            &err,
+                            TransferFromError::CreatedInFuture { .. }
+                                | TransferFromError::TemporarilyUnavailable
+                        ),
+                        details: format!("Error transferring ICP to CMC account: {err}"),
+                    },
+                })
+            }
+        };
+
+        block_index_to_u64(block_index).map_err(TransferIcpError::unknown)
+    }
+}
+
+/// Mints cycles from ICP held by an account that approved the funding canister as a spender (ICRC-2),
+/// e.g. a treasury account, so that the funding canister never has to hold ICP itself.
+///
+/// The ICP transfers are kept pending like the ones of `MintCycles`, and the allowance is checked to
+/// cover the ICP and the fee before transferring them.
+pub struct MintCyclesFromAllowance {
+    pub cmc: Arc<dyn CyclesMintingCanister>,
+    pub ledger: Arc<dyn LedgerCanister>,
+    /// The account holding the ICP that granted the allowance to the funding canister.
+    pub from: Account,
+    /// The subaccount of the funding canister the allowance was granted to.
+    pub spender_subaccount: Option<account::Subaccount>,
+    /// The mints of the direct calls to `obtain_cycles`, the fund manager passes its own.
+    pending_mints: PendingMints,
+    /// The policy for retrying the notification of the CMC.
+    notify_retry_policy: RetryPolicy,
+    /// The sanity checks of the ICP/XDR rate and the limits of the ICP spent.
+    price_guard: Option<IcpPriceGuard>,
+    /// The fee of the ICP transfers to the CMC.
+    fee: IcpFee,
+}
+
+#[async_trait]
+impl ObtainCycles for MintCyclesFromAllowance {
+    async fn obtain_cycles(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+    ) -> Result<u128, ObtainCyclesError> {
+        Ok(self
+            .minter()
+            .mint(amount, target_canister_id, &self.pending_mints)
+            .await?
+            .cycles)
+    }
+
+    async fn obtain_cycles_with_receipt(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+        pending_mints: &PendingMints,
+        _pending_withdrawals: &PendingWithdrawals,
+    ) -> Result<ObtainCyclesReceipt, ObtainCyclesError> {
+        let mint = self
+            .minter()
+            .mint(amount, target_canister_id, pending_mints)
+            .await?;
+
+        Ok(mint.into())
+    }
+
+    async fn available_cycles(&self) -> Result<Option<u128>, ObtainCyclesError> {
+        Ok(Some(self.minter().available_cycles().await?))
+    }
+
+    async fn resume_pending(
+        &self,
+        pending_mints: &PendingMints,
+    ) -> Vec<(Principal, ObtainCyclesReceipt)> {
+        self.minter().resume(pending_mints).await
+    }
+}
+
+impl MintCyclesFromAllowance {
+    /// Creates a new minting strategy pulling the ICP from the account that approved the funding
+    /// canister as a spender.
+    pub fn new(
+        cmc: Arc<dyn CyclesMintingCanister>,
+        ledger: Arc<dyn LedgerCanister>,
+        from: Account,
+    ) -> Self {
+        Self {
+            cmc,
+            ledger,
+            from,
+            spender_subaccount: None,
+            pending_mints: PendingMints::default(),
+            notify_retry_policy: default_notify_retry_policy(),
+            price_guard: None,
+            fee: IcpFee::default(),
+        }
+    }
+
+    /// Sets the subaccount of the funding canister the allowance was granted to.
+    pub fn with_spender_subaccount(
+        mut self,
+        spender_subaccount: Option<account::Subaccount>,
+    ) -> Self {
+        self.spender_subaccount = spender_subaccount;
+        self
+    }
+
+    /// Sets the fee of the ICP transfers to the CMC.
+    pub fn with_fee(mut self, fee: IcpFee) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// Sets the sanity checks of the ICP/XDR rate and the limits of the ICP spent, which refuse to
+    /// mint cycles when violated.
+    pub fn with_price_guard(mut self, price_guard: IcpPriceGuard) -> Self {
+        self.price_guard = Some(price_guard);
+        self
+    }
+
+    /// Sets the policy for retrying the notification of the CMC.
+    pub fn with_notify_retry_policy(mut self, notify_retry_policy: RetryPolicy) -> Self {
+        self.notify_retry_policy = notify_retry_policy;
+        self
+    }
+
+    /// Sets the pending mints of the direct calls to `obtain_cycles`, e.g. to restore them after an
+    /// upgrade. The fund manager passes its own pending mints instead.
+    pub fn with_pending_mints(mut self, pending_mints: PendingMints) -> Self {
+        self.pending_mints = pending_mints;
+        self
+    }
+
+    /// Returns the pending mints of the direct calls to `obtain_cycles`.
+    pub fn pending_mints(&self) -> PendingMints {
+        self.pending_mints.clone()
+    }
+
+    fn minter(&self) -> IcpMinter<'_> {
+        IcpMinter {
+            cmc: self.cmc.as_ref(),
+            ledger: self.ledger.as_ref(),
+            source: IcpSource::Allowance {
+                from: self.from,
+                spender_subaccount: self.spender_subaccount,
+            },
+            kind: MintKind::TopUp,
+            fee: self.fee,
+            price_guard: self.price_guard.as_ref(),
+            notify_retry_policy: &self.notify_retry_policy,
+        }
+    }
+}
+
+async fn get_icp_xdr_price(
+    cmc: &dyn CyclesMintingCanister,
+) -> Result<GetIcpXdrResult, ObtainCyclesError> {
+    cmc.get_icp_xdr().await.map_err(|err| ObtainCyclesError {
+        details: format!("Error getting ICP/XDR price from CMC: message={}", err),
+        can_retry: true,
+    })
+}
+
+/// Converts the cycles to the ICP in e8s needed to mint them, rounded up so that at least the cycles
+/// are minted.
+///
+/// One XDR mints 1T cycles, so an ICP worth one XDR permyriad mints 100M cycles, i.e. one cycle per e8s.
+fn calculate_icp_amount(
+    cycles_amount: u128,
+    xdr_permyriad_per_icp: u64,
+) -> Result<u64, ObtainCyclesError> {
+    if xdr_permyriad_per_icp == 0 {
+        return Err(ObtainCyclesError {
+            details: "The ICP/XDR rate is zero".to_string(),
+            can_retry: false,
+        });
+    }
+
+    let icp_amount = cycles_amount.div_ceil(xdr_permyriad_per_icp as u128);
+
+    u64::try_from(icp_amount).map_err(|_| ObtainCyclesError {
+        details: format!("The ICP amount of {icp_amount} e8s to mint {cycles_amount} cycles does not fit into u64"),
+        can_retry: false,
+    })
+}
+
+/// The CMC expects ICRC-1 memos to hold the little-endian bytes of the legacy memo.
+fn icrc1_memo(memo: u64) -> icrc_ledger_types::icrc1::transfer::Memo {
+    icrc_ledger_types::icrc1::transfer::Memo::from(memo.to_le_bytes().to_vec())
+}
+
+fn block_index_to_u64(block_index: BlockIndex) -> Result<u64, ObtainCyclesError> {
+    u64::try_from(block_index.0.clone()).map_err(|_| ObtainCyclesError {
+        details: format!("Block index {block_index} does not fit into u64"),
+        can_retry: false,
+    })
+}
+
+/// A failed CMC notification.
+struct NotifyCmcError {
+    error: ObtainCyclesError,
+    /// The state of the transferred ICP after the notification.
+    transfer: TransferState,
+}
+
+/// The error of an ICP transfer to the CMC.
+struct TransferIcpError {
+    error: ObtainCyclesError,
+    /// Whether the ledger rejected the transfer, e.g. for insufficient funds, which proves that no
+    /// previous attempt of the same transfer was executed either.
+    rejected: bool,
+}
+
+impl TransferIcpError {
+    /// Returns the error of a transfer that may have been executed.
+    fn unknown(error: ObtainCyclesError) -> Self {
+        Self {
+            error,
+            rejected: false,
+        }
+    }
+}
+
+/// The state of an ICP transfer to the CMC after a failed notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransferState {
+    /// The cycles may still be minted by notifying the CMC again.
+    Pending,
+    /// The CMC refunded the ICP.
+    Refunded,
+    /// The CMC refuses to mint the cycles, the ICP is left at its account.
+    Stranded,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A notification is retried as long as the transferred ICP may still be minted.
+impl RetryableError for NotifyCmcError {
+    fn details(&self) -> String {
+        self.error.details.clone()
+    }
+
+    fn can_retry(&self) -> bool {
+        self.transfer == TransferState::Pending
+    }
+}
+
+impl From<NotifyCmcError> for ObtainCyclesError {
+    fn from(err: NotifyCmcError) -> Self {
+        err.error
+    }
+}
+
+async fn notify_cmc_top_up(
+    cmc: &dyn CyclesMintingCanister,
+    retry_policy: &RetryPolicy,
+    block_index: u64,
+    target_canister_id: Principal,
+) -> Result<u128, NotifyCmcError> {
+    notify_cmc("top-up", retry_policy, || async {
+        cmc.notify_top_up(block_index, target_canister_id)
+            .await
+            .map(|result| match result {
+                NotifyTopUpResult::Ok(cycles) => Ok(cycles),
+                NotifyTopUpResult::Err(err) => Err(err),
+            })
+    })
+    .await
+}
+
+async fn notify_cmc_mint_cycles(
+    cmc: &dyn CyclesMintingCanister,
+    retry_policy: &RetryPolicy,
+    block_index: u64,
+    to_subaccount: Option<account::Subaccount>,
+) -> Result<NotifyMintCyclesSuccess, NotifyCmcError> {
+    notify_cmc("mint", retry_policy, || async {
+        cmc.notify_mint_cycles(block_index, to_subaccount)
+            .await
+            .map(|result| match result {
+                NotifyMintCyclesResult::Ok(success) => Ok(success),
+                NotifyMintCyclesResult::Err(err) => Err(err),
+            })
+    })
+    .await
+}
+
+/// Notifies the CMC about an ICP transfer, retrying according to the policy while the transaction is
+/// still processing.
+async fn notify_cmc<T, F, Fut>(
+    operation: &str,
+    retry_policy: &RetryPolicy,
+    notify: F,
+) -> Result<T, NotifyCmcError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = CallResult<Result<T, NotifyError>>>,
+{
+    retry_policy
+        .retry(RetryOperation::NotifyCmc, || async {
+            match notify().await {
+                Err(err) => Err(NotifyCmcError {
+                    error: ObtainCyclesError {
+                        details: format!("Error notifying CMC about {operation}: message={}", err),
+                        can_retry: false,
+                    },
+                    transfer: TransferState::Pending,
+                }),
+                Ok(Ok(result)) => Ok(result),
+                Ok(Err(err)) => Err(match err {
+                    NotifyError::Refunded {
+                        reason,
+                        block_index,
+                    } => NotifyCmcError {
+                        error: ObtainCyclesError {
+                            details: format!(
+                                "CMC {operation} transaction refunded: reason={reason}, block_index={block_index:?}"
+                            ),
+                            can_retry: true,
+                        },
+                        transfer: TransferState::Refunded,
+                    },
+                    NotifyError::Processing => NotifyCmcError {
+                        error: ObtainCyclesError {
+                            details: format!(
+                                "CMC {operation} transaction still processing after retries."
+                            ),
+                            can_retry: false,
+                        },
+                        transfer: TransferState::Pending,
+                    },
+                    NotifyError::TransactionTooOld(_) => NotifyCmcError {
+                        error: ObtainCyclesError {
+                            details: format!("CMC {operation} transaction too old."),
+                            can_retry: false,
+                        },
+                        transfer: TransferState::Stranded,
+                    },
+                    NotifyError::InvalidTransaction(message) => NotifyCmcError {
+                        error: ObtainCyclesError {
+                            details: format!("Invalid CMC {operation} transaction: {message}"),
+                            can_retry: false,
+                        },
+                        transfer: TransferState::Stranded,
+                    },
+                    NotifyError::Other {
+                        error_code,
+                        error_message,
+                    } => NotifyCmcError {
+                        error: ObtainCyclesError {
+                            details: format!(
+                                "Error notifying CMC about {operation}: code={error_code}, message={error_message}"
+                            ),
+                            can_retry: false,
+                        },
+                        transfer: TransferState::Pending,
+                    },
+                }),
+            }
         })
+        .await
+}
+
+/// Returns the ICP balance in e8s of the account on the ICP ledger.
+async fn icp_account_balance(
+    ledger: &dyn LedgerCanister,
+    account: AccountIdentifier,
+) -> Result<u64, ObtainCyclesError> {
+    let balance = ledger
+        .account_balance(AccountBalanceArgs { account })
+        .await
+        .map_err(|err| ObtainCyclesError {
+            details: format!("Error getting ICP balance from ledger: message={}", err),
+            can_retry: true,
+        })?;
+
+    Ok(balance.e8s())
+}
+
+/// Returns the ICP balance in e8s of the account on the ICP ledger using the ICRC-1 standard.
+async fn icrc1_balance(
+    ledger: &dyn LedgerCanister,
+    account: Account,
+) -> Result<u128, ObtainCyclesError> {
+    let balance = ledger
+        .icrc1_balance_of(account)
+        .await
+        .map_err(|err| ObtainCyclesError {
+            details: format!("Error getting ICP balance from ledger: message={}", err),
+            can_retry: true,
+        })?;
+
+    Ok(cycles_nat_to_u128(balance).unwrap_or(u128::MAX))
+}
+
+/// Returns the cycles balance of the account on the cycles ledger.
+async fn cycles_ledger_balance(
+    ledger: &dyn WithdrawableLedgerCanister,
+    account: Account,
+) -> Result<u128, ObtainCyclesError> {
+    let balance = ledger
+        .icrc1_balance_of(account)
+        .await
+        .map_err(|err| ObtainCyclesError {
+            details: format!(
+                "Error getting cycles balance from cycles ledger: message={}",
+                err
+            ),
+            can_retry: true,
+        })?;
+
+    Ok(cycles_nat_to_u128(balance).unwrap_or(u128::MAX))
+}
+
+/// Returns the cycles the ICP balance can mint at the current ICP/XDR rate, after paying the
+/// transfer fee.
+async fn mintable_cycles(
+    cmc: &dyn CyclesMintingCanister,
+    balance_e8s: u64,
+    fee_e8s: u64,
+) -> Result<u128, ObtainCyclesError> {
+    let price = get_icp_xdr_price(cmc).await?;
+
+    Ok((balance_e8s.saturating_sub(fee_e8s) as u128)
+        .saturating_mul(price.data.xdr_permyriad_per_icp as u128))
+}
+
+/// The default policy for notifying the CMC, retrying immediately while the transaction is processing.
+pub fn default_notify_retry_policy() -> RetryPolicy {
+    RetryPolicy::immediate(10)
+}
+
+/// Mints cycles from ICP into the cycles ledger account of the funding canister in batches and withdraws
+/// the requested cycles from there, so that the ICP fees are paid once per batch instead of once per top-up.
+///
+/// The ICP transfers are kept as pending mints and the withdrawals are retried with the same
+/// `created_at_time`, so that neither the ICP nor the cycles are spent twice when a call fails.
+pub struct MintCyclesToCyclesLedger {
+    pub cmc: Arc<dyn CyclesMintingCanister>,
+    pub ledger: Arc<dyn LedgerCanister>,
+    pub cycles_ledger: Arc<dyn WithdrawableLedgerCanister>,
+    /// The subaccount of the funding canister holding the ICP.
+    pub from_subaccount: Subaccount,
+    /// The subaccount of the funding canister on the cycles ledger the cycles are minted into.
+    pub cycles_ledger_subaccount: Option<account::Subaccount>,
+    /// The minimum amount of cycles to mint at once when the cycles ledger balance is too low.
+    pub mint_batch_cycles: u128,
+    /// The policy for retrying the notification of the CMC.
+    pub notify_retry_policy: RetryPolicy,
+    /// The mints of the direct calls to `obtain_cycles`, the fund manager passes its own.
+    pending_mints: PendingMints,
+    /// The withdrawals of the direct calls to `obtain_cycles`, the fund manager passes its own.
+    pending_withdrawals: PendingWithdrawals,
+    /// The fee of the ICP transfers to the CMC.
+    fee: IcpFee,
+    /// The sanity checks of the ICP/XDR rate and the limits of the ICP spent.
+    price_guard: Option<IcpPriceGuard>,
+}
+
+#[async_trait]
+impl ObtainCycles for MintCyclesToCyclesLedger {
+    async fn obtain_cycles(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+    ) -> Result<u128, ObtainCyclesError> {
+        Ok(self
+            .obtain_cycles_with_receipt(
+                amount,
+                target_canister_id,
+                &self.pending_mints,
+                &self.pending_withdrawals,
+            )
+            .await?
+            .cycles)
+    }
+
+    async fn obtain_cycles_with_receipt(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+        pending_mints: &PendingMints,
+        pending_withdrawals: &PendingWithdrawals,
+    ) -> Result<ObtainCyclesReceipt, ObtainCyclesError> {
+        let balance = match pending_withdrawals
+            .withdraw(
+                self.cycles_ledger.as_ref(),
+                self.cycles_ledger_subaccount,
+                amount,
+                target_canister_id,
+            )
+            .await?
+        {
+            Ok(withdrawal) => return withdraw_receipt(withdrawal),
+            Err(WithdrawError::InsufficientFunds { balance }) => {
+                cycles_nat_to_u128(balance).unwrap_or_default()
+            }
+            Err(err) => return Err(withdraw_error(err)),
+        };
+
+        // The cycles ledger balance is too low, so a new batch of cycles is minted into it, or the
+        // pending batch is completed.
+        let shortfall = amount
+            .saturating_add(CYCLES_LEDGER_WITHDRAW_FEE)
+            .saturating_sub(balance);
+        let minted = self
+            .minter()
+            .mint(
+                cmp::max(shortfall, self.mint_batch_cycles),
+                canister_self(),
+                pending_mints,
+            )
+            .await?;
+        let balance = balance.saturating_add(minted.cycles);
+
+        // The minted cycles can be slightly lower than requested due to rounding of the ICP amount.
+        let amount = cmp::min(amount, balance.saturating_sub(CYCLES_LEDGER_WITHDRAW_FEE));
+        if amount == 0 {
+            return Err(ObtainCyclesError {
+                details: format!("Insufficient balance after minting, balance: {balance}"),
+                can_retry: false,
+            });
+        }
+
+        let withdrawal = pending_withdrawals
+            .withdraw(
+                self.cycles_ledger.as_ref(),
+                self.cycles_ledger_subaccount,
+                amount,
+                target_canister_id,
+            )
+            .await?
+            .map_err(withdraw_error)?;
+
+        withdraw_receipt(withdrawal)
+    }
+
+    async fn available_cycles(&self) -> Result<Option<u128>, ObtainCyclesError> {
+        let cycles_balance = cycles_ledger_balance(
+            self.cycles_ledger.as_ref(),
+            Account {
+                owner: canister_self(),
+                subaccount: self.cycles_ledger_subaccount,
+            },
+        )
+        .await?;
+        let mintable_cycles = self.minter().available_cycles().await?;
+
+        Ok(Some(
+            cycles_balance
+                .saturating_add(mintable_cycles)
+                .saturating_sub(CYCLES_LEDGER_WITHDRAW_FEE),
+        ))
+    }
+
+    async fn resume_pending(
+        &self,
+        pending_mints: &PendingMints,
+    ) -> Vec<(Principal, ObtainCyclesReceipt)> {
+        self.minter().resume(pending_mints).await
+    }
+}
+
+impl MintCyclesToCyclesLedger {
+    /// The default minimum amount of cycles minted at once.
+    pub const DEFAULT_MINT_BATCH_CYCLES: u128 = 10_000_000_000_000; // 10T cycles
+
+    /// Creates a new strategy minting the cycles into the default cycles ledger account of the funding canister.
+    pub fn new(
+        cmc: Arc<dyn CyclesMintingCanister>,
+        ledger: Arc<dyn LedgerCanister>,
+        cycles_ledger: Arc<dyn WithdrawableLedgerCanister>,
+        from_subaccount: Subaccount,
+    ) -> Self {
+        Self {
+            cmc,
+            ledger,
+            cycles_ledger,
+            from_subaccount,
+            cycles_ledger_subaccount: None,
+            mint_batch_cycles: Self::DEFAULT_MINT_BATCH_CYCLES,
+            notify_retry_policy: default_notify_retry_policy(),
+            pending_mints: PendingMints::default(),
+            pending_withdrawals: PendingWithdrawals::default(),
+            fee: IcpFee::default(),
+            price_guard: None,
+        }
+    }
+
+    /// Sets the policy for retrying the notification of the CMC.
+    pub fn with_notify_retry_policy(mut self, notify_retry_policy: RetryPolicy) -> Self {
+        self.notify_retry_policy = notify_retry_policy;
+        self
+    }
+
+    /// Sets the subaccount of the funding canister on the cycles ledger the cycles are minted into.
+    pub fn with_cycles_ledger_subaccount(
+        mut self,
+        cycles_ledger_subaccount: Option<account::Subaccount>,
+    ) -> Self {
+        self.cycles_ledger_subaccount = cycles_ledger_subaccount;
+        self
+    }
+
+    /// Sets the minimum amount of cycles to mint at once when the cycles ledger balance is too low.
+    pub fn with_mint_batch_cycles(mut self, mint_batch_cycles: u128) -> Self {
+        self.mint_batch_cycles = mint_batch_cycles;
+        self
+    }
+
+    /// Sets the fee of the ICP transfers to the CMC.
+    pub fn with_fee(mut self, fee: IcpFee) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// Sets the sanity checks of the ICP/XDR rate and the limits of the ICP spent, which refuse to
+    /// mint cycles when violated.
+    pub fn with_price_guard(mut self, price_guard: IcpPriceGuard) -> Self {
+        self.price_guard = Some(price_guard);
+        self
+    }
+
+    /// Sets the pending mints of the direct calls to `obtain_cycles`, e.g. to restore them after an
+    /// upgrade. The fund manager passes its own pending mints instead.
+    pub fn with_pending_mints(mut self, pending_mints: PendingMints) -> Self {
+        self.pending_mints = pending_mints;
+        self
+    }
+
+    /// Returns the pending mints of the direct calls to `obtain_cycles`.
+    pub fn pending_mints(&self) -> PendingMints {
+        self.pending_mints.clone()
+    }
+
+    /// Sets the pending withdrawals of the direct calls to `obtain_cycles`, e.g. to restore them
+    /// after an upgrade. The fund manager passes its own pending withdrawals instead.
+    pub fn with_pending_withdrawals(mut self, pending_withdrawals: PendingWithdrawals) -> Self {
+        self.pending_withdrawals = pending_withdrawals;
+        self
+    }
+
+    /// Returns the pending withdrawals of the direct calls to `obtain_cycles`.
+    pub fn pending_withdrawals(&self) -> PendingWithdrawals {
+        self.pending_withdrawals.clone()
+    }
+
+    fn minter(&self) -> IcpMinter<'_> {
+        IcpMinter {
+            cmc: self.cmc.as_ref(),
+            ledger: self.ledger.as_ref(),
+            source: IcpSource::Subaccount(self.from_subaccount, IcpTransferMethod::Legacy),
+            kind: MintKind::CyclesLedger {
+                subaccount: self.cycles_ledger_subaccount,
+            },
+            fee: self.fee,
+            price_guard: self.price_guard.as_ref(),
+            notify_retry_policy: &self.notify_retry_policy,
+        }
+    }
+}
+
+pub struct WithdrawFromCyclesLedger {
+    pub ledger: Arc<dyn WithdrawableLedgerCanister>,
+    pub from_subaccount: Option<account::Subaccount>,
+    /// The withdrawals of the direct calls to `obtain_cycles`, the fund manager passes its own.
+    pending_withdrawals: PendingWithdrawals,
+}
+
+/// A withdrawal from the cycles ledger whose outcome is unknown, e.g. because the reply of the
+/// cycles ledger was lost.
+///
+/// Retrying the withdrawal reuses its amount and `created_at_time`, so that the cycles ledger
+/// deduplicates it instead of withdrawing the cycles twice.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingWithdrawal {
+    /// The account the cycles are withdrawn from.
+    pub from: Account,
+    /// The canister the cycles are withdrawn to.
+    pub to: Principal,
+    /// The amount of cycles withdrawn.
+    pub amount: u128,
+    /// The `created_at_time` of the withdrawal in nanoseconds.
+    pub created_at_time: u64,
+}
+
+impl PendingWithdrawal {
+    /// Returns whether this is the withdrawal from the account to the canister.
+    fn is_withdrawal_of(&self, from: &Account, to: &Principal) -> bool {
+        self.from == *from && self.to == *to
+    }
+}
+
+/// The cycles withdrawn from the cycles ledger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Withdrawal {
+    /// The amount of cycles withdrawn.
+    pub amount: u128,
+    /// The block index of the withdrawal.
+    pub block_index: BlockIndex,
+    /// Whether a pending withdrawal of a previous request was retried instead of withdrawing the
+    /// requested amount, in which case the amounts can differ.
+    pub reused_pending: bool,
+}
+
+/// The withdrawals from the cycles ledger whose outcome is unknown, and the ones that can no longer
+/// be retried because the cycles ledger no longer deduplicates them.
+///
+/// The fund manager keeps them in its state next to the pending mints and passes them to the obtain
+/// cycles sources, so that they can be persisted across upgrades. Clones share the same withdrawals.
+#[derive(Clone, Debug, Default)]
+pub struct PendingWithdrawals {
+    inner: Arc<Mutex<PendingWithdrawalsState>>,
+}
+
+#[derive(Debug, Default)]
+struct PendingWithdrawalsState {
+    pending: Vec<PendingWithdrawal>,
+    stranded: Vec<PendingWithdrawal>,
+}
+
+/// The outcome of an attempt of a pending withdrawal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WithdrawalOutcome {
+    /// The withdrawal was executed, or is known to have failed.
+    Settled,
+    /// The withdrawal can be retried.
+    Unknown,
+    /// The withdrawal can no longer be retried, but may have been executed by a previous attempt.
+    Stranded,
+}
+
+impl PendingWithdrawals {
+    /// Creates the withdrawals from persisted ones, e.g. after an upgrade.
+    pub fn new(pending: Vec<PendingWithdrawal>, stranded: Vec<PendingWithdrawal>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(PendingWithdrawalsState { pending, stranded })),
+        }
+    }
+
+    /// Returns the withdrawals whose outcome is unknown.
+    pub fn pending(&self) -> Vec<PendingWithdrawal> {
+        lock(&self.inner).pending.clone()
+    }
+
+    /// Returns the withdrawals that can no longer be retried and need to be checked manually.
+    pub fn stranded(&self) -> Vec<PendingWithdrawal> {
+        lock(&self.inner).stranded.clone()
+    }
+
+    /// Removes a stranded withdrawal once its outcome has been checked.
+    pub fn remove_stranded(
+        &self,
+        stranded_withdrawal: &PendingWithdrawal,
+    ) -> Option<PendingWithdrawal> {
+        let stranded = &mut lock(&self.inner).stranded;
+        let position = stranded
+            .iter()
+            .position(|withdrawal| withdrawal == stranded_withdrawal)?;
+        Some(stranded.remove(position))
+    }
+
+    /// Returns whether a withdrawal from the account to the canister is pending.
+    fn contains(&self, from: &Account, to: &Principal) -> bool {
+        lock(&self.inner)
+            .pending
+            .iter()
+            .any(|withdrawal| withdrawal.is_withdrawal_of(from, to))
+    }
+
+    /// Returns the pending withdrawal from the account to the canister and `true`, or a new pending
+    /// withdrawal of the amount and `false`.
+    fn get_or_insert(
+        &self,
+        from: Account,
+        to: Principal,
+        amount: u128,
+    ) -> (PendingWithdrawal, bool) {
+        let pending = &mut lock(&self.inner).pending;
+        if let Some(withdrawal) = pending
+            .iter()
+            .find(|withdrawal| withdrawal.is_withdrawal_of(&from, &to))
+        {
+            return (withdrawal.clone(), true);
+        }
+
+        let withdrawal = PendingWithdrawal {
+            from,
+            to,
+            amount,
+            created_at_time: time(),
+        };
+        pending.push(withdrawal.clone());
+        (withdrawal, false)
+    }
+
+    /// Removes the pending withdrawal unless its outcome is unknown, keeping it as stranded if it
+    /// can no longer be retried.
+    fn settle(&self, pending_withdrawal: &PendingWithdrawal, outcome: WithdrawalOutcome) {
+        if outcome == WithdrawalOutcome::Unknown {
+            return;
+        }
+
+        let mut state = lock(&self.inner);
+        let Some(position) = state.pending.iter().position(|withdrawal| {
+            withdrawal.is_withdrawal_of(&pending_withdrawal.from, &pending_withdrawal.to)
+        }) else {
+            return;
+        };
+
+        let removed = state.pending.remove(position);
+        if outcome == WithdrawalOutcome::Stranded {
+            state.stranded.push(removed);
+        }
+    }
+
+    /// Withdraws the cycles from the account of the funding canister, or retries the pending
+    /// withdrawal to the canister, and returns the withdrawal or the error of the cycles ledger.
+    async fn withdraw(
+        &self,
+        ledger: &dyn WithdrawableLedgerCanister,
+        from_subaccount: Option<account::Subaccount>,
+        amount: u128,
+        to: Principal,
+    ) -> Result<Result<Withdrawal, WithdrawError>, ObtainCyclesError> {
+        let from = Account {
+            owner: canister_self(),
+            subaccount: from_subaccount,
+        };
+        let (pending_withdrawal, reused) = self.get_or_insert(from, to, amount);
+
+        let result = match ledger
+            .withdraw(WithdrawArgs {
+                amount: pending_withdrawal.amount.into(),
+                from_subaccount,
+                to,
+                created_at_time: Some(pending_withdrawal.created_at_time),
+            })
+            .await
+        {
+            Err(err) => Err(ObtainCyclesError {
+                details: format!("error: {}", err),
+                can_retry: true,
+            }),
+            Ok(Ok(block_index)) => Ok(Ok(block_index)),
+            // the withdrawal was already executed by a previous attempt
+            Ok(Err(WithdrawError::Duplicate { duplicate_of })) => Ok(Ok(duplicate_of)),
+            Ok(Err(err)) => Ok(Err(err)),
+        };
+
+        self.settle(
+            &pending_withdrawal,
+            match &result {
+                Err(_) => WithdrawalOutcome::Unknown,
+                Ok(Ok(_)) => WithdrawalOutcome::Settled,
+                // a reused withdrawal may have been executed by a previous attempt whose reply was
+                // lost, it is only too old to be deduplicated now
+                Ok(Err(WithdrawError::TooOld)) if reused => WithdrawalOutcome::Stranded,
+                Ok(Err(err)) if withdraw_error(err.clone()).can_retry => WithdrawalOutcome::Unknown,
+                Ok(Err(_)) => WithdrawalOutcome::Settled,
+            },
+        );
+
+        result.map(|result| {
+            result.map(|block_index| Withdrawal {
+                amount: pending_withdrawal.amount,
+                block_index,
+                reused_pending: reused,
+            })
+        })
+    }
+
+    /// Withdraws the cycles from the account that approved the funding canister as a spender, or
+    /// retries the pending withdrawal to the canister, and returns the withdrawal.
+    async fn withdraw_from(
+        &self,
+        ledger: &dyn WithdrawableLedgerCanister,
+        from: Account,
+        spender_subaccount: Option<account::Subaccount>,
+        amount: u128,
+        to: Principal,
+    ) -> Result<Withdrawal, ObtainCyclesError> {
+        let (pending_withdrawal, reused) = self.get_or_insert(from, to, amount);
+
+        let result = match ledger
+            .withdraw_from(WithdrawFromArgs {
+                spender_subaccount,
+                from,
+                to,
+                created_at_time: Some(pending_withdrawal.created_at_time),
+                amount: pending_withdrawal.amount.into(),
+            })
+            .await
+        {
+            Err(err) => Err(ObtainCyclesError {
+                details: format!("error: {}", err),
+                can_retry: true,
+            }),
+            Ok(Ok(block_index)) => Ok(Ok(block_index)),
+            // the withdrawal was already executed by a previous attempt
+            Ok(Err(WithdrawFromError::Duplicate { duplicate_of })) => Ok(Ok(duplicate_of)),
+            Ok(Err(err)) => Ok(Err(err)),
+        };
+
+        self.settle(
+            &pending_withdrawal,
+            match &result {
+                Err(_) => WithdrawalOutcome::Unknown,
+                Ok(Ok(_)) => WithdrawalOutcome::Settled,
+                // a reused withdrawal may have been executed by a previous attempt whose reply was
+                // lost, it is only too old to be deduplicated now
+                Ok(Err(WithdrawFromError::TooOld)) if reused => WithdrawalOutcome::Stranded,
+                Ok(Err(err)) if withdraw_from_error(err.clone()).can_retry => {
+                    WithdrawalOutcome::Unknown
+                }
+                Ok(Err(_)) => WithdrawalOutcome::Settled,
+            },
+        );
+
+        result?
+            .map(|block_index| Withdrawal {
+                amount: pending_withdrawal.amount,
+                block_index,
+                reused_pending: reused,
+            })
+            .map_err(withdraw_from_error)
+    }
+}
+
+/// Returns the receipt of the cycles withdrawn.
+fn withdraw_receipt(withdrawal: Withdrawal) -> Result<ObtainCyclesReceipt, ObtainCyclesError> {
+    Ok(ObtainCyclesReceipt {
+        cycles: withdrawal.amount,
+        block_index: Some(block_index_to_u64(withdrawal.block_index)?),
+        mint: None,
+    })
+}
+
+#[async_trait]
+impl ObtainCycles for WithdrawFromCyclesLedger {
+    async fn obtain_cycles(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+    ) -> Result<u128, ObtainCyclesError> {
+        Ok(self.withdraw(amount, target_canister_id).await?.amount)
+    }
+
+    async fn obtain_cycles_with_receipt(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+        _pending_mints: &PendingMints,
+        pending_withdrawals: &PendingWithdrawals,
+    ) -> Result<ObtainCyclesReceipt, ObtainCyclesError> {
+        let withdrawal = pending_withdrawals
+            .withdraw(
+                self.ledger.as_ref(),
+                self.from_subaccount,
+                amount,
+                target_canister_id,
+            )
+            .await?
+            .map_err(withdraw_error)?;
+
+        withdraw_receipt(withdrawal)
+    }
+
+    async fn available_cycles(&self) -> Result<Option<u128>, ObtainCyclesError> {
+        let balance = cycles_ledger_balance(
+            self.ledger.as_ref(),
+            Account {
+                owner: canister_self(),
+                subaccount: self.from_subaccount,
+            },
+        )
+        .await?;
+
+        Ok(Some(balance.saturating_sub(CYCLES_LEDGER_WITHDRAW_FEE)))
+    }
+}
+
+impl WithdrawFromCyclesLedger {
+    pub fn new(
+        ledger: Arc<dyn WithdrawableLedgerCanister>,
+        from_subaccount: Option<account::Subaccount>,
+    ) -> Self {
+        Self {
+            ledger,
+            from_subaccount,
+            pending_withdrawals: PendingWithdrawals::default(),
+        }
+    }
+
+    /// Sets the pending withdrawals of the direct calls to `obtain_cycles` and `withdraw`, e.g. to
+    /// restore them after an upgrade. The fund manager passes its own pending withdrawals instead.
+    pub fn with_pending_withdrawals(mut self, pending_withdrawals: PendingWithdrawals) -> Self {
+        self.pending_withdrawals = pending_withdrawals;
+        self
+    }
+
+    /// Returns the pending withdrawals of the direct calls to `obtain_cycles` and `withdraw`.
+    pub fn pending_withdrawals(&self) -> PendingWithdrawals {
+        self.pending_withdrawals.clone()
+    }
+
+    /// Withdraws the cycles to the target canister.
+    ///
+    /// If a previous withdrawal to the canister has an unknown outcome, it is retried instead with
+    /// its own amount, so that the cycles are not withdrawn twice, and the withdrawal is marked as
+    /// `reused_pending`.
+    ///
+    /// # Errors
+    /// Returns an error if the withdrawal fails.
+    pub async fn withdraw(
+        &self,
+        amount: u128,
+        to: Principal,
+    ) -> Result<Withdrawal, ObtainCyclesError> {
+        self.pending_withdrawals
+            .withdraw(self.ledger.as_ref(), self.from_subaccount, amount, to)
+            .await?
+            .map_err(withdraw_error)
+    }
+}
+
+fn withdraw_error(err: WithdrawError) -> ObtainCyclesError {
+    ObtainCyclesError {
+        details: match &err {
+            WithdrawError::BadFee { expected_fee } => {
+                format!("Bad fee, expected: {expected_fee}")
+            }
+            WithdrawError::InsufficientFunds { balance } => {
+                format!("Insufficient balance, balance: {balance}")
+            }
+            WithdrawError::TooOld => "Tx too old".to_string(),
+            WithdrawError::CreatedInFuture { .. } => "Tx created in future".to_string(),
+            WithdrawError::Duplicate { duplicate_of } => {
+                format!("Tx duplicate, duplicate_of: {duplicate_of}")
+            }
+            WithdrawError::FailedToWithdraw {
+                rejection_code,
+                rejection_reason,
+                ..
+            } => {
+                format!("Failed to withdraw. Code:{rejection_code:?}, reason:{rejection_reason}")
+            }
+            WithdrawError::TemporarilyUnavailable => "Ledger temporarily unavailable".to_string(),
+            WithdrawError::GenericError {
+                error_code,
+                message,
+            } => {
+                format!("Error occurred. Code: {error_code}, message: {message}")
+            }
+            WithdrawError::InvalidReceiver { receiver } => {
+                format!("Invalid receiver: {receiver}")
+            }
+        },
+        can_retry: matches!(
+            &err,
+            WithdrawError::CreatedInFuture { .. } | WithdrawError::TemporarilyUnavailable
+        ),
+    }
+}
+
+/// Withdraws cycles from an account on the cycles ledger that approved the funding canister
+/// as a spender (ICRC-2), e.g. a treasury account holding the cycles.
+///
+/// Like the ones of `WithdrawFromCyclesLedger`, the withdrawals whose outcome is unknown are retried
+/// with the same amount and `created_at_time`.
+pub struct WithdrawFromCyclesLedgerAllowance {
+    pub ledger: Arc<dyn WithdrawableLedgerCanister>,
+    /// The account that granted the allowance to the funding canister.
+    pub from: Account,
+    /// The subaccount of the funding canister the allowance was granted to.
+    pub spender_subaccount: Option<account::Subaccount>,
+    /// The withdrawals of the direct calls to `obtain_cycles`, the fund manager passes its own.
+    pending_withdrawals: PendingWithdrawals,
+}
+
+#[async_trait]
+impl ObtainCycles for WithdrawFromCyclesLedgerAllowance {
+    async fn obtain_cycles(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+    ) -> Result<u128, ObtainCyclesError> {
+        Ok(self
+            .obtain_cycles_with_receipt(
+                amount,
+                target_canister_id,
+                &PendingMints::default(),
+                &self.pending_withdrawals,
+            )
+            .await?
+            .cycles)
+    }
+
+    async fn obtain_cycles_with_receipt(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+        _pending_mints: &PendingMints,
+        pending_withdrawals: &PendingWithdrawals,
+    ) -> Result<ObtainCyclesReceipt, ObtainCyclesError> {
+        // a pending withdrawal was already checked against the allowance it may have used
+        if !pending_withdrawals.contains(&self.from, &target_canister_id) {
+            let allowance = self.get_allowance().await?;
+            check_allowance(
+                &allowance,
+                amount.saturating_add(CYCLES_LEDGER_WITHDRAW_FEE),
+                time(),
+            )?;
+        }
+
+        let withdrawal = pending_withdrawals
+            .withdraw_from(
+                self.ledger.as_ref(),
+                self.from,
+                self.spender_subaccount,
+                amount,
+                target_canister_id,
+            )
+            .await?;
+
+        withdraw_receipt(withdrawal)
+    }
+
+    async fn available_cycles(&self) -> Result<Option<u128>, ObtainCyclesError> {
+        let allowance = self.get_allowance().await?;
+        if allowance
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= time())
+        {
+            return Ok(Some(0));
+        }
+
+        let allowance = cycles_nat_to_u128(allowance.allowance).unwrap_or(u128::MAX);
+        let balance = cycles_ledger_balance(self.ledger.as_ref(), self.from).await?;
+
+        Ok(Some(
+            cmp::min(allowance, balance).saturating_sub(CYCLES_LEDGER_WITHDRAW_FEE),
+        ))
+    }
+}
+
+impl WithdrawFromCyclesLedgerAllowance {
+    /// Creates a new strategy withdrawing from the account that approved the funding canister as a
+    /// spender.
+    pub fn new(ledger: Arc<dyn WithdrawableLedgerCanister>, from: Account) -> Self {
+        Self {
+            ledger,
+            from,
+            spender_subaccount: None,
+            pending_withdrawals: PendingWithdrawals::default(),
+        }
+    }
+
+    /// Sets the subaccount of the funding canister the allowance was granted to.
+    pub fn with_spender_subaccount(
+        mut self,
+        spender_subaccount: Option<account::Subaccount>,
+    ) -> Self {
+        self.spender_subaccount = spender_subaccount;
+        self
+    }
+
+    async fn get_allowance(&self) -> Result<Allowance, ObtainCyclesError> {
+        self.ledger
+            .allowance(AllowanceArgs {
+                account: self.from,
+                spender: Account {
+                    owner: canister_self(),
+                    subaccount: self.spender_subaccount,
+                },
+            })
+            .await
+            .map_err(|err| ObtainCyclesError {
+                details: format!(
+                    "Error getting allowance from cycles ledger: message={}",
+                    err
+                ),
+                can_retry: true,
+            })
+    }
+
+    /// Sets the pending withdrawals of the direct calls to `obtain_cycles` and `withdraw_from`, e.g.
+    /// to restore them after an upgrade. The fund manager passes its own pending withdrawals instead.
+    pub fn with_pending_withdrawals(mut self, pending_withdrawals: PendingWithdrawals) -> Self {
+        self.pending_withdrawals = pending_withdrawals;
+        self
+    }
+
+    /// Returns the pending withdrawals of the direct calls to `obtain_cycles` and `withdraw_from`.
+    pub fn pending_withdrawals(&self) -> PendingWithdrawals {
+        self.pending_withdrawals.clone()
+    }
+
+    /// Withdraws the cycles to the target canister.
+    ///
+    /// If a previous withdrawal to the canister has an unknown outcome, it is retried instead with
+    /// its own amount, so that the cycles are not withdrawn twice, and the withdrawal is marked as
+    /// `reused_pending`.
+    ///
+    /// # Errors
+    /// Returns an error if the withdrawal fails.
+    pub async fn withdraw_from(
+        &self,
+        amount: u128,
+        to: Principal,
+    ) -> Result<Withdrawal, ObtainCyclesError> {
+        self.pending_withdrawals
+            .withdraw_from(
+                self.ledger.as_ref(),
+                self.from,
+                self.spender_subaccount,
+                amount,
+                to,
+            )
+            .await
+    }
+}
+
+fn withdraw_from_error(err: WithdrawFromError) -> ObtainCyclesError {
+    ObtainCyclesError {
+        details: match &err {
+            WithdrawFromError::InsufficientFunds { balance } => {
+                format!("Insufficient balance, balance: {balance}")
+            }
+            WithdrawFromError::InsufficientAllowance { allowance } => {
+                format!("Insufficient allowance, allowance: {allowance}")
+            }
+            WithdrawFromError::TooOld => "Tx too old".to_string(),
+            WithdrawFromError::CreatedInFuture { .. } => "Tx created in future".to_string(),
+            WithdrawFromError::Duplicate { duplicate_of } => {
+                format!("Tx duplicate, duplicate_of: {duplicate_of}")
+            }
+            WithdrawFromError::FailedToWithdrawFrom {
+                rejection_code,
+                rejection_reason,
+                ..
+            } => {
+                format!(
+                    "Failed to withdraw from. Code:{rejection_code:?}, reason:{rejection_reason}"
+                )
+            }
+            WithdrawFromError::TemporarilyUnavailable => {
+                "Ledger temporarily unavailable".to_string()
+            }
+            WithdrawFromError::GenericError {
+                error_code,
+                message,
+            } => {
+                format!("Error occurred. Code: {error_code}, message: {message}")
+            }
+            WithdrawFromError::InvalidReceiver { receiver } => {
+                format!("Invalid receiver: {receiver}")
+            }
+        },
+        can_retry: matches!(
+            &err,
+            WithdrawFromError::CreatedInFuture { .. } | WithdrawFromError::TemporarilyUnavailable
+        ),
+    }
+}
+
+/// Checks that the allowance covers the required amount, including the fee, and has not expired.
+fn check_allowance(
+    allowance: &Allowance,
+    required: u128,
+    now: u64,
+) -> Result<(), ObtainCyclesError> {
+    if allowance
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+    {
+        return Err(ObtainCyclesError {
+            details: format!(
+                "Allowance expired at {}",
+                allowance.expires_at.unwrap_or_default()
+            ),
+            can_retry: false,
+        });
+    }
+
+    // An allowance that does not fit into u128 is large enough for any transfer.
+    let available = cycles_nat_to_u128(allowance.allowance.clone()).unwrap_or(u128::MAX);
+    if available < required {
+        return Err(ObtainCyclesError {
+            details: format!(
+                "Insufficient allowance, allowance: {available}, required: {required}"
+            ),
+            can_retry: false,
+        });
+    }
+
+    Ok(())
+}
+
+/// Requests cycles from a parent funding canister that serves the `canfund_request_cycles` endpoint,
+/// so that funding canisters can be chained.
+pub struct RequestCyclesFromFunder {
+    /// The parent funding canister the cycles are requested from.
+    funder: Arc<dyn FundingCanister>,
+}
+
+impl RequestCyclesFromFunder {
+    /// Creates a new strategy requesting the cycles from the parent funding canister.
+    pub fn new(funder: Arc<dyn FundingCanister>) -> Self {
+        Self { funder }
+    }
+}
+
+#[async_trait]
+impl ObtainCycles for RequestCyclesFromFunder {
+    async fn obtain_cycles(
+        &self,
+        amount: u128,
+        target_canister_id: Principal,
+    ) -> Result<u128, ObtainCyclesError> {
+        let result = self
+            .funder
+            .request_cycles(RequestCyclesArgs {
+                target: target_canister_id,
+                amount: amount.into(),
+            })
+            .await
+            .map_err(|err| ObtainCyclesError {
+                details: format!("error: {}", err),
+                // the request is not idempotent, so it is only retried if it was not executed
+                can_retry: err.is_clean_reject(),
+            })?;
+
+        match result {
+            Ok(success) => Ok(cycles_nat_to_u128(success.deposited).unwrap_or(amount)),
+            Err(err) => Err(ObtainCyclesError {
+                details: match &err {
+                    RequestCyclesError::Unauthorized => {
+                        "Not authorized to request cycles from the funder".to_string()
+                    }
+                    RequestCyclesError::BudgetExceeded { remaining } => {
+                        format!("Funder budget exceeded, remaining: {remaining}")
+                    }
+                    RequestCyclesError::InsufficientCycles { available } => {
+                        format!("Funder has insufficient cycles, available: {available}")
+                    }
+                    RequestCyclesError::DepositFailed { reason } => {
+                        format!("Funder failed to deposit cycles, reason: {reason}")
+                    }
+                },
+                can_retry: matches!(err, RequestCyclesError::DepositFailed { .. }),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::funder::test::TestFundingCanister;
+    use crate::api::ledger::test::TestCyclesLedgerCanister;
+    use crate::api::{cmc::test::TestCmcCanister, ledger::test::TestLedgerCanister};
+    use crate::types::WithdrawFromArgs;
+    use candid::Nat;
+    use ic_cdk::call::{CallRejected, Error, RejectCode};
+
+    #[tokio::test]
+    async fn test_mint_refused_by_price_guard() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister::default());
+
+        // the test CMC returns a rate of 5 XDR per ICP
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]))
+            .with_price_guard(IcpPriceGuard::new().with_min_xdr_permyriad_per_icp(6 * 10_000));
+
+        let err = obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .unwrap_err();
+
+        assert!(!err.can_retry);
+        assert!(err.details.starts_with("Refused to mint cycles"));
+        assert!(ledger.transfer_called_with.read().await.is_empty());
+        assert!(obtain.pending_mints().pending().is_empty());
+
+        // 1T cycles cost 0.2 ICP at 5 XDR per ICP
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]))
+            .with_price_guard(IcpPriceGuard::new().with_max_icp_e8s_per_day(30_000_000));
+
+        assert!(obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .is_ok());
+        assert!(obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_calculate_icp_amount_rounds_up() {
+        // 5 XDR per ICP mint 5 cycles per e8s
+        assert_eq!(
+            calculate_icp_amount(1_000_000_000_000, 50_000).unwrap(),
+            20_000_000
+        );
+        assert_eq!(
+            calculate_icp_amount(1_000_000_000_001, 50_000).unwrap(),
+            20_000_001
+        );
+        assert_eq!(calculate_icp_amount(0, 50_000).unwrap(), 0);
+
+        assert!(calculate_icp_amount(1, 0).is_err());
+        assert!(calculate_icp_amount(u128::MAX, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mint_capacity_from_icp_balance() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister {
+            account_balance_returns_with: Some(Ok(Tokens::from_e8s(100_010_000))),
+            ..Default::default()
+        });
+
+        let obtain = MintCycles::new(cmc, ledger, Subaccount([0u8; 32]));
+
+        // 1 ICP at 5 XDR per ICP after paying the transfer fee.
+        assert_eq!(
+            obtain.available_cycles().await.unwrap(),
+            Some(5_000_000_000_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_capacity_is_capped_by_allowance() {
+        let ledger = Arc::new(TestCyclesLedgerCanister {
+            icrc1_balance_of_returns_with: Some(Ok(Nat::from(1_000_000_000_000u128))),
+            allowance_returns_with: Some(Ok(Allowance {
+                allowance: Nat::from(500_000_000_000u128),
+                expires_at: None,
+            })),
+            ..Default::default()
+        });
+
+        let obtain = WithdrawFromCyclesLedgerAllowance::new(
+            ledger.clone(),
+            Account::from(Principal::anonymous()),
+        );
+
+        assert_eq!(
+            obtain.available_cycles().await.unwrap(),
+            Some(500_000_000_000 - CYCLES_LEDGER_WITHDRAW_FEE)
+        );
+        assert_eq!(
+            WithdrawFromCyclesLedger::new(ledger, None)
+                .available_cycles()
+                .await
+                .unwrap(),
+            Some(1_000_000_000_000 - CYCLES_LEDGER_WITHDRAW_FEE)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mint_reports_icp_spent() {
+        let cmc = Arc::new(TestCmcCanister {
+            notify_top_up_returns_with: Some(Ok(NotifyTopUpResult::Ok(1_000_000_000_000))),
+            ..Default::default()
+        });
+        let ledger = Arc::new(TestLedgerCanister {
+            icrc1_fee_returns_with: Some(Ok(20_000u64.into())),
+            ..Default::default()
+        });
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]))
+            .with_fee(IcpFee::Queried);
+
+        let receipt = obtain
+            .obtain_cycles_with_receipt(
+                1_000_000_000_000,
+                Principal::anonymous(),
+                &PendingMints::default(),
+                &PendingWithdrawals::default(),
+            )
+            .await
+            .expect("obtain_cycles failed");
+        let mint = receipt.mint.unwrap();
+
+        assert_eq!(mint.icp_spent_e8s, 20_000_000);
+        assert_eq!(mint.fee_e8s, 20_000);
+        assert_eq!(mint.cycles, 1_000_000_000_000);
+        assert_eq!(mint.effective_cycles_per_icp(), 4_995_004_995_004);
+        assert_eq!(
+            ledger.transfer_called_with.read().await[0].fee,
+            Tokens::from_e8s(20_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_obtain_by_minting() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister::default());
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]));
+
+        obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect("obtain_cycles failed");
+
+        // calls to get the ICP price
+        assert!(*cmc.get_icp_xdr_called.read().await);
+
+        // calls to transfer ICP to the CMC account
+        assert!(matches!(
+            ledger.transfer_called_with.read().await.first(),
+            Some(TransferArgs { amount, .. }) if amount == &Tokens::from_e8s(100_000_000 / 5)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_by_minting_with_icrc1_transfer() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let target_canister_id = Principal::from_slice(&[1]);
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([1u8; 32]))
+            .with_transfer_method(IcpTransferMethod::Icrc1);
+
+        obtain
+            .obtain_cycles(1_000_000_000_000, target_canister_id)
+            .await
+            .expect("obtain_cycles failed");
+
+        // transfers ICP to the top-up account of the CMC derived from the target canister
+        assert!(ledger.transfer_called_with.read().await.is_empty());
+        assert!(matches!(
+            ledger.icrc1_transfer_called_with.read().await.first(),
+            Some(TransferArg { amount, from_subaccount, to, .. })
+                if amount == &Nat::from(100_000_000u64 / 5)
+                    && from_subaccount == &Some([1u8; 32])
+                    && to.subaccount == Some(Subaccount::from(target_canister_id).0)
+        ));
+
+        assert_eq!(cmc.notify_top_up_called_with.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_by_minting_treats_duplicate_as_success() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister {
+            returns_with: Some(Ok(Err(ic_ledger_types::TransferError::TxDuplicate {
+                duplicate_of: 7,
+            }))),
+            ..Default::default()
+        });
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]));
+
+        obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect("obtain_cycles failed");
+
+        // the CMC is notified about the original transfer
+        assert_eq!(*cmc.notify_top_up_called_with.read().await, vec![7]);
+        assert!(obtain.pending_mints().pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_obtain_by_minting_retries_transfer_with_same_created_at_time() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister {
+            returns_with: Some(Err(Error::InsufficientLiquidCycleBalance(
+                ic_cdk::call::InsufficientLiquidCycleBalance {
+                    available: 0,
+                    required: 100,
+                },
+            ))),
+            ..Default::default()
+        });
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]));
+
+        for _ in 0..2 {
+            let error = obtain
+                .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+                .await
+                .expect_err("obtain_cycles should fail");
+            assert!(error.can_retry);
+        }
+
+        let transfers = ledger.transfer_called_with.read().await;
+        assert_eq!(transfers.len(), 2);
+        assert!(transfers[0].created_at_time.is_some());
+        assert_eq!(transfers[0].created_at_time, transfers[1].created_at_time);
+        assert_eq!(transfers[0].amount, transfers[1].amount);
+        assert_eq!(obtain.pending_mints().pending().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_by_minting_resumes_pending_notification() {
+        let cmc = Arc::new(TestCmcCanister {
+            notify_top_up_returns_with: Some(Ok(NotifyTopUpResult::Err(NotifyError::Processing))),
+            ..Default::default()
+        });
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let pending_mints = PendingMints::default();
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]));
+        obtain
+            .obtain_cycles_with_receipt(
+                1_000_000_000_000,
+                Principal::anonymous(),
+                &pending_mints,
+                &PendingWithdrawals::default(),
+            )
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        // the mint is kept by the caller, not by the source
+        assert!(obtain.pending_mints().pending().is_empty());
+        let pending = pending_mints.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].block_index, Some(0));
+
+        // after an upgrade the pending mint is restored and only the notification is retried
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]));
+        let pending_mints = PendingMints::new(pending, Vec::new());
+
+        obtain
+            .obtain_cycles_with_receipt(
+                1_000_000_000_000,
+                Principal::anonymous(),
+                &pending_mints,
+                &PendingWithdrawals::default(),
+            )
+            .await
+            .expect("obtain_cycles failed");
+
+        assert!(ledger.transfer_called_with.read().await.is_empty());
+        assert_eq!(cmc.notify_top_up_called_with.read().await.len(), 1);
+        assert!(pending_mints.pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resume_pending_mints() {
+        let target_canister_id = Principal::from_slice(&[1]);
+        let pending_mint = PendingMint {
+            from: Account {
+                owner: canister_self(),
+                subaccount: Some([0u8; 32]),
+            },
+            target_canister_id,
+            kind: MintKind::TopUp,
+            icp_amount_e8s: 100,
+            created_at_time: 0,
+            block_index: Some(3),
+            fee_e8s: None,
+        };
+        let pending_mints = PendingMints::new(
+            vec![
+                pending_mint.clone(),
+                // transfers without a known block index are completed by `obtain_cycles`
+                PendingMint {
+                    target_canister_id: Principal::from_slice(&[2]),
+                    block_index: None,
+                    ..pending_mint.clone()
+                },
+                // transfers of other sources are completed by them
+                PendingMint {
+                    from: Account::from(Principal::from_slice(&[3])),
+                    ..pending_mint.clone()
+                },
+                // mints into the cycles ledger are completed by `MintCyclesToCyclesLedger`
+                PendingMint {
+                    target_canister_id: canister_self(),
+                    kind: MintKind::CyclesLedger { subaccount: None },
+                    ..pending_mint.clone()
+                },
+            ],
+            Vec::new(),
+        );
+
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]));
+
+        let obtained = obtain.resume_pending(&pending_mints).await;
+
+        assert_eq!(obtained.len(), 1);
+        assert_eq!(obtained[0].0, target_canister_id);
+        assert_eq!(obtained[0].1.block_index, Some(3));
+        assert_eq!(*cmc.notify_top_up_called_with.read().await, vec![3]);
+        assert!(ledger.transfer_called_with.read().await.is_empty());
+        assert_eq!(pending_mints.pending().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_refused_mints_are_stranded() {
+        let cmc = Arc::new(TestCmcCanister {
+            notify_top_up_returns_with: Some(Ok(NotifyTopUpResult::Err(
+                NotifyError::TransactionTooOld(0),
+            ))),
+            ..Default::default()
+        });
+        let ledger = Arc::new(TestLedgerCanister {
+            returns_with: Some(Ok(Ok(5))),
+            ..Default::default()
+        });
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]));
+        obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        let pending_mints = obtain.pending_mints();
+        assert!(pending_mints.pending().is_empty());
+        let stranded_mints = pending_mints.stranded();
+        assert_eq!(stranded_mints.len(), 1);
+        assert_eq!(stranded_mints[0].block_index, Some(5));
+
+        assert_eq!(
+            pending_mints.remove_stranded(&stranded_mints[0]),
+            Some(stranded_mints[0].clone())
+        );
+        assert!(pending_mints.stranded().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reused_mint_too_old_to_deduplicate_is_stranded() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister {
+            returns_with: Some(Ok(Err(ic_ledger_types::TransferError::TxTooOld {
+                allowed_window_nanos: 0,
+            }))),
+            ..Default::default()
+        });
+        // the transfer of a previous round whose reply was lost
+        let pending_mint = PendingMint {
+            from: Account {
+                owner: canister_self(),
+                subaccount: Some([0u8; 32]),
+            },
+            target_canister_id: Principal::anonymous(),
+            kind: MintKind::TopUp,
+            icp_amount_e8s: 100_000,
+            created_at_time: 0,
+            block_index: None,
+            fee_e8s: None,
+        };
+        let pending_mints = PendingMints::new(vec![pending_mint.clone()], Vec::new());
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]))
+            .with_price_guard(IcpPriceGuard::new().with_max_icp_e8s_per_day(1_000_000));
+        let price_guard = obtain.price_guard.as_ref().unwrap();
+        price_guard
+            .reserve_icp(pending_mint.total_e8s(), time())
+            .unwrap();
+
+        let error = obtain
+            .obtain_cycles_with_receipt(
+                1_000_000_000_000,
+                Principal::anonymous(),
+                &pending_mints,
+                &PendingWithdrawals::default(),
+            )
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        assert!(!error.can_retry);
+        assert!(pending_mints.pending().is_empty());
+        assert_eq!(pending_mints.stranded(), vec![pending_mint.clone()]);
+        // the ICP may have been spent, so it is still charged to the daily limit
+        assert_eq!(
+            price_guard.remaining_icp_e8s_today(time()),
+            Some(1_000_000 - pending_mint.total_e8s())
+        );
+        assert_eq!(ledger.transfer_called_with.read().await.len(), 1);
+        assert!(cmc.notify_top_up_called_with.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reused_mint_rejected_by_the_ledger_is_released() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister {
+            returns_with: Some(Ok(Err(ic_ledger_types::TransferError::InsufficientFunds {
+                balance: Tokens::from_e8s(0),
+            }))),
+            ..Default::default()
+        });
+        let pending_mint = PendingMint {
+            from: Account {
+                owner: canister_self(),
+                subaccount: Some([0u8; 32]),
+            },
+            target_canister_id: Principal::anonymous(),
+            kind: MintKind::TopUp,
+            icp_amount_e8s: 100_000,
+            created_at_time: 0,
+            block_index: None,
+            fee_e8s: None,
+        };
+        let pending_mints = PendingMints::new(vec![pending_mint.clone()], Vec::new());
+
+        let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]))
+            .with_price_guard(IcpPriceGuard::new().with_max_icp_e8s_per_day(1_000_000));
+        let price_guard = obtain.price_guard.as_ref().unwrap();
+        price_guard
+            .reserve_icp(pending_mint.total_e8s(), time())
+            .unwrap();
+
+        obtain
+            .obtain_cycles_with_receipt(
+                1_000_000_000_000,
+                Principal::anonymous(),
+                &pending_mints,
+                &PendingWithdrawals::default(),
+            )
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        assert!(pending_mints.pending().is_empty());
+        assert!(pending_mints.stranded().is_empty());
+        assert_eq!(price_guard.remaining_icp_e8s_today(time()), Some(1_000_000));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_by_minting_from_allowance() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let treasury = Account {
+            owner: Principal::from_slice(&[1]),
+            subaccount: None,
+        };
+
+        let obtain = MintCyclesFromAllowance::new(cmc.clone(), ledger.clone(), treasury);
+
+        obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect("obtain_cycles failed");
+
+        // pulls the ICP from the treasury into the top-up account of the CMC
+        assert!(ledger.transfer_called_with.read().await.is_empty());
+        assert!(matches!(
+            ledger.transfer_from_called_with.read().await.first(),
+            Some(TransferFromArgs { amount, from, to, memo, .. })
+                if amount == &Nat::from(100_000_000u64 / 5)
+                    && from == &treasury
+                    && to == &cmc.get_top_up_account(Principal::anonymous())
+                    && memo.as_ref().map(|memo| memo.0.as_slice()) == Some(b"TPUP\0\0\0\0".as_slice())
+        ));
+
+        assert_eq!(cmc.notify_top_up_called_with.read().await.len(), 1);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::api::ledger::test::TestCyclesLedgerCanister;
-    use crate::api::{cmc::test::TestCmcCanister, ledger::test::TestLedgerCanister};
-    use candid::Nat;
-    use ic_cdk::call::Error;
+    #[tokio::test]
+    async fn test_obtain_by_minting_from_allowance_fails() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister {
+            transfer_from_returns_with: Some(Ok(Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(0u64),
+            }))),
+            ..Default::default()
+        });
+
+        let obtain = MintCyclesFromAllowance::new(
+            cmc.clone(),
+            ledger,
+            Account {
+                owner: Principal::from_slice(&[1]),
+                subaccount: None,
+            },
+        );
+
+        let error = obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        assert!(!error.can_retry);
+        assert!(cmc.notify_top_up_called_with.read().await.is_empty());
+        assert!(obtain.pending_mints().pending().is_empty());
+    }
 
     #[tokio::test]
-    async fn test_obtain_by_minting() {
+    async fn test_minting_from_allowance_checks_the_allowance() {
         let cmc = Arc::new(TestCmcCanister::default());
-        let ledger = Arc::new(TestLedgerCanister::default());
+        let ledger = Arc::new(TestLedgerCanister {
+            // covers the ICP of the mint but not the fee
+            allowance_returns_with: Some(Ok(Allowance {
+                allowance: Nat::from(100_000_000u64 / 5),
+                expires_at: None,
+            })),
+            ..Default::default()
+        });
+
+        let obtain = MintCyclesFromAllowance::new(
+            cmc.clone(),
+            ledger.clone(),
+            Account {
+                owner: Principal::from_slice(&[1]),
+                subaccount: None,
+            },
+        );
+
+        let error = obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect_err("obtain_cycles should fail");
 
-        let obtain = MintCycles {
-            cmc: cmc.clone(),
-            ledger: ledger.clone(),
-            from_subaccount: Subaccount([0u8; 32]),
+        assert!(!error.can_retry);
+        assert!(error.details.contains("Insufficient allowance"));
+        assert!(ledger.transfer_from_called_with.read().await.is_empty());
+        assert!(obtain.pending_mints().pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_minting_from_allowance_retries_the_same_transfer() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let failing_ledger = Arc::new(TestLedgerCanister {
+            transfer_from_returns_with: Some(Err(Error::CallRejected(
+                CallRejected::with_rejection(RejectCode::SysUnknown as u32, String::new()),
+            ))),
+            ..Default::default()
+        });
+        let treasury = Account {
+            owner: Principal::from_slice(&[1]),
+            subaccount: None,
         };
 
+        let obtain = MintCyclesFromAllowance::new(cmc.clone(), failing_ledger.clone(), treasury);
+        let error = obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        // the transfer may have happened, so it is kept pending
+        assert!(error.can_retry);
+        let pending_mints = obtain.pending_mints();
+        assert_eq!(pending_mints.pending().len(), 1);
+        let pending_mint = pending_mints.pending()[0].clone();
+        assert_eq!(pending_mint.from, treasury);
+        assert_eq!(pending_mint.block_index, None);
+
+        // the ledger deduplicates the retried transfer with the same created_at_time
+        let ledger = Arc::new(TestLedgerCanister {
+            transfer_from_returns_with: Some(Ok(Err(TransferFromError::Duplicate {
+                duplicate_of: Nat::from(7u64),
+            }))),
+            ..Default::default()
+        });
+        let obtain = MintCyclesFromAllowance::new(cmc.clone(), ledger.clone(), treasury)
+            .with_pending_mints(pending_mints.clone());
         obtain
             .obtain_cycles(1_000_000_000_000, Principal::anonymous())
             .await
             .expect("obtain_cycles failed");
 
-        // calls to get the ICP price
-        assert!(*cmc.get_icp_xdr_called.read().await);
-
-        // calls to transfer ICP to the CMC account
+        // the allowance is not checked again for the pending transfer
+        assert!(ledger.allowance_called_with.read().await.is_empty());
         assert!(matches!(
-            ledger.transfer_called_with.read().await.first(),
-            Some(TransferArgs { amount, .. }) if amount == &Tokens::from_e8s(100_000_000 / 5)
+            ledger.transfer_from_called_with.read().await.first(),
+            Some(TransferFromArgs { created_at_time, amount, .. })
+                if created_at_time == &Some(pending_mint.created_at_time)
+                    && amount == &Nat::from(pending_mint.icp_amount_e8s)
         ));
+        assert_eq!(
+            cmc.notify_top_up_called_with.read().await.last().copied(),
+            Some(7)
+        );
+        assert!(pending_mints.pending().is_empty());
     }
 
     #[tokio::test]
@@ -338,11 +2916,7 @@ mod test {
 
             let ledger = Arc::new(TestLedgerCanister::default());
 
-            let obtain = MintCycles {
-                cmc: cmc.clone(),
-                ledger: ledger.clone(),
-                from_subaccount: Subaccount([0u8; 32]),
-            };
+            let obtain = MintCycles::new(cmc.clone(), ledger.clone(), Subaccount([0u8; 32]));
 
             obtain
                 .obtain_cycles(1_000_000_000_000, Principal::anonymous())
@@ -365,10 +2939,7 @@ mod test {
     async fn test_obtain_from_ledger() {
         let ledger = Arc::new(TestCyclesLedgerCanister::default());
 
-        let obtain = WithdrawFromCyclesLedger {
-            ledger: ledger.clone(),
-            from_subaccount: None,
-        };
+        let obtain = WithdrawFromCyclesLedger::new(ledger.clone(), None);
 
         obtain
             .obtain_cycles(1_000_000_000_000, Principal::anonymous())
@@ -381,4 +2952,573 @@ mod test {
             Some(WithdrawArgs { amount, .. }) if amount == &Nat::from(1_000_000_000_000u64)
         ));
     }
+
+    #[tokio::test]
+    async fn test_obtain_from_ledger_treats_duplicate_as_success() {
+        let ledger = Arc::new(TestCyclesLedgerCanister {
+            returns_with: Some(Ok(Err(WithdrawError::Duplicate {
+                duplicate_of: 9_u64.into(),
+            }))),
+            ..Default::default()
+        });
+
+        let obtain = WithdrawFromCyclesLedger::new(ledger.clone(), None);
+
+        let receipt = obtain
+            .obtain_cycles_with_receipt(
+                1_000_000_000_000,
+                Principal::anonymous(),
+                &PendingMints::default(),
+                &PendingWithdrawals::default(),
+            )
+            .await
+            .expect("obtain_cycles failed");
+
+        assert_eq!(
+            receipt,
+            ObtainCyclesReceipt {
+                cycles: 1_000_000_000_000,
+                block_index: Some(9),
+                mint: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_obtain_from_ledger_retries_withdrawal_with_same_created_at_time() {
+        let ledger = Arc::new(TestCyclesLedgerCanister {
+            returns_with: Some(Err(Error::InsufficientLiquidCycleBalance(
+                ic_cdk::call::InsufficientLiquidCycleBalance {
+                    available: 0,
+                    required: 100,
+                },
+            ))),
+            ..Default::default()
+        });
+
+        let obtain = WithdrawFromCyclesLedger::new(ledger.clone(), None);
+
+        for amount in [1_000_000_000_000, 2_000_000_000_000] {
+            let error = obtain
+                .obtain_cycles(amount, Principal::anonymous())
+                .await
+                .expect_err("obtain_cycles should fail");
+            assert!(error.can_retry);
+        }
+
+        // the retry repeats the withdrawal with the unknown outcome
+        let withdrawals = ledger.transfer_called_with.read().await;
+        assert_eq!(withdrawals.len(), 2);
+        assert!(withdrawals[0].created_at_time.is_some());
+        assert_eq!(withdrawals[0], withdrawals[1]);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_reuses_the_pending_amount() {
+        let ledger = Arc::new(TestCyclesLedgerCanister {
+            returns_with: Some(Ok(Ok(7_u64.into()))),
+            ..Default::default()
+        });
+        let pending_withdrawal = PendingWithdrawal {
+            from: Account::from(canister_self()),
+            to: Principal::anonymous(),
+            amount: 1_000_000_000_000,
+            created_at_time: 0,
+        };
+
+        let obtain = WithdrawFromCyclesLedger::new(ledger.clone(), None).with_pending_withdrawals(
+            PendingWithdrawals::new(vec![pending_withdrawal.clone()], Vec::new()),
+        );
+
+        let withdrawal = obtain
+            .withdraw(2_000_000_000_000, Principal::anonymous())
+            .await
+            .expect("withdraw failed");
+
+        assert_eq!(
+            withdrawal,
+            Withdrawal {
+                amount: 1_000_000_000_000,
+                block_index: 7_u64.into(),
+                reused_pending: true,
+            }
+        );
+        assert_eq!(
+            ledger.transfer_called_with.read().await[0].created_at_time,
+            Some(0)
+        );
+        assert!(obtain.pending_withdrawals().pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reused_withdrawal_too_old_to_deduplicate_is_stranded() {
+        let ledger = Arc::new(TestCyclesLedgerCanister {
+            returns_with: Some(Ok(Err(WithdrawError::TooOld))),
+            ..Default::default()
+        });
+        // the withdrawal of a previous round whose reply was lost
+        let pending_withdrawal = PendingWithdrawal {
+            from: Account::from(canister_self()),
+            to: Principal::anonymous(),
+            amount: 1_000_000_000_000,
+            created_at_time: 0,
+        };
+        let pending_withdrawals =
+            PendingWithdrawals::new(vec![pending_withdrawal.clone()], Vec::new());
+
+        let obtain = WithdrawFromCyclesLedger::new(ledger.clone(), None);
+        let error = obtain
+            .obtain_cycles_with_receipt(
+                1_000_000_000_000,
+                Principal::anonymous(),
+                &PendingMints::default(),
+                &pending_withdrawals,
+            )
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        assert!(!error.can_retry);
+        assert!(pending_withdrawals.pending().is_empty());
+        assert_eq!(
+            pending_withdrawals.stranded(),
+            vec![pending_withdrawal.clone()]
+        );
+
+        // a new withdrawal that is too old failed for sure
+        let error = obtain
+            .obtain_cycles_with_receipt(
+                1_000_000_000_000,
+                Principal::anonymous(),
+                &PendingMints::default(),
+                &pending_withdrawals,
+            )
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        assert!(!error.can_retry);
+        assert!(pending_withdrawals.pending().is_empty());
+        assert_eq!(pending_withdrawals.stranded().len(), 1);
+
+        assert_eq!(
+            pending_withdrawals.remove_stranded(&pending_withdrawal),
+            Some(pending_withdrawal)
+        );
+        assert!(pending_withdrawals.stranded().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_obtain_from_minted_cycles_ledger_balance() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let cycles_ledger = Arc::new(TestCyclesLedgerCanister::default());
+
+        let obtain = MintCyclesToCyclesLedger::new(
+            cmc.clone(),
+            ledger.clone(),
+            cycles_ledger.clone(),
+            Subaccount([0u8; 32]),
+        );
+
+        let obtained = obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect("obtain_cycles failed");
+
+        // the cycles ledger balance suffices, so no ICP is spent
+        assert_eq!(obtained, 1_000_000_000_000);
+        assert_eq!(cycles_ledger.transfer_called_with.read().await.len(), 1);
+        assert!(ledger.transfer_called_with.read().await.is_empty());
+        assert!(cmc.notify_mint_cycles_called_with.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_obtain_mints_batch_into_cycles_ledger() {
+        let cmc = Arc::new(TestCmcCanister {
+            notify_mint_cycles_returns_with: Some(Ok(NotifyMintCyclesResult::Ok(
+                NotifyMintCyclesSuccess {
+                    block_index: Nat::from(1u64),
+                    minted: Nat::from(10_000_000_000_000u64),
+                    balance: Nat::from(10_000_000_000_000u64),
+                },
+            ))),
+            ..Default::default()
+        });
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let cycles_ledger = Arc::new(TestCyclesLedgerCanister {
+            returns_with: Some(Ok(Err(WithdrawError::InsufficientFunds {
+                balance: Nat::from(0u64),
+            }))),
+            ..Default::default()
+        });
+
+        let obtain = MintCyclesToCyclesLedger::new(
+            cmc.clone(),
+            ledger.clone(),
+            cycles_ledger.clone(),
+            Subaccount([0u8; 32]),
+        );
+
+        obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect_err("obtain_cycles should fail as the mock never has funds");
+
+        // mints the whole batch of 10T cycles at 5 XDR per ICP
+        assert!(matches!(
+            ledger.transfer_called_with.read().await.first(),
+            Some(TransferArgs { amount, memo, .. })
+                if amount == &Tokens::from_e8s(200_000_000) && memo == &Memo(MEMO_MINT_CYCLES)
+        ));
+        assert_eq!(cmc.notify_mint_cycles_called_with.read().await.len(), 1);
+        assert!(cmc.notify_top_up_called_with.read().await.is_empty());
+
+        // withdraws again after minting
+        assert_eq!(cycles_ledger.transfer_called_with.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mint_into_cycles_ledger_refused_by_price_guard() {
+        let cmc = Arc::new(TestCmcCanister::default());
+        let ledger = Arc::new(TestLedgerCanister::default());
+        let cycles_ledger = Arc::new(TestCyclesLedgerCanister {
+            returns_with: Some(Ok(Err(WithdrawError::InsufficientFunds {
+                balance: Nat::from(0u64),
+            }))),
+            ..Default::default()
+        });
+
+        // the test CMC returns a rate of 5 XDR per ICP
+        let obtain = MintCyclesToCyclesLedger::new(
+            cmc.clone(),
+            ledger.clone(),
+            cycles_ledger.clone(),
+            Subaccount([0u8; 32]),
+        )
+        .with_price_guard(IcpPriceGuard::new().with_min_xdr_permyriad_per_icp(6 * 10_000));
+
+        let err = obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .unwrap_err();
+
+        assert!(!err.can_retry);
+        assert!(err.details.starts_with("Refused to mint cycles"));
+        assert!(ledger.transfer_called_with.read().await.is_empty());
+        assert!(obtain.pending_mints().pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_mint_into_cycles_ledger_is_resumed() {
+        let failing_cmc = Arc::new(TestCmcCanister {
+            notify_mint_cycles_returns_with: Some(Ok(NotifyMintCyclesResult::Err(
+                NotifyError::Other {
+                    error_code: 0,
+                    error_message: String::new(),
+                },
+            ))),
+            ..Default::default()
+        });
+        let ledger = Arc::new(TestLedgerCanister {
+            returns_with: Some(Ok(Ok(4))),
+            ..Default::default()
+        });
+        let cycles_ledger = Arc::new(TestCyclesLedgerCanister {
+            returns_with: Some(Ok(Err(WithdrawError::InsufficientFunds {
+                balance: Nat::from(0u64),
+            }))),
+            ..Default::default()
+        });
+
+        let obtain = MintCyclesToCyclesLedger::new(
+            failing_cmc.clone(),
+            ledger.clone(),
+            cycles_ledger.clone(),
+            Subaccount([0u8; 32]),
+        )
+        .with_notify_retry_policy(RetryPolicy::immediate(1));
+        obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect_err("obtain_cycles should fail");
+
+        // the ICP was transferred, so the mint is kept with the block index of the transfer
+        let pending_mints = obtain.pending_mints();
+        assert_eq!(ledger.transfer_called_with.read().await.len(), 1);
+        assert!(ledger.transfer_called_with.read().await[0]
+            .created_at_time
+            .is_some());
+        assert!(matches!(
+            pending_mints.pending().as_slice(),
+            [PendingMint {
+                kind: MintKind::CyclesLedger { subaccount: None },
+                block_index: Some(4),
+                ..
+            }]
+        ));
+
+        // the next call only notifies the CMC again instead of transferring the ICP again
+        let cmc = Arc::new(TestCmcCanister::default());
+        let obtain = MintCyclesToCyclesLedger::new(
+            cmc.clone(),
+            ledger.clone(),
+            cycles_ledger,
+            Subaccount([0u8; 32]),
+        )
+        .with_pending_mints(pending_mints.clone());
+        let _ = obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await;
+
+        assert_eq!(ledger.transfer_called_with.read().await.len(), 1);
+        assert_eq!(*cmc.notify_mint_cycles_called_with.read().await, vec![4]);
+        assert!(pending_mints.pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_obtain_from_ledger_allowance() {
+        let ledger = Arc::new(TestCyclesLedgerCanister::default());
+        let treasury = Account {
+            owner: Principal::from_slice(&[1]),
+            subaccount: None,
+        };
+
+        let obtain = WithdrawFromCyclesLedgerAllowance::new(ledger.clone(), treasury);
+
+        obtain
+            .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+            .await
+            .expect("obtain_cycles failed");
+
+        assert_eq!(ledger.allowance_called_with.read().await.len(), 1);
+        assert!(matches!(
+            ledger.withdraw_from_called_with.read().await.first(),
+            Some(WithdrawFromArgs { amount, from, .. })
+                if amount == &Nat::from(1_000_000_000_000u64) && from == &treasury
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_from_ledger_allowance_checks() {
+        let allowances = vec![
+            // does not cover the withdrawal fee
+            Allowance {
+                allowance: Nat::from(1_000_000_000_000u64),
+                expires_at: None,
+            },
+            // expired
+            Allowance {
+                allowance: Nat::from(u128::MAX),
+                expires_at: Some(1),
+            },
+        ];
+
+        for allowance in allowances {
+            let ledger = Arc::new(TestCyclesLedgerCanister {
+                allowance_returns_with: Some(Ok(allowance)),
+                ..Default::default()
+            });
+
+            let obtain = WithdrawFromCyclesLedgerAllowance::new(
+                ledger.clone(),
+                Account {
+                    owner: Principal::from_slice(&[1]),
+                    subaccount: None,
+                },
+            );
+
+            let error = obtain
+                .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+                .await
+                .expect_err("obtain_cycles should fail");
+
+            assert!(!error.can_retry);
+            assert!(ledger.withdraw_from_called_with.read().await.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_obtain_from_ledger_errors() {
+        let errors = vec![
+            (
+                WithdrawError::InsufficientFunds {
+                    balance: Nat::from(0u64),
+                },
+                false,
+            ),
+            (WithdrawError::TooOld, false),
+            (WithdrawError::TemporarilyUnavailable, true),
+        ];
+
+        for (error, can_retry) in errors {
+            let ledger = Arc::new(TestCyclesLedgerCanister {
+                returns_with: Some(Ok(Err(error))),
+                ..Default::default()
+            });
+
+            let obtain = WithdrawFromCyclesLedger::new(ledger, None);
+
+            let error = obtain
+                .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+                .await
+                .expect_err("obtain_cycles should fail");
+
+            // the same mapping as for the withdrawals from an allowance
+            assert_eq!(error.can_retry, can_retry);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_obtain_from_ledger_allowance_errors() {
+        let errors = vec![
+            (
+                WithdrawFromError::InsufficientAllowance {
+                    allowance: Nat::from(0u64),
+                },
+                false,
+            ),
+            (
+                WithdrawFromError::InsufficientFunds {
+                    balance: Nat::from(0u64),
+                },
+                false,
+            ),
+            (WithdrawFromError::TemporarilyUnavailable, true),
+        ];
+
+        for (error, can_retry) in errors {
+            let ledger = Arc::new(TestCyclesLedgerCanister {
+                withdraw_from_returns_with: Some(Ok(Err(error))),
+                ..Default::default()
+            });
+
+            let obtain = WithdrawFromCyclesLedgerAllowance::new(
+                ledger,
+                Account {
+                    owner: Principal::from_slice(&[1]),
+                    subaccount: None,
+                },
+            );
+
+            let error = obtain
+                .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+                .await
+                .expect_err("obtain_cycles should fail");
+
+            assert_eq!(error.can_retry, can_retry);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_cycles_from_funder() {
+        let funder = Arc::new(TestFundingCanister::default());
+        let obtain = RequestCyclesFromFunder::new(funder.clone());
+
+        let target = Principal::from_slice(&[1]);
+        let obtained = obtain
+            .obtain_cycles(1_000_000_000_000, target)
+            .await
+            .expect("obtain_cycles failed");
+
+        assert_eq!(obtained, 1_000_000_000_000);
+        assert_eq!(
+            *funder.request_cycles_called_with.read().await,
+            vec![RequestCyclesArgs {
+                target,
+                amount: Nat::from(1_000_000_000_000u64),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_cycles_from_funder_errors() {
+        let errors = vec![
+            (RequestCyclesError::Unauthorized, false),
+            (
+                RequestCyclesError::BudgetExceeded {
+                    remaining: Nat::from(0u64),
+                },
+                false,
+            ),
+            (
+                RequestCyclesError::InsufficientCycles {
+                    available: Nat::from(0u64),
+                },
+                false,
+            ),
+            (
+                RequestCyclesError::DepositFailed {
+                    reason: "rejected".to_string(),
+                },
+                true,
+            ),
+        ];
+
+        for (error, can_retry) in errors {
+            let obtain = RequestCyclesFromFunder::new(Arc::new(TestFundingCanister {
+                request_cycles_returns_with: Some(Ok(Err(error))),
+                ..Default::default()
+            }));
+
+            let error = obtain
+                .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+                .await
+                .expect_err("obtain_cycles should fail");
+
+            assert_eq!(error.can_retry, can_retry);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_cycles_with_unknown_outcome_is_not_retried() {
+        let rejects = vec![
+            (RejectCode::SysUnknown, false),
+            (RejectCode::SysTransient, true),
+        ];
+
+        for (reject_code, can_retry) in rejects {
+            let obtain = RequestCyclesFromFunder::new(Arc::new(TestFundingCanister {
+                request_cycles_returns_with: Some(Err(Error::CallRejected(
+                    CallRejected::with_rejection(reject_code as u32, String::new()),
+                ))),
+                ..Default::default()
+            }));
+
+            let error = obtain
+                .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+                .await
+                .expect_err("obtain_cycles should fail");
+
+            // the parent may have deposited the cycles if the outcome is unknown
+            assert_eq!(error.can_retry, can_retry);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_from_allowance_with_unknown_outcome_is_repeated() {
+        let ledger = Arc::new(TestCyclesLedgerCanister {
+            withdraw_from_returns_with: Some(Err(Error::CallRejected(
+                CallRejected::with_rejection(RejectCode::SysUnknown as u32, String::new()),
+            ))),
+            ..Default::default()
+        });
+        let obtain = WithdrawFromCyclesLedgerAllowance::new(
+            ledger.clone(),
+            Account::from(Principal::anonymous()),
+        );
+
+        for _ in 0..2 {
+            let error = obtain
+                .obtain_cycles(1_000_000_000_000, Principal::anonymous())
+                .await
+                .expect_err("obtain_cycles should fail");
+            assert!(error.can_retry);
+        }
+
+        // the allowance is only checked for the new withdrawal, which is repeated as is
+        assert_eq!(ledger.allowance_called_with.read().await.len(), 1);
+        let withdrawals = ledger.withdraw_from_called_with.read().await;
+        assert_eq!(withdrawals.len(), 2);
+        assert!(withdrawals[0].created_at_time.is_some());
+        assert_eq!(withdrawals[0], withdrawals[1]);
+    }
 }
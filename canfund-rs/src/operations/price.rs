@@ -0,0 +1,285 @@
+use std::sync::{Arc, Mutex};
+
+use crate::api::cmc::GetIcpXdrResult;
+use crate::operations::obtain::ObtainCyclesError;
+
+/// The period in seconds the daily ICP limit applies to.
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// Verifies the certificate of the ICP/XDR rate returned by the CMC, e.g. against the IC root key.
+///
+/// The rate of an inter-canister call is already agreed on by consensus, so verifying the
+/// certificate mostly matters if the rate was relayed from outside of the IC.
+pub trait VerifyIcpXdrRate: Send + Sync {
+    /// Returns an error describing why the certificate of the rate is not valid.
+    fn verify(&self, rate: &GetIcpXdrResult) -> Result<(), String>;
+}
+
+/// Sanity checks of the ICP/XDR rate and limits of the ICP spent when minting cycles.
+///
+/// Cycles are not minted if a check fails, and the mint fails with an error that is not retried.
+///
+/// The ICP spent within the current day is kept in heap memory only, so the daily limit starts
+/// over after an upgrade.
+#[derive(Default)]
+pub struct IcpPriceGuard {
+    /// The maximum age in seconds of the rate.
+    max_rate_age_secs: Option<u64>,
+    /// The minimum acceptable rate in XDR permyriad per ICP.
+    min_xdr_permyriad_per_icp: Option<u64>,
+    /// The maximum acceptable rate in XDR permyriad per ICP.
+    max_xdr_permyriad_per_icp: Option<u64>,
+    /// The maximum ICP in e8s spent per mint.
+    max_icp_e8s_per_mint: Option<u64>,
+    /// The maximum ICP in e8s spent per day.
+    max_icp_e8s_per_day: Option<u64>,
+    /// Verifies the certificate of the rate.
+    certificate_verifier: Option<Arc<dyn VerifyIcpXdrRate>>,
+    /// The ICP spent within the current day.
+    daily_spend: Mutex<DailySpend>,
+}
+
+#[derive(Default)]
+struct DailySpend {
+    /// The timestamp in nanoseconds when the current day started.
+    started_at: u64,
+    /// The ICP in e8s spent within the current day.
+    icp_e8s: u64,
+}
+
+impl IcpPriceGuard {
+    /// Creates a new guard without any checks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum age in seconds of the rate.
+    pub fn with_max_rate_age_secs(mut self, max_rate_age_secs: u64) -> Self {
+        self.max_rate_age_secs = Some(max_rate_age_secs);
+        self
+    }
+
+    /// Sets the minimum acceptable rate in XDR permyriad per ICP.
+    pub fn with_min_xdr_permyriad_per_icp(mut self, min_xdr_permyriad_per_icp: u64) -> Self {
+        self.min_xdr_permyriad_per_icp = Some(min_xdr_permyriad_per_icp);
+        self
+    }
+
+    /// Sets the maximum acceptable rate in XDR permyriad per ICP.
+    pub fn with_max_xdr_permyriad_per_icp(mut self, max_xdr_permyriad_per_icp: u64) -> Self {
+        self.max_xdr_permyriad_per_icp = Some(max_xdr_permyriad_per_icp);
+        self
+    }
+
+    /// Sets the maximum ICP in e8s spent per mint.
+    pub fn with_max_icp_e8s_per_mint(mut self, max_icp_e8s_per_mint: u64) -> Self {
+        self.max_icp_e8s_per_mint = Some(max_icp_e8s_per_mint);
+        self
+    }
+
+    /// Sets the maximum ICP in e8s spent per day, counted since the start of the current day or
+    /// the last upgrade of the canister.
+    pub fn with_max_icp_e8s_per_day(mut self, max_icp_e8s_per_day: u64) -> Self {
+        self.max_icp_e8s_per_day = Some(max_icp_e8s_per_day);
+        self
+    }
+
+    /// Sets the verifier of the rate certificate.
+    pub fn with_certificate_verifier(
+        mut self,
+        certificate_verifier: Arc<dyn VerifyIcpXdrRate>,
+    ) -> Self {
+        self.certificate_verifier = Some(certificate_verifier);
+        self
+    }
+
+    /// Get the maximum age in seconds of the rate.
+    pub fn max_rate_age_secs(&self) -> Option<u64> {
+        self.max_rate_age_secs
+    }
+
+    /// Get the minimum acceptable rate in XDR permyriad per ICP.
+    pub fn min_xdr_permyriad_per_icp(&self) -> Option<u64> {
+        self.min_xdr_permyriad_per_icp
+    }
+
+    /// Get the maximum acceptable rate in XDR permyriad per ICP.
+    pub fn max_xdr_permyriad_per_icp(&self) -> Option<u64> {
+        self.max_xdr_permyriad_per_icp
+    }
+
+    /// Get the maximum ICP in e8s spent per mint.
+    pub fn max_icp_e8s_per_mint(&self) -> Option<u64> {
+        self.max_icp_e8s_per_mint
+    }
+
+    /// Get the maximum ICP in e8s spent per day.
+    pub fn max_icp_e8s_per_day(&self) -> Option<u64> {
+        self.max_icp_e8s_per_day
+    }
+
+    /// Returns the ICP in e8s that can still be spent within the current day, if limited.
+    pub fn remaining_icp_e8s_today(&self, now: u64) -> Option<u64> {
+        let max_icp_e8s_per_day = self.max_icp_e8s_per_day?;
+        let mut daily_spend = self.daily_spend();
+        daily_spend.renew(now);
+
+        Some(max_icp_e8s_per_day.saturating_sub(daily_spend.icp_e8s))
+    }
+
+    /// Checks that the rate is recent, within the acceptable range and certified.
+    pub fn check_rate(&self, rate: &GetIcpXdrResult, now: u64) -> Result<(), ObtainCyclesError> {
+        let rate_age_secs = (now / 1_000_000_000).saturating_sub(rate.data.timestamp_seconds);
+        if let Some(max_rate_age_secs) = self.max_rate_age_secs {
+            if rate_age_secs > max_rate_age_secs {
+                return Err(refused(format!(
+                    "the ICP/XDR rate is {rate_age_secs}s old, the maximum is {max_rate_age_secs}s"
+                )));
+            }
+        }
+
+        let xdr_permyriad_per_icp = rate.data.xdr_permyriad_per_icp;
+        if let Some(min_xdr_permyriad_per_icp) = self.min_xdr_permyriad_per_icp {
+            if xdr_permyriad_per_icp < min_xdr_permyriad_per_icp {
+                return Err(refused(format!(
+                    "the ICP/XDR rate of {xdr_permyriad_per_icp} XDR permyriad is below the minimum of {min_xdr_permyriad_per_icp}"
+                )));
+            }
+        }
+        if let Some(max_xdr_permyriad_per_icp) = self.max_xdr_permyriad_per_icp {
+            if xdr_permyriad_per_icp > max_xdr_permyriad_per_icp {
+                return Err(refused(format!(
+                    "the ICP/XDR rate of {xdr_permyriad_per_icp} XDR permyriad is above the maximum of {max_xdr_permyriad_per_icp}"
+                )));
+            }
+        }
+
+        if let Some(certificate_verifier) = &self.certificate_verifier {
+            certificate_verifier.verify(rate).map_err(|err| {
+                refused(format!("the ICP/XDR rate certificate is not valid: {err}"))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Charges the ICP of a mint to the daily limit, if it is within the limits.
+    pub fn reserve_icp(&self, icp_e8s: u64, now: u64) -> Result<(), ObtainCyclesError> {
+        if let Some(max_icp_e8s_per_mint) = self.max_icp_e8s_per_mint {
+            if icp_e8s > max_icp_e8s_per_mint {
+                return Err(refused(format!(
+                    "minting requires {icp_e8s} e8s, the maximum per mint is {max_icp_e8s_per_mint} e8s"
+                )));
+            }
+        }
+
+        let mut daily_spend = self.daily_spend();
+        daily_spend.renew(now);
+
+        if let Some(max_icp_e8s_per_day) = self.max_icp_e8s_per_day {
+            let remaining_icp_e8s = max_icp_e8s_per_day.saturating_sub(daily_spend.icp_e8s);
+            if icp_e8s > remaining_icp_e8s {
+                return Err(refused(format!(
+                    "minting requires {icp_e8s} e8s, only {remaining_icp_e8s} e8s of the daily maximum of {max_icp_e8s_per_day} e8s are left"
+                )));
+            }
+        }
+
+        daily_spend.icp_e8s = daily_spend.icp_e8s.saturating_add(icp_e8s);
+        Ok(())
+    }
+
+    /// Returns the ICP of a mint that was not spent to the daily limit.
+    pub fn release_icp(&self, icp_e8s: u64) {
+        let mut daily_spend = self.daily_spend();
+        daily_spend.icp_e8s = daily_spend.icp_e8s.saturating_sub(icp_e8s);
+    }
+
+    fn daily_spend(&self) -> std::sync::MutexGuard<'_, DailySpend> {
+        self.daily_spend
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl DailySpend {
+    fn renew(&mut self, now: u64) {
+        if now.saturating_sub(self.started_at) >= DAY_SECS.saturating_mul(1_000_000_000) {
+            self.started_at = now;
+            self.icp_e8s = 0;
+        }
+    }
+}
+
+fn refused(reason: String) -> ObtainCyclesError {
+    ObtainCyclesError {
+        details: format!("Refused to mint cycles: {reason}"),
+        can_retry: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::cmc::GetIcpXdrResultData;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn rate(xdr_permyriad_per_icp: u64, timestamp_seconds: u64) -> GetIcpXdrResult {
+        GetIcpXdrResult {
+            data: GetIcpXdrResultData {
+                xdr_permyriad_per_icp,
+                timestamp_seconds,
+            },
+            ..Default::default()
+        }
+    }
+
+    struct RejectAll;
+
+    impl VerifyIcpXdrRate for RejectAll {
+        fn verify(&self, _rate: &GetIcpXdrResult) -> Result<(), String> {
+            Err("untrusted".to_string())
+        }
+    }
+
+    #[test]
+    fn test_check_rate() {
+        let guard = IcpPriceGuard::new()
+            .with_max_rate_age_secs(600)
+            .with_min_xdr_permyriad_per_icp(10_000)
+            .with_max_xdr_permyriad_per_icp(100_000);
+        let now = 1_000 * SECOND;
+
+        assert!(guard.check_rate(&rate(50_000, 500), now).is_ok());
+        assert!(guard.check_rate(&rate(50_000, 300), now).is_err());
+        assert!(guard.check_rate(&rate(5_000, 500), now).is_err());
+        assert!(guard.check_rate(&rate(150_000, 500), now).is_err());
+
+        let err = IcpPriceGuard::new()
+            .with_certificate_verifier(Arc::new(RejectAll))
+            .check_rate(&rate(50_000, 500), now)
+            .unwrap_err();
+        assert!(!err.can_retry);
+        assert!(err.details.contains("untrusted"));
+    }
+
+    #[test]
+    fn test_reserve_icp_within_limits() {
+        let guard = IcpPriceGuard::new()
+            .with_max_icp_e8s_per_mint(100)
+            .with_max_icp_e8s_per_day(150);
+        let now = DAY_SECS * SECOND;
+
+        assert!(guard.reserve_icp(101, now).is_err());
+        assert!(guard.reserve_icp(100, now).is_ok());
+        assert!(guard.reserve_icp(100, now).is_err());
+        assert_eq!(guard.remaining_icp_e8s_today(now), Some(50));
+
+        guard.release_icp(100);
+        assert!(guard.reserve_icp(100, now).is_ok());
+
+        // the limit is renewed after a day
+        assert!(guard.reserve_icp(100, now + DAY_SECS * SECOND).is_ok());
+    }
+}
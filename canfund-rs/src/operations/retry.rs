@@ -0,0 +1,364 @@
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+
+use ic_cdk::call::CallErrorExt;
+
+use crate::operations::obtain::ObtainCyclesError;
+use crate::utils::time;
+
+/// The operations retried according to a `RetryPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryOperation {
+    /// Obtaining cycles from an `ObtainCycles` source.
+    ObtainCycles,
+    /// Notifying the CMC about an ICP transfer.
+    NotifyCmc,
+    /// Depositing cycles to a funded canister.
+    DepositCycles,
+}
+
+/// A failed attempt of an operation, as seen by the retryable-error classifier.
+#[derive(Debug, Clone, Copy)]
+pub struct FailedAttempt<'a> {
+    /// The operation that failed.
+    pub operation: RetryOperation,
+    /// The number of the failed attempt, starting at 1.
+    pub attempt: u32,
+    /// Details of the error.
+    pub details: &'a str,
+    /// Whether the error is retryable by default.
+    pub can_retry: bool,
+}
+
+pub type RetryClassifier = Arc<dyn Fn(&FailedAttempt) -> bool + Send + Sync>;
+
+/// How often and when a failed operation is retried.
+///
+/// The delay before the n-th retry is `initial_backoff * backoff_multiplier^(n - 1)`, capped at
+/// `max_backoff`, plus a random jitter of up to `jitter`. The delays are awaited using one-shot timers.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    max_attempts: u32,
+    /// The delay before the first retry.
+    initial_backoff: Duration,
+    /// The maximum delay between two attempts.
+    max_backoff: Duration,
+    /// The factor the delay grows by after each retry.
+    backoff_multiplier: u32,
+    /// The maximum random delay added to each backoff.
+    jitter: Duration,
+    /// Decides whether a failed attempt is retried, by default if the error is retryable.
+    classifier: Option<RetryClassifier>,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("backoff_multiplier", &self.backoff_multiplier)
+            .field("jitter", &self.jitter)
+            .field("classifier", &self.classifier.is_some())
+            .finish()
+    }
+}
+
+impl Default for RetryPolicy {
+    /// The default is to not retry.
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::from_secs(60),
+            backoff_multiplier: 2,
+            jitter: Duration::ZERO,
+            classifier: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Retries immediately until the maximum number of attempts is reached.
+    pub fn immediate(max_attempts: u32) -> Self {
+        Self::new().with_max_attempts(max_attempts)
+    }
+
+    /// Set the maximum number of attempts, including the first one.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the delay before the first retry.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Set the maximum delay between two attempts.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Set the factor the delay grows by after each retry.
+    pub fn with_backoff_multiplier(mut self, backoff_multiplier: u32) -> Self {
+        self.backoff_multiplier = backoff_multiplier;
+        self
+    }
+
+    /// Set the maximum random delay added to each backoff.
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Set the classifier deciding whether a failed attempt is retried.
+    pub fn with_classifier(
+        mut self,
+        classifier: impl Fn(&FailedAttempt) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.classifier = Some(Arc::new(classifier));
+        self
+    }
+
+    /// Returns whether the policies retry with the same attempts and backoff, and whether both have
+    /// a classifier. The classifiers themselves cannot be compared.
+    pub(crate) fn same_settings(&self, other: &RetryPolicy) -> bool {
+        self.max_attempts == other.max_attempts
+            && self.initial_backoff == other.initial_backoff
+            && self.max_backoff == other.max_backoff
+            && self.backoff_multiplier == other.backoff_multiplier
+            && self.jitter == other.jitter
+            && self.classifier.is_some() == other.classifier.is_some()
+    }
+
+    /// Get the maximum number of attempts, including the first one.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Get the delay before the first retry.
+    pub fn initial_backoff(&self) -> Duration {
+        self.initial_backoff
+    }
+
+    /// Get the maximum delay between two attempts.
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    /// Get the factor the delay grows by after each retry.
+    pub fn backoff_multiplier(&self) -> u32 {
+        self.backoff_multiplier
+    }
+
+    /// Get the maximum random delay added to each backoff.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Returns the delay without jitter before retrying the failed attempt.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self
+            .backoff_multiplier
+            .checked_pow(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// Returns whether the failed attempt is retried, ignoring the maximum number of attempts.
+    pub fn is_retryable(&self, attempt: &FailedAttempt) -> bool {
+        match &self.classifier {
+            Some(classifier) => classifier(attempt),
+            None => attempt.can_retry,
+        }
+    }
+
+    /// Runs the operation until it succeeds, fails with an error that is not retried or the maximum
+    /// number of attempts is reached, and returns the last result.
+    pub(crate) async fn retry<T, E, F, Fut>(
+        &self,
+        operation: RetryOperation,
+        mut run: F,
+    ) -> Result<T, E>
+    where
+        E: RetryableError,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let err = match run().await {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+
+            let details = err.details();
+            let failed_attempt = FailedAttempt {
+                operation,
+                attempt,
+                details: &details,
+                can_retry: err.can_retry(),
+            };
+
+            if attempt >= self.max_attempts || !self.is_retryable(&failed_attempt) {
+                return Err(err);
+            }
+
+            sleep(self.backoff(attempt) + self.random_jitter()).await;
+        }
+    }
+
+    fn random_jitter(&self) -> Duration {
+        let jitter_nanos = self.jitter.as_nanos() as u64;
+        if jitter_nanos == 0 {
+            return Duration::ZERO;
+        }
+
+        // The time is unpredictable enough to spread retries, without a source of randomness.
+        Duration::from_nanos(time().wrapping_mul(0x9e37_79b9_7f4a_7c15) % (jitter_nanos + 1))
+    }
+}
+
+/// An error of an operation that can be retried according to a `RetryPolicy`.
+pub(crate) trait RetryableError {
+    fn details(&self) -> String;
+
+    fn can_retry(&self) -> bool;
+}
+
+impl RetryableError for ObtainCyclesError {
+    fn details(&self) -> String {
+        self.details.clone()
+    }
+
+    fn can_retry(&self) -> bool {
+        self.can_retry
+    }
+}
+
+impl RetryableError for ic_cdk::call::Error {
+    fn details(&self) -> String {
+        self.to_string()
+    }
+
+    /// Only calls rejected without any state change are retried, so that cycles are not sent twice.
+    fn can_retry(&self) -> bool {
+        self.is_clean_reject() && self.is_immediately_retryable()
+    }
+}
+
+/// Waits for the delay using a one-shot timer.
+#[cfg(target_arch = "wasm32")]
+async fn sleep(delay: Duration) {
+    if delay.is_zero() {
+        return;
+    }
+
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    ic_cdk_timers::set_timer(delay, async move {
+        let _ = sender.send(());
+    });
+    let _ = receiver.await;
+}
+
+/// Timers are only available in canisters, so the delay is skipped.
+#[cfg(not(target_arch = "wasm32"))]
+async fn sleep(_delay: Duration) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::call::{CallRejected, RejectCode};
+
+    #[test]
+    fn test_backoff_grows_exponentially_up_to_max() {
+        let policy = RetryPolicy::new()
+            .with_initial_backoff(Duration::from_secs(1))
+            .with_backoff_multiplier(3)
+            .with_max_backoff(Duration::from_secs(20));
+
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(3));
+        assert_eq!(policy.backoff(3), Duration::from_secs(9));
+        assert_eq!(policy.backoff(4), Duration::from_secs(20));
+        assert_eq!(policy.backoff(100), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_jitter_is_bounded() {
+        let policy = RetryPolicy::new().with_jitter(Duration::from_millis(10));
+
+        for _ in 0..100 {
+            assert!(policy.random_jitter() <= Duration::from_millis(10));
+        }
+        assert_eq!(RetryPolicy::new().random_jitter(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_unknown_call_outcome_is_not_retried() {
+        let reject = |reject_code: RejectCode| -> ic_cdk::call::Error {
+            CallRejected::with_rejection(reject_code as u32, String::new()).into()
+        };
+
+        // e.g. a bounded-wait call that timed out, the callee may still have executed it
+        assert!(!RetryableError::can_retry(&reject(RejectCode::SysUnknown)));
+        assert!(!RetryableError::can_retry(&reject(
+            RejectCode::CanisterError
+        )));
+        assert!(RetryableError::can_retry(&reject(RejectCode::SysTransient)));
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_max_attempts() {
+        let mut attempts = 0;
+        let result: Result<(), ObtainCyclesError> = RetryPolicy::immediate(3)
+            .retry(RetryOperation::ObtainCycles, || {
+                attempts += 1;
+                async {
+                    Err(ObtainCyclesError {
+                        details: "failed".to_string(),
+                        can_retry: true,
+                    })
+                }
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_uses_classifier() {
+        let mut attempts = 0;
+        let result: Result<(), ObtainCyclesError> = RetryPolicy::immediate(3)
+            .with_classifier(|attempt| attempt.details != "fatal")
+            .retry(RetryOperation::ObtainCycles, || {
+                attempts += 1;
+                let details = if attempts == 1 { "transient" } else { "fatal" };
+                async move {
+                    Err(ObtainCyclesError {
+                        details: details.to_string(),
+                        can_retry: false,
+                    })
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap_err().details, "fatal");
+        assert_eq!(attempts, 2);
+    }
+}
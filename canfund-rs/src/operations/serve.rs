@@ -0,0 +1,322 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use candid::Principal;
+use ic_cdk::call::CallErrorExt;
+use ic_cdk::management_canister::DepositCyclesArgs;
+
+use crate::api::call::{deposit_cycles, CallWait};
+use crate::types::{RequestCyclesArgs, RequestCyclesError, RequestCyclesSuccess};
+use crate::utils::{canister_cycle_balance, cycles_nat_to_u128, time};
+
+/// The default period after which the budget of a child funder is renewed, one day.
+pub const DEFAULT_BUDGET_PERIOD_SECS: u64 = 24 * 60 * 60;
+
+/// The default balance the parent funder keeps for itself when serving requests, 250B cycles.
+pub const DEFAULT_RESERVE_CYCLES: u128 = 250_000_000_000;
+
+/// The amount of cycles a child funder can request within a period.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunderBudget {
+    /// The maximum amount of cycles that can be requested within a period.
+    max_cycles_per_period: u128,
+    /// The length of the period in seconds.
+    period_secs: u64,
+}
+
+impl Default for FunderBudget {
+    fn default() -> Self {
+        Self {
+            max_cycles_per_period: 0,
+            period_secs: DEFAULT_BUDGET_PERIOD_SECS,
+        }
+    }
+}
+
+impl FunderBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum amount of cycles that can be requested within a period.
+    pub fn with_max_cycles_per_period(mut self, max_cycles_per_period: u128) -> Self {
+        self.max_cycles_per_period = max_cycles_per_period;
+        self
+    }
+
+    /// Set the length of the period in seconds.
+    pub fn with_period_secs(mut self, period_secs: u64) -> Self {
+        self.period_secs = period_secs;
+        self
+    }
+
+    /// Get the maximum amount of cycles that can be requested within a period.
+    pub fn max_cycles_per_period(&self) -> u128 {
+        self.max_cycles_per_period
+    }
+
+    /// Get the length of the period in seconds.
+    pub fn period_secs(&self) -> u64 {
+        self.period_secs
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ChildFunder {
+    budget: FunderBudget,
+    /// The timestamp in nanoseconds when the current period started.
+    period_started_at: u64,
+    /// The cycles requested within the current period.
+    used_cycles: u128,
+}
+
+impl ChildFunder {
+    fn renew_period(&mut self, now: u64) {
+        let period_nanos = self.budget.period_secs.saturating_mul(1_000_000_000);
+        if now.saturating_sub(self.period_started_at) >= period_nanos {
+            self.period_started_at = now;
+            self.used_cycles = 0;
+        }
+    }
+
+    fn remaining_cycles(&self) -> u128 {
+        self.budget
+            .max_cycles_per_period
+            .saturating_sub(self.used_cycles)
+    }
+}
+
+/// Serves requests for cycles from child funding canisters, e.g. sub-funders configured with
+/// `RequestCyclesFromFunder`.
+///
+/// Only allowlisted callers can request cycles, up to their budget per period, and only the cycles
+/// above the reserve of the parent are deposited. The handler is meant to be called from the
+/// `canfund_request_cycles` update endpoint of the parent canister.
+///
+/// The allowlist and the budgets are kept in heap memory only and are not persisted across upgrades,
+/// so they must be allowed again after an upgrade, which also renews the budget periods.
+#[derive(Clone)]
+pub struct CyclesRequestHandler {
+    children: Rc<RefCell<HashMap<Principal, ChildFunder>>>,
+    /// The balance the parent keeps for itself.
+    reserve_cycles: u128,
+    /// How long to wait for the management canister when depositing cycles.
+    deposit_call_wait: CallWait,
+}
+
+impl Default for CyclesRequestHandler {
+    fn default() -> Self {
+        Self {
+            children: Rc::default(),
+            reserve_cycles: DEFAULT_RESERVE_CYCLES,
+            deposit_call_wait: CallWait::default(),
+        }
+    }
+}
+
+impl CyclesRequestHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the balance the parent keeps for itself, requests are only served from the cycles above it.
+    pub fn with_reserve_cycles(mut self, reserve_cycles: u128) -> Self {
+        self.reserve_cycles = reserve_cycles;
+        self
+    }
+
+    /// Set how long to wait for the management canister when depositing cycles.
+    ///
+    /// The budget of the caller stays charged for a deposit that times out, as it may have been
+    /// executed.
+    pub fn with_deposit_call_wait(mut self, deposit_call_wait: CallWait) -> Self {
+        self.deposit_call_wait = deposit_call_wait;
+        self
+    }
+
+    /// Get the balance the parent keeps for itself.
+    pub fn reserve_cycles(&self) -> u128 {
+        self.reserve_cycles
+    }
+
+    /// Get how long to wait for the management canister when depositing cycles.
+    pub fn deposit_call_wait(&self) -> CallWait {
+        self.deposit_call_wait
+    }
+
+    /// Allows the caller to request cycles within the budget, replacing its previous budget.
+    pub fn allow(&self, caller: Principal, budget: FunderBudget) {
+        self.children.borrow_mut().insert(
+            caller,
+            ChildFunder {
+                budget,
+                period_started_at: time(),
+                used_cycles: 0,
+            },
+        );
+    }
+
+    /// Removes the caller from the allowlist.
+    pub fn disallow(&self, caller: &Principal) {
+        self.children.borrow_mut().remove(caller);
+    }
+
+    /// Returns the cycles the caller can still request in the current period, if it is allowlisted.
+    pub fn remaining_budget(&self, caller: &Principal) -> Option<u128> {
+        let now = time();
+        self.children.borrow_mut().get_mut(caller).map(|child| {
+            child.renew_period(now);
+            child.remaining_cycles()
+        })
+    }
+
+    /// Validates the request and deposits the requested cycles to the target canister.
+    ///
+    /// The budget of the caller is only charged if the deposit succeeds, or if it is unknown whether
+    /// it did.
+    pub async fn handle(
+        &self,
+        caller: Principal,
+        args: RequestCyclesArgs,
+    ) -> Result<RequestCyclesSuccess, RequestCyclesError> {
+        let amount = cycles_nat_to_u128(args.amount.clone()).unwrap_or(u128::MAX);
+        self.reserve(&caller, amount, time())?;
+
+        if let Err(err) = self.check_available(canister_cycle_balance(), amount) {
+            self.release(&caller, amount);
+            return Err(err);
+        }
+
+        if let Err(err) = deposit_cycles(
+            self.deposit_call_wait,
+            &DepositCyclesArgs {
+                canister_id: args.target,
+            },
+            amount,
+        )
+        .await
+        {
+            if err.is_clean_reject() {
+                self.release(&caller, amount);
+            }
+            return Err(RequestCyclesError::DepositFailed {
+                reason: err.to_string(),
+            });
+        }
+
+        Ok(RequestCyclesSuccess {
+            deposited: args.amount,
+        })
+    }
+
+    /// Charges the amount to the budget of the caller, so that concurrent requests cannot exceed it.
+    fn reserve(
+        &self,
+        caller: &Principal,
+        amount: u128,
+        now: u64,
+    ) -> Result<(), RequestCyclesError> {
+        let mut children = self.children.borrow_mut();
+        let child = children
+            .get_mut(caller)
+            .ok_or(RequestCyclesError::Unauthorized)?;
+
+        child.renew_period(now);
+        if amount > child.remaining_cycles() {
+            return Err(RequestCyclesError::BudgetExceeded {
+                remaining: child.remaining_cycles().into(),
+            });
+        }
+
+        child.used_cycles += amount;
+        Ok(())
+    }
+
+    /// Checks that the balance above the reserve covers the amount.
+    fn check_available(&self, balance: u128, amount: u128) -> Result<(), RequestCyclesError> {
+        let available = balance.saturating_sub(self.reserve_cycles);
+        if available < amount {
+            return Err(RequestCyclesError::InsufficientCycles {
+                available: available.into(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the amount to the budget of the caller after a failed request.
+    fn release(&self, caller: &Principal, amount: u128) {
+        if let Some(child) = self.children.borrow_mut().get_mut(caller) {
+            child.used_cycles = child.used_cycles.saturating_sub(amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_reserve_validates_allowlist_and_budget() {
+        let handler = CyclesRequestHandler::new();
+        let child = Principal::from_slice(&[1]);
+
+        assert_eq!(
+            handler.reserve(&child, 100, 0),
+            Err(RequestCyclesError::Unauthorized)
+        );
+
+        handler.allow(child, FunderBudget::new().with_max_cycles_per_period(150));
+
+        assert_eq!(handler.reserve(&child, 100, time()), Ok(()));
+        assert_eq!(
+            handler.reserve(&child, 100, time()),
+            Err(RequestCyclesError::BudgetExceeded {
+                remaining: 50u64.into()
+            })
+        );
+
+        handler.release(&child, 100);
+        assert_eq!(handler.remaining_budget(&child), Some(150));
+
+        handler.disallow(&child);
+        assert_eq!(handler.remaining_budget(&child), None);
+    }
+
+    #[test]
+    fn test_requests_are_served_above_the_reserve() {
+        let handler = CyclesRequestHandler::new().with_reserve_cycles(1_000);
+
+        assert_eq!(handler.check_available(1_500, 500), Ok(()));
+        assert_eq!(
+            handler.check_available(1_500, 501),
+            Err(RequestCyclesError::InsufficientCycles {
+                available: 500u64.into()
+            })
+        );
+        assert_eq!(
+            handler.check_available(900, 1),
+            Err(RequestCyclesError::InsufficientCycles {
+                available: 0u64.into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_budget_renews_after_period() {
+        let handler = CyclesRequestHandler::new();
+        let child = Principal::from_slice(&[1]);
+        handler.allow(
+            child,
+            FunderBudget::new()
+                .with_max_cycles_per_period(100)
+                .with_period_secs(10),
+        );
+        let now = time();
+
+        assert_eq!(handler.reserve(&child, 100, now), Ok(()));
+        assert!(handler.reserve(&child, 1, now + 9 * SECOND).is_err());
+        assert_eq!(handler.reserve(&child, 100, now + 10 * SECOND), Ok(()));
+    }
+}
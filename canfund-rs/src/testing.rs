@@ -0,0 +1,9 @@
+//! Test utilities for unit-testing funding configurations, available with the `testing` feature.
+//!
+//! The mocks record the calls they receive and reply with configurable results, so they can be
+//! plugged into `ObtainCycles` implementations and `RegisterOpts` in place of the real canisters.
+
+pub use crate::api::cmc::test::TestCmcCanister;
+pub use crate::api::funder::test::TestFundingCanister;
+pub use crate::api::ledger::test::{TestCyclesLedgerCanister, TestLedgerCanister};
+pub use crate::operations::fetch::test::TestFetchCyclesBalance;
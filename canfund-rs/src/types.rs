@@ -1,5 +1,5 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
-use icrc_ledger_types::icrc1::account::Subaccount;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use icrc_ledger_types::icrc1::transfer::BlockIndex;
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -61,3 +61,73 @@ pub enum WithdrawError {
         receiver: Principal,
     },
 }
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WithdrawFromArgs {
+    #[serde(default)]
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Principal,
+    #[serde(default)]
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum WithdrawFromError {
+    InsufficientFunds {
+        balance: Nat,
+    },
+    InsufficientAllowance {
+        allowance: Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    TemporarilyUnavailable,
+    Duplicate {
+        duplicate_of: BlockIndex,
+    },
+    FailedToWithdrawFrom {
+        withdraw_from_block: Option<Nat>,
+        refund_block: Option<Nat>,
+        approval_refund_block: Option<Nat>,
+        rejection_code: Nat,
+        rejection_reason: String,
+    },
+    GenericError {
+        error_code: Nat,
+        message: String,
+    },
+    InvalidReceiver {
+        receiver: Principal,
+    },
+}
+
+/// The arguments of a request for cycles to a parent funding canister.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RequestCyclesArgs {
+    /// The canister to deposit the cycles to.
+    pub target: Principal,
+    /// The amount of cycles requested.
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RequestCyclesSuccess {
+    /// The amount of cycles deposited to the target canister.
+    pub deposited: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RequestCyclesError {
+    /// The caller is not allowed to request cycles.
+    Unauthorized,
+    /// The request exceeds the remaining budget of the caller.
+    BudgetExceeded { remaining: Nat },
+    /// The funding canister does not hold enough cycles to serve the request.
+    InsufficientCycles { available: Nat },
+    /// Depositing the cycles to the target canister failed.
+    DepositFailed { reason: String },
+}
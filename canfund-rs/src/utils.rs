@@ -1,4 +1,5 @@
 use crate::errors::Error;
+use candid::Principal;
 
 /// Converts the cycles from `candid::Nat` to `u128`.
 pub fn cycles_nat_to_u128(cycles: candid::Nat) -> Result<u128, Error> {
@@ -22,6 +23,50 @@ pub fn cycles_str_to_u128(cycles: &str) -> Result<u128, Error> {
     }
 }
 
+/// Returns the current time in nanoseconds since the epoch.
+///
+/// Outside of a canister the system time is used, so that logic depending on it can be unit-tested.
+pub fn time() -> u64 {
+    #[cfg(target_family = "wasm")]
+    {
+        ic_cdk::api::time()
+    }
+    #[cfg(not(target_family = "wasm"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as u64)
+    }
+}
+
+/// Returns the id of the canister running canfund.
+///
+/// Outside of a canister the anonymous principal is used, so that logic depending on it can be unit-tested.
+pub fn canister_self() -> Principal {
+    #[cfg(target_family = "wasm")]
+    {
+        ic_cdk::api::canister_self()
+    }
+    #[cfg(not(target_family = "wasm"))]
+    {
+        Principal::anonymous()
+    }
+}
+
+/// Returns the cycles balance of the canister running canfund.
+///
+/// Outside of a canister the balance is zero, so that logic depending on it can be unit-tested.
+pub fn canister_cycle_balance() -> u128 {
+    #[cfg(target_family = "wasm")]
+    {
+        ic_cdk::api::canister_cycle_balance()
+    }
+    #[cfg(not(target_family = "wasm"))]
+    {
+        0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
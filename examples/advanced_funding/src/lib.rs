@@ -5,7 +5,7 @@ use canfund::{
     api::{cmc::IcCyclesMintingCanister, ledger::IcLedgerCanister},
     manager::{
         options::{
-            CyclesThreshold, EstimatedRuntime, FundManagerOptions, FundStrategy,
+            CyclesThreshold, EstimatedRuntime, FundManagerOptions, FundStrategy, FunderOptions,
             ObtainCyclesOptions,
         },
         RegisterOpts,
@@ -13,7 +13,7 @@ use canfund::{
     operations::{fetch::FetchCyclesBalanceFromCanisterStatus, obtain::MintCycles},
     FundManager,
 };
-use ic_cdk::api::{canister_self, debug_print};
+use ic_cdk::api::debug_print;
 use ic_cdk::query;
 use ic_cdk_macros::{init, post_upgrade};
 use ic_ledger_types::{
@@ -75,28 +75,26 @@ pub fn start_canister_cycles_monitoring(config: FundingConfig) {
         fund_manager_options =
             fund_manager_options.with_obtain_cycles_options(get_obtain_cycles_config());
 
+        // The funding canister itself is also monitored, with its own strategy.
+        fund_manager_options = fund_manager_options.with_funder_options(Some(
+            FunderOptions::new().with_strategy(FundStrategy::BelowThreshold(
+                CyclesThreshold::new()
+                    .with_min_cycles(500_000_000_000)
+                    .with_fund_cycles(750_000_000_000),
+            )),
+        ));
+
         fund_manager.with_options(fund_manager_options);
 
-        for canister_id in config.funded_canister_ids {
-            fund_manager.register(
+        // Reconcile the registered canisters with the configured ones, e.g. after an upgrade.
+        fund_manager.sync(config.funded_canister_ids.into_iter().map(|canister_id| {
+            (
                 canister_id,
                 RegisterOpts::new()
                     .with_cycles_fetcher(Arc::new(FetchCyclesBalanceFromCanisterStatus::new()))
                     .with_obtain_cycles_options(get_obtain_cycles_config().unwrap()),
-            );
-        }
-
-        // The funding canister itself can also be monitored.
-        fund_manager.register(
-            canister_self(),
-            RegisterOpts::new()
-                .with_cycles_fetcher(Arc::new(FetchCyclesBalanceFromCanisterStatus::new()))
-                .with_strategy(FundStrategy::BelowThreshold(
-                    CyclesThreshold::new()
-                        .with_min_cycles(500_000_000_000)
-                        .with_fund_cycles(750_000_000_000),
-                )),
-        );
+            )
+        }));
 
         fund_manager.start();
     });
@@ -104,15 +102,13 @@ pub fn start_canister_cycles_monitoring(config: FundingConfig) {
 
 // Default subaccount for minting cycles is derived from the canister's account.
 pub fn get_obtain_cycles_config() -> Option<ObtainCyclesOptions> {
-    Some(ObtainCyclesOptions {
-        obtain_cycles: Arc::new(MintCycles {
-            ledger: Arc::new(IcLedgerCanister::new(MAINNET_LEDGER_CANISTER_ID)),
-            cmc: Arc::new(IcCyclesMintingCanister::new(
-                MAINNET_CYCLES_MINTING_CANISTER_ID,
-            )),
-            from_subaccount: DEFAULT_SUBACCOUNT,
-        }),
-    })
+    Some(ObtainCyclesOptions::new(Arc::new(MintCycles::new(
+        Arc::new(IcCyclesMintingCanister::new(
+            MAINNET_CYCLES_MINTING_CANISTER_ID,
+        )),
+        Arc::new(IcLedgerCanister::new(MAINNET_LEDGER_CANISTER_ID)),
+        DEFAULT_SUBACCOUNT,
+    ))))
 }
 
 #[derive(CandidType, Deserialize)]
@@ -0,0 +1,110 @@
+use std::{cell::RefCell, sync::Arc};
+
+use candid::{self, CandidType, Deserialize, Nat, Principal};
+use canfund::{
+    api::{call::CallWait, funder::IcFundingCanister},
+    manager::{
+        options::{CyclesThreshold, FundManagerOptions, FundStrategy, ObtainCyclesOptions},
+        RegisterOpts,
+    },
+    operations::{
+        fetch::FetchCyclesBalanceFromCanisterStatus,
+        obtain::RequestCyclesFromFunder,
+        serve::{CyclesRequestHandler, FunderBudget},
+    },
+    types::{RequestCyclesArgs, RequestCyclesError, RequestCyclesSuccess},
+    utils::cycles_nat_to_u128,
+    FundManager,
+};
+use ic_cdk::{api::msg_caller, update};
+use ic_cdk_macros::{init, post_upgrade};
+
+thread_local! {
+    /// Monitor the cycles of canisters and top up if necessary.
+    pub static FUND_MANAGER: RefCell<FundManager> = RefCell::new(FundManager::new());
+
+    /// Serve the cycles requests of the sub-funders.
+    pub static REQUEST_HANDLER: CyclesRequestHandler = CyclesRequestHandler::new()
+        .with_deposit_call_wait(CallWait::bounded(CallWait::DEFAULT_TIMEOUT_SECS));
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SubFunder {
+    pub canister_id: Principal,
+    pub max_cycles_per_day: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct FundingConfig {
+    pub funded_canister_ids: Vec<Principal>,
+    /// The funding canister to request cycles from, if this canister is a sub-funder.
+    pub parent_funder_id: Option<Principal>,
+    /// The funding canisters allowed to request cycles from this canister.
+    pub sub_funders: Vec<SubFunder>,
+}
+
+#[init]
+fn initialize(config: FundingConfig) {
+    start_canister_cycles_monitoring(config);
+}
+
+#[post_upgrade]
+fn post_upgrade(config: FundingConfig) {
+    start_canister_cycles_monitoring(config);
+}
+
+pub fn start_canister_cycles_monitoring(config: FundingConfig) {
+    // The allowlist is kept in heap memory, so the sub-funders are allowed again after an upgrade.
+    REQUEST_HANDLER.with(|handler| {
+        for sub_funder in config.sub_funders {
+            handler.allow(
+                sub_funder.canister_id,
+                FunderBudget::new().with_max_cycles_per_period(
+                    cycles_nat_to_u128(sub_funder.max_cycles_per_day).unwrap_or(u128::MAX),
+                ),
+            );
+        }
+    });
+
+    FUND_MANAGER.with(|fund_manager| {
+        let mut fund_manager = fund_manager.borrow_mut();
+
+        let mut fund_manager_options = FundManagerOptions::new()
+            .with_interval_secs(12 * 60 * 60) // twice a day
+            .with_strategy(FundStrategy::BelowThreshold(
+                CyclesThreshold::new()
+                    .with_min_cycles(400_000_000_000)
+                    .with_fund_cycles(250_000_000_000),
+            ));
+
+        // A sub-funder requests the cycles it cannot spare from its parent.
+        if let Some(parent_funder_id) = config.parent_funder_id {
+            fund_manager_options = fund_manager_options.with_obtain_cycles_options(Some(
+                ObtainCyclesOptions::new(Arc::new(RequestCyclesFromFunder::new(Arc::new(
+                    IcFundingCanister::new(parent_funder_id),
+                )))),
+            ));
+        }
+
+        fund_manager.with_options(fund_manager_options);
+
+        // Reconcile the registered canisters with the configured ones, e.g. after an upgrade.
+        fund_manager.sync(config.funded_canister_ids.into_iter().map(|canister_id| {
+            (
+                canister_id,
+                RegisterOpts::new()
+                    .with_cycles_fetcher(Arc::new(FetchCyclesBalanceFromCanisterStatus::new())),
+            )
+        }));
+
+        fund_manager.start();
+    });
+}
+
+#[update]
+async fn canfund_request_cycles(
+    args: RequestCyclesArgs,
+) -> Result<RequestCyclesSuccess, RequestCyclesError> {
+    let handler = REQUEST_HANDLER.with(|handler| handler.clone());
+    handler.handle(msg_caller(), args).await
+}
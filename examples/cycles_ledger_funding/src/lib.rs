@@ -4,7 +4,7 @@ use canfund::operations::obtain::WithdrawFromCyclesLedger;
 use canfund::{
     manager::{
         options::{
-            CyclesThreshold, EstimatedRuntime, FundManagerOptions, FundStrategy,
+            CyclesThreshold, EstimatedRuntime, FundManagerOptions, FundStrategy, FunderOptions,
             ObtainCyclesOptions,
         },
         RegisterOpts,
@@ -12,7 +12,6 @@ use canfund::{
     operations::fetch::FetchCyclesBalanceFromCanisterStatus,
     FundManager,
 };
-use ic_cdk::api::canister_self;
 use ic_cdk::query;
 use ic_cdk_macros::{init, post_upgrade, update};
 use icrc_ledger_types::icrc1::account::Account;
@@ -70,28 +69,26 @@ pub fn start_canister_cycles_monitoring(config: FundingConfig) {
         fund_manager_options =
             fund_manager_options.with_obtain_cycles_options(get_obtain_cycles_config());
 
+        // The funding canister itself is also monitored, with its own strategy.
+        fund_manager_options = fund_manager_options.with_funder_options(Some(
+            FunderOptions::new().with_strategy(FundStrategy::BelowThreshold(
+                CyclesThreshold::new()
+                    .with_min_cycles(500_000_000_000)
+                    .with_fund_cycles(750_000_000_000),
+            )),
+        ));
+
         fund_manager.with_options(fund_manager_options);
 
-        for canister_id in config.funded_canister_ids {
-            fund_manager.register(
+        // Reconcile the registered canisters with the configured ones, e.g. after an upgrade.
+        fund_manager.sync(config.funded_canister_ids.into_iter().map(|canister_id| {
+            (
                 canister_id,
                 RegisterOpts::new()
                     .with_cycles_fetcher(Arc::new(FetchCyclesBalanceFromCanisterStatus::new()))
                     .with_obtain_cycles_options(get_obtain_cycles_config().unwrap()),
-            );
-        }
-
-        // The funding canister itself can also be monitored.
-        fund_manager.register(
-            canister_self(),
-            RegisterOpts::new()
-                .with_cycles_fetcher(Arc::new(FetchCyclesBalanceFromCanisterStatus::new()))
-                .with_strategy(FundStrategy::BelowThreshold(
-                    CyclesThreshold::new()
-                        .with_min_cycles(500_000_000_000)
-                        .with_fund_cycles(750_000_000_000),
-                )),
-        );
+            )
+        }));
 
         fund_manager.start();
     });
@@ -101,12 +98,12 @@ pub const MAINNET_CYCLES_LEDGER_CANISTER_ID: Principal =
     Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x02, 0x10, 0x00, 0x02, 0x01, 0x01]);
 // Default subaccount for minting cycles is derived from the canister's account.
 pub fn get_obtain_cycles_config() -> Option<ObtainCyclesOptions> {
-    Some(ObtainCyclesOptions {
-        obtain_cycles: Arc::new(WithdrawFromCyclesLedger {
-            ledger: Arc::new(CyclesLedgerCanister::new(MAINNET_CYCLES_LEDGER_CANISTER_ID)),
-            from_subaccount: None,
-        }),
-    })
+    Some(ObtainCyclesOptions::new(Arc::new(
+        WithdrawFromCyclesLedger::new(
+            Arc::new(CyclesLedgerCanister::new(MAINNET_CYCLES_LEDGER_CANISTER_ID)),
+            None,
+        ),
+    )))
 }
 
 #[derive(CandidType, Deserialize)]
@@ -50,8 +50,9 @@ pub fn start_canister_cycles_monitoring(config: FundingConfig) {
 
         fund_manager.with_options(fund_manager_options);
 
-        for canister_id in config.funded_canister_ids {
-            fund_manager.register(
+        // Reconcile the registered canisters with the configured ones, e.g. after an upgrade.
+        fund_manager.sync(config.funded_canister_ids.into_iter().map(|canister_id| {
+            (
                 canister_id,
                 RegisterOpts::new()
                     .with_cycles_fetcher(Arc::new(FetchCyclesBalanceFromCanisterStatus::new()))
@@ -60,8 +61,8 @@ pub fn start_canister_cycles_monitoring(config: FundingConfig) {
                             .with_min_cycles(400_000_000_000)
                             .with_fund_cycles(500_000_000_000),
                     )),
-            );
-        }
+            )
+        }));
 
         fund_manager.start();
     });